@@ -0,0 +1,108 @@
+//
+// Copyright (C) 2024 Eric Le Bihan <eric.le.bihan.dev@free.fr>
+//
+// SPDX-License-Identifier: MIT
+//
+
+use crate::command::{Command, ErrorPolicy, RetryPolicy};
+use crate::condition::Condition;
+use crate::expectation::Expectation;
+use std::fmt::Display;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Location of a statement in the source of a batch.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Location {
+    /// File of the statement, if the batch was read from a file.
+    pub file: Option<Arc<Path>>,
+    /// Line of the statement, or 0 if the batch has no lines, such as a
+    /// structured definition.
+    pub line: usize,
+}
+
+impl Location {
+    pub fn new(file: Option<Arc<Path>>, line: usize) -> Self {
+        Self { file, line }
+    }
+}
+
+impl Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.file, self.line) {
+            (Some(file), 0) => write!(f, "{}", file.display()),
+            (Some(file), line) => write!(f, "{}:{}", file.display(), line),
+            (None, 0) => write!(f, "unknown line"),
+            (None, line) => write!(f, "line {}", line),
+        }
+    }
+}
+
+/// Represent a command of a batch, with its modifiers.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Step {
+    /// Name of the step, if given by a structured definition.
+    pub name: Option<String>,
+    pub command: Command,
+    /// Error policy of the command, overriding the one of the batch.
+    pub policy: Option<ErrorPolicy>,
+    /// What the command must produce to pass.
+    pub expectations: Vec<Expectation>,
+    /// How many times the command is run again if it fails.
+    pub retry: Option<RetryPolicy>,
+    /// Maximum duration of each run of the command.
+    pub timeout: Option<Duration>,
+    /// Name of the variable set to the output of the command.
+    pub capture: Option<String>,
+    /// Statements run when the command fails.
+    pub on_failure: Vec<Statement>,
+    /// Labels given to the step by a structured definition.
+    pub tags: Vec<String>,
+    /// Location of the command in the batch.
+    pub location: Location,
+}
+
+impl From<Command> for Step {
+    fn from(command: Command) -> Self {
+        Self {
+            name: None,
+            command,
+            policy: None,
+            expectations: vec![],
+            retry: None,
+            timeout: None,
+            capture: None,
+            on_failure: vec![],
+            tags: vec![],
+            location: Location::default(),
+        }
+    }
+}
+
+/// Represent a statement of a batch.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Statement {
+    /// Run a command.
+    Run(Step),
+    /// Set a variable, found at the given location.
+    Set {
+        name: String,
+        value: String,
+        location: Location,
+    },
+    /// Run the statements of the first branch if the condition, found at the
+    /// given location, holds, otherwise the ones of the second branch.
+    If {
+        condition: Condition,
+        then: Vec<Statement>,
+        otherwise: Vec<Statement>,
+        location: Location,
+    },
+    /// Run the commands of the steps concurrently, the block starting at the
+    /// given location.
+    Parallel {
+        steps: Vec<Step>,
+        location: Location,
+    },
+}
@@ -4,61 +4,253 @@
 // SPDX-License-Identifier: MIT
 //
 
-use super::command::Command;
+use super::ast::{Location, Statement, Step};
+use super::command::{Error as CommandError, ErrorPolicy};
+use super::definition::{BatchDefinition, DefinitionFormat};
 use super::error::Error;
+use super::parser::Parser;
+use super::variables::{self, Variables};
+use std::collections::HashSet;
 use std::io::BufRead;
+use std::sync::Arc;
 use std::{
     fs::File,
-    io::{BufReader, Read},
+    io::{BufReader, ErrorKind, Read},
     path::Path,
     str::FromStr,
 };
 
 /// Represent a batch of commands.
-#[derive(Debug, PartialEq)]
+///
+/// A command can be followed by modifiers, such as `POLICY continue` to keep
+/// running the batch if the command fails, or `EXPECT stdout contains TEXT`
+/// to check its output. `RETRY 3 1s` runs a failed command again, up to 3
+/// times with an exponential backoff starting at 1 s, and `TIMEOUT 30s`
+/// limits the duration of each run. The default error policy of the batch is
+/// set with `DEFAULT POLICY`, before any statement.
+///
+/// Variables are set with `SET name = value`, or by capturing the output of a
+/// command with `EXECUTE: command -> name`. They are substituted in the
+/// arguments of the next commands with `${name}`.
+///
+/// Statements can be guarded by an `IF condition` block, with an optional
+/// `ELSE` branch, closed by `END`. The statements of an `ON FAILURE` block,
+/// also closed by `END`, are run when the command it follows fails.
+///
+/// The commands of a `PARALLEL` block, closed by `END`, are run concurrently.
+/// Only commands and their modifiers, but `ON FAILURE`, are allowed in it.
+///
+/// `INCLUDE path` inserts the statements of another batch file, relative to
+/// the including one. `DEFINE name(param, ...)` declares a procedure whose
+/// statements, up to `END`, are inserted by `CALL name(arg, ...)`, with the
+/// arguments substituted to the references to its parameters.
+///
+/// A batch can also be defined in a structured format, YAML, TOML or JSON,
+/// as a list of steps with a name, a command, its arguments, modifiers and
+/// tags. See `DefinitionFormat`.
+#[derive(Debug, Default, PartialEq)]
 pub struct Batch {
-    pub(crate) commands: Vec<Command>,
+    pub(crate) statements: Vec<Statement>,
+    pub(crate) policy: ErrorPolicy,
+}
+
+/// Collect the references to undefined variables in `statements`.
+fn check_statements<'a>(
+    statements: &'a [Statement],
+    variables: &Variables,
+    defined: &mut HashSet<&'a str>,
+) -> Result<(), Error> {
+    let check = |text: &str, location: &Location, defined: &HashSet<&str>| {
+        let names = variables::references(text).map_err(|source| Error::Syntax {
+            location: location.clone(),
+            source,
+        })?;
+        for name in names {
+            if !defined.contains(name) && !variables.contains(name) {
+                return Err(Error::UndefinedVariable {
+                    name: name.to_string(),
+                    location: location.clone(),
+                });
+            }
+        }
+        Ok(())
+    };
+    for statement in statements {
+        match statement {
+            Statement::Run(step) => {
+                for text in variables::command_texts(&step.command) {
+                    check(text, &step.location, defined)?;
+                }
+                if let Some(name) = &step.capture {
+                    defined.insert(name.as_str());
+                }
+                check_statements(&step.on_failure, variables, defined)?;
+            }
+            Statement::Set {
+                name,
+                value,
+                location,
+            } => {
+                check(value, location, defined)?;
+                defined.insert(name.as_str());
+            }
+            Statement::If {
+                condition,
+                then,
+                otherwise,
+                location,
+            } => {
+                for text in condition.texts() {
+                    check(text, location, defined)?;
+                }
+                check_statements(then, variables, defined)?;
+                check_statements(otherwise, variables, defined)?;
+            }
+            Statement::Parallel { steps, .. } => {
+                for step in steps {
+                    for text in variables::command_texts(&step.command) {
+                        check(text, &step.location, defined)?;
+                    }
+                }
+                defined.extend(steps.iter().filter_map(|s| s.capture.as_deref()));
+            }
+        }
+    }
+    Ok(())
+}
+
+impl Batch {
+    fn parse_lines<I: IntoIterator<Item = String>>(
+        mut parser: Parser,
+        lines: I,
+    ) -> Result<Self, Error> {
+        parser.parse_lines(lines);
+        let (statements, policy) = parser.finish()?;
+        Ok(Batch { statements, policy })
+    }
+
+    /// Check that the variables referenced by the batch are defined, either
+    /// in `variables` or by a previous statement.
+    ///
+    /// Variables set in any branch of a conditional block are considered as
+    /// defined after it. Variables captured in a parallel block are only
+    /// defined after it.
+    pub fn check_variables(&self, variables: &Variables) -> Result<(), Error> {
+        check_statements(&self.statements, variables, &mut HashSet::new())
+    }
+
+    /// Return the error policy applying to `step`.
+    pub(crate) fn policy_of(&self, step: &Step) -> ErrorPolicy {
+        step.policy.unwrap_or(self.policy)
+    }
 }
 
 impl FromStr for Batch {
     type Err = Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let commands: Result<Vec<Command>, Error> = s
-            .split(';')
-            .map(|s| Command::from_str(s).map_err(Error::Syntax))
-            .collect();
-        Ok(Batch {
-            commands: commands?,
-        })
+        Self::parse_lines(Parser::new(), s.split(';').map(String::from))
     }
 }
 
 impl Batch {
     /// Build a `Batch` from the contents of a file.
+    ///
+    /// If the extension of the file matches a `DefinitionFormat`, the batch
+    /// is read as a structured definition. Otherwise, all the errors found
+    /// in the batch are returned as `Error::Parse`, and files included by the
+    /// batch are resolved relative to it.
     pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        if let Some(format) = DefinitionFormat::from_path(path) {
+            let text = std::fs::read_to_string(path)?;
+            let location = Location::new(Some(Arc::from(path)), 0);
+            return Self::compile_definition(&text, format, &location);
+        }
         let file = File::open(path)?;
-        Self::from_reader(file)
+        Self::read_lines(Parser::with_file(path), file)
     }
 
+    /// Build a `Batch` from the contents of a reader.
+    ///
+    /// Files included by the batch are resolved relative to the current
+    /// directory.
     pub fn from_reader<R: Read>(reader: R) -> Result<Self, Error> {
+        Self::read_lines(Parser::new(), reader)
+    }
+
+    /// Build a `Batch` from a structured definition in the given format.
+    pub fn from_definition(text: &str, format: DefinitionFormat) -> Result<Self, Error> {
+        Self::compile_definition(text, format, &Location::default())
+    }
+
+    fn compile_definition(
+        text: &str,
+        format: DefinitionFormat,
+        location: &Location,
+    ) -> Result<Self, Error> {
+        let (statements, policy) = BatchDefinition::parse(text, format)?.compile(location)?;
+        Ok(Batch { statements, policy })
+    }
+
+    /// Convert the batch to a structured definition in the given format.
+    ///
+    /// Included files and calls to procedures are expanded.
+    pub fn to_definition(&self, format: DefinitionFormat) -> Result<String, Error> {
+        BatchDefinition::new(&self.statements, self.policy).render(format)
+    }
+
+    /// Parse the lines of `reader`, reporting the lines which are not valid
+    /// UTF-8 along with the other errors.
+    fn read_lines<R: Read>(mut parser: Parser, reader: R) -> Result<Self, Error> {
         let reader = BufReader::new(reader);
-        let commands: Result<Vec<Command>, Error> = reader
-            .lines()
-            .map_while(Result::ok)
-            .filter(|l| !l.starts_with('#'))
-            .filter(|l| !l.is_empty())
-            .map(|l| Command::from_str(&l).map_err(Error::Syntax))
-            .collect();
-        Ok(Batch {
-            commands: commands?,
-        })
+        for (index, line) in reader.lines().enumerate() {
+            match line {
+                Ok(line) => parser.parse_line(&line, index + 1),
+                Err(e) if e.kind() == ErrorKind::InvalidData => {
+                    parser.reject_line(index + 1, CommandError::InvalidEncoding)
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let (statements, policy) = parser.finish()?;
+        Ok(Batch { statements, policy })
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::command::{Command, RetryPolicy};
+    use crate::diagnostic::Diagnostic;
+    use crate::expectation::Expectation;
+    use std::time::Duration;
+
+    /// Return the errors found when parsing a batch.
+    fn diagnostics(res: Result<Batch, Error>) -> Vec<Diagnostic> {
+        match res {
+            Err(Error::Parse(diagnostics)) => diagnostics.0,
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
+    fn run(command: Command, line: usize) -> Statement {
+        Statement::Run(Step {
+            location: Location::new(None, line),
+            ..command.into()
+        })
+    }
+
+    fn steps(batch: &Batch) -> Vec<&Step> {
+        batch
+            .statements
+            .iter()
+            .filter_map(|s| match s {
+                Statement::Run(step) => Some(step),
+                _ => None,
+            })
+            .collect()
+    }
 
     #[test]
     fn parse_valid_string() {
@@ -67,7 +259,11 @@ mod tests {
         assert_eq!(
             res.unwrap(),
             Batch {
-                commands: vec![Command::Execute("date -u".to_string()), Command::Upgrade]
+                statements: vec![
+                    run(Command::Execute("date -u".to_string()), 1),
+                    run(Command::Upgrade(None), 2)
+                ],
+                policy: ErrorPolicy::default(),
             }
         );
     }
@@ -86,12 +282,640 @@ UPGRADE
         assert_eq!(
             res.unwrap(),
             Batch {
-                commands: vec![
-                    Command::Inspect,
-                    Command::Execute("date -u".to_string()),
-                    Command::Upgrade
-                ]
+                statements: vec![
+                    run(Command::Inspect, 2),
+                    run(Command::Execute("date -u".to_string()), 4),
+                    run(Command::Upgrade(None), 5)
+                ],
+                policy: ErrorPolicy::default(),
+            }
+        );
+    }
+
+    const BATCH_POLICIES: &str = r##"
+DEFAULT POLICY continue
+EXECUTE: false
+    POLICY ignore-exit-code
+UPGRADE
+    POLICY fail-fast
+INSPECT
+"##;
+
+    #[test]
+    fn parse_policies() {
+        let batch = Batch::from_reader(BATCH_POLICIES.as_bytes()).unwrap();
+        let policies: Vec<ErrorPolicy> = steps(&batch)
+            .into_iter()
+            .map(|s| batch.policy_of(s))
+            .collect();
+        assert_eq!(
+            policies,
+            vec![
+                ErrorPolicy {
+                    continue_on_failure: false,
+                    ignore_exit_code: true
+                },
+                ErrorPolicy::default(),
+                ErrorPolicy {
+                    continue_on_failure: true,
+                    ignore_exit_code: false
+                },
+            ]
+        );
+        let res = "POLICY continue; INSPECT".parse::<Batch>();
+        assert!(matches!(
+            diagnostics(res)[0].error,
+            CommandError::MisplacedModifier(_)
+        ));
+        for batch in [
+            "INSPECT; POLICYcontinue",
+            "DEFAULT POLICYfail-fast; INSPECT",
+        ] {
+            assert!(matches!(
+                diagnostics(batch.parse::<Batch>())[0].error,
+                CommandError::UnknownCommand(_)
+            ));
+        }
+        let res = "INSPECT; DEFAULT POLICY continue".parse::<Batch>();
+        assert!(matches!(
+            &diagnostics(res)[0].error,
+            CommandError::UnexpectedKeyword(keyword) if keyword == "DEFAULT POLICY"
+        ));
+    }
+
+    const BATCH_EXPECTATIONS: &str = r##"
+EXECUTE: uname -r
+    EXPECT exit-code 0
+    EXPECT stdout matches ^6\.
+INSPECT
+    EXPECT uptime > 1h
+"##;
+
+    #[test]
+    fn parse_expectations() {
+        let batch = Batch::from_reader(BATCH_EXPECTATIONS.as_bytes()).unwrap();
+        let steps = steps(&batch);
+        assert_eq!(steps[0].expectations.len(), 2);
+        assert_eq!(
+            steps[1].expectations,
+            vec![Expectation::UptimeAbove(std::time::Duration::from_secs(
+                3600
+            ))]
+        );
+        let res = "INSPECT; EXPECT stderr empty".parse::<Batch>();
+        assert!(matches!(
+            diagnostics(res)[0].error,
+            CommandError::InvalidExpectation(_)
+        ));
+    }
+
+    const BATCH_RETRIES: &str = r##"
+UPGRADE
+    RETRY 2 10s
+    TIMEOUT 5m
+EXECUTE: ping -c 1 example.com
+    RETRY 5
+"##;
+
+    #[test]
+    fn parse_retries() {
+        let batch = Batch::from_reader(BATCH_RETRIES.as_bytes()).unwrap();
+        let steps = steps(&batch);
+        assert_eq!(
+            steps[0].retry,
+            Some(RetryPolicy {
+                retries: 2,
+                backoff: Duration::from_secs(10)
+            })
+        );
+        assert_eq!(steps[0].timeout, Some(Duration::from_secs(300)));
+        assert_eq!(steps[1].retry.unwrap().retries, 5);
+        assert_eq!(steps[1].timeout, None);
+        let res = "INSPECT; TIMEOUT never".parse::<Batch>();
+        assert!(matches!(
+            diagnostics(res)[0].error,
+            CommandError::InvalidDuration(_)
+        ));
+    }
+
+    const BATCH_VARIABLES: &str = r##"
+SET target = ${release}-rc1
+EXECUTE: uname -r -> kernel
+UPGRADE: ${target}
+EXECUTE: echo ${kernel} ${user}
+"##;
+
+    #[test]
+    fn parse_variables() {
+        let batch = Batch::from_reader(BATCH_VARIABLES.as_bytes()).unwrap();
+        assert_eq!(
+            batch.statements[0],
+            Statement::Set {
+                name: "target".to_string(),
+                value: "${release}-rc1".to_string(),
+                location: Location::new(None, 2)
             }
         );
+        assert_eq!(steps(&batch)[0].capture, Some("kernel".to_string()));
+        let mut variables = Variables::new();
+        variables.define("release", "2.0");
+        let res = batch.check_variables(&variables);
+        assert!(matches!(
+            res,
+            Err(Error::UndefinedVariable { ref name, ref location }) if name == "user" && location.line == 5
+        ));
+        variables.define("user", "root");
+        assert!(batch.check_variables(&variables).is_ok());
+        for (line, command) in [
+            ("EXECUTE: echo a->b", "echo a->b"),
+            ("EXECUTE: echo a -> b c", "echo a -> b c"),
+            ("EXECUTE: echo a -> 2b", "echo a -> 2b"),
+        ] {
+            let batch = line.parse::<Batch>().unwrap();
+            assert_eq!(
+                steps(&batch)[0].command,
+                Command::Execute(command.to_string())
+            );
+            assert_eq!(steps(&batch)[0].capture, None);
+        }
+        let res = "SET 1st = one".parse::<Batch>();
+        assert!(matches!(
+            diagnostics(res)[0].error,
+            CommandError::InvalidVariable(_)
+        ));
+    }
+
+    const BATCH_CONDITIONS: &str = r##"
+INSPECT
+IF kernel-version < 6.1
+    UPGRADE: 2.0
+    ON FAILURE
+        EXECUTE: journalctl -u artifex
+    END
+ELSE
+    EXECUTE: echo up to date
+END
+"##;
+
+    #[test]
+    fn parse_conditions() {
+        let batch = Batch::from_reader(BATCH_CONDITIONS.as_bytes()).unwrap();
+        assert_eq!(batch.statements.len(), 2);
+        match &batch.statements[1] {
+            Statement::If {
+                condition,
+                then,
+                otherwise,
+                location,
+            } => {
+                assert_eq!(condition.to_string(), "kernel-version < 6.1");
+                assert_eq!(location.line, 3);
+                assert_eq!(otherwise.len(), 1);
+                match &then[..] {
+                    [Statement::Run(step)] => {
+                        assert_eq!(
+                            step.on_failure,
+                            vec![run(
+                                Command::Execute("journalctl -u artifex".to_string()),
+                                6
+                            )]
+                        );
+                    }
+                    _ => panic!("Unexpected statements"),
+                }
+            }
+            _ => panic!("Unexpected statement"),
+        }
+    }
+
+    fn batch_directory(name: &str, files: &[(&str, &str)]) -> std::path::PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("artifex-batch-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        for (path, contents) in files {
+            let path = dir.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn parse_includes() {
+        let dir = batch_directory(
+            "include",
+            &[
+                (
+                    "main.txt",
+                    "INSPECT\nINCLUDE lib/common.txt\nCALL check(nginx)\n",
+                ),
+                (
+                    "lib/common.txt",
+                    "DEFAULT POLICY continue\nINCLUDE procs.txt\nUPGRADE\n",
+                ),
+                (
+                    "lib/procs.txt",
+                    "DEFINE check(unit)\n  EXECUTE: systemctl is-active ${unit} ${user}\nEND\n",
+                ),
+            ],
+        );
+        let batch = Batch::from_file(dir.join("main.txt")).unwrap();
+        let steps = steps(&batch);
+        assert_eq!(steps.len(), 3);
+        assert!(batch.policy.continue_on_failure);
+        assert_eq!(steps[1].location.line, 3);
+        assert!(steps[1]
+            .location
+            .file
+            .as_ref()
+            .unwrap()
+            .ends_with("common.txt"));
+        assert_eq!(
+            steps[2].command,
+            Command::Execute("systemctl is-active nginx ${user}".to_string())
+        );
+        assert!(steps[2]
+            .location
+            .file
+            .as_ref()
+            .unwrap()
+            .ends_with("procs.txt"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn report_include_errors() {
+        let dir = batch_directory(
+            "include-errors",
+            &[
+                ("a.txt", "INSPECT\nINCLUDE b.txt\n"),
+                ("b.txt", "INCLUDE a.txt\n"),
+                ("c.txt", "INSPECT\nINCLUDE d.txt\n"),
+                ("d.txt", "\nIF uptime\nEND\n"),
+                ("e.txt", "INCLUDE missing.txt\n"),
+            ],
+        );
+        let res = Batch::from_file(dir.join("a.txt"));
+        assert!(matches!(
+            &diagnostics(res)[0],
+            Diagnostic {
+                location,
+                error: CommandError::IncludeCycle(_),
+                ..
+            } if location.line == 1 && location.file.as_ref().unwrap().ends_with("b.txt")
+        ));
+        let res = Batch::from_file(dir.join("c.txt"));
+        assert!(matches!(
+            &diagnostics(res)[0],
+            Diagnostic {
+                location,
+                error: CommandError::InvalidCondition(_),
+                ..
+            } if location.line == 2 && location.file.as_ref().unwrap().ends_with("d.txt")
+        ));
+        let res = Batch::from_file(dir.join("e.txt"));
+        assert!(matches!(
+            &diagnostics(res)[0],
+            Diagnostic {
+                location,
+                error: CommandError::Include(..),
+                ..
+            } if location.line == 1
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    const BATCH_PROCEDURES: &str = r##"
+DEFINE restart(unit, delay)
+    EXECUTE: systemctl restart ${unit}
+    IF exit-code != 0
+        SET failed = ${unit} after ${delay}
+    END
+END
+CALL restart(nginx, "5s")
+CALL restart(sshd, 1s)
+"##;
+
+    #[test]
+    fn parse_procedures() {
+        let batch = Batch::from_reader(BATCH_PROCEDURES.as_bytes()).unwrap();
+        assert_eq!(batch.statements.len(), 4);
+        assert_eq!(
+            batch.statements[2],
+            run(Command::Execute("systemctl restart sshd".to_string()), 3)
+        );
+        match &batch.statements[1] {
+            Statement::If { then, .. } => assert_eq!(
+                then[0],
+                Statement::Set {
+                    name: "failed".to_string(),
+                    value: "nginx after 5s".to_string(),
+                    location: Location::new(None, 5)
+                }
+            ),
+            _ => panic!("Unexpected statement"),
+        }
+        let res = "CALL restart(nginx)".parse::<Batch>();
+        assert!(matches!(
+            diagnostics(res)[0].error,
+            CommandError::UnknownProcedure(_)
+        ));
+        let res = "DEFINE f(a); INSPECT; END; CALL f(1, 2)".parse::<Batch>();
+        assert!(matches!(
+            diagnostics(res)[0].error,
+            CommandError::InvalidCall(_)
+        ));
+        let res = "IF uptime > 1h; DEFINE f; END; END".parse::<Batch>();
+        assert!(matches!(
+            diagnostics(res)[0].error,
+            CommandError::UnexpectedKeyword(_)
+        ));
+    }
+
+    #[test]
+    fn report_syntax_error_line() {
+        let res = Batch::from_reader("INSPECT\nIF uptime\nEND\n".as_bytes());
+        assert!(matches!(
+            &diagnostics(res)[0],
+            Diagnostic {
+                location,
+                error: CommandError::InvalidCondition(_),
+                ..
+            } if location.line == 2
+        ));
+        let res = Batch::from_reader("INSPECT\nIF uptime > 1h\nINSPECT\n".as_bytes());
+        assert!(matches!(
+            &diagnostics(res)[0],
+            Diagnostic {
+                location,
+                error: CommandError::UnclosedBlock(_),
+                ..
+            } if location.line == 2
+        ));
+        let res = "INSPECT; ELSE; END".parse::<Batch>();
+        assert!(matches!(
+            &diagnostics(res)[0],
+            Diagnostic {
+                location,
+                error: CommandError::UnexpectedKeyword(_),
+                ..
+            } if location.line == 2
+        ));
+        let res = "SET a = 1; ON FAILURE; END".parse::<Batch>();
+        assert!(matches!(
+            &diagnostics(res)[0],
+            Diagnostic {
+                location,
+                error: CommandError::MisplacedModifier(_),
+                ..
+            } if location.line == 2
+        ));
+    }
+
+    const BATCH_ERRORS: &str = r##"
+INSPECT
+    TIMEOUT never
+EXECUTE
+    RETRY 3
+IF uptime
+    UPGRADE
+ELSE
+    INSPECT
+END
+UPGRADE: 2.0
+    EXPECT stderr empty
+"##;
+
+    #[test]
+    fn report_all_errors() {
+        let diagnostics = diagnostics(Batch::from_reader(BATCH_ERRORS.as_bytes()));
+        let lines: Vec<usize> = diagnostics.iter().map(|d| d.location.line).collect();
+        assert_eq!(lines, vec![3, 4, 6, 12]);
+        assert_eq!(diagnostics[0].columns, 13..18);
+        assert!(matches!(
+            diagnostics[1].error,
+            CommandError::MissingArgument
+        ));
+        assert_eq!(diagnostics[1].columns, 1..8);
+        assert!(matches!(
+            diagnostics[2].error,
+            CommandError::InvalidCondition(_)
+        ));
+        assert_eq!(diagnostics[2].columns, 4..10);
+        assert!(matches!(
+            diagnostics[3].error,
+            CommandError::InvalidExpectation(_)
+        ));
+    }
+
+    #[test]
+    fn report_invalid_encoding() {
+        let res = Batch::from_reader(&b"INSPECT\n\xff\nREBOOT\n"[..]);
+        let diagnostics = diagnostics(res);
+        assert!(matches!(
+            &diagnostics[..],
+            [
+                Diagnostic {
+                    error: CommandError::InvalidEncoding,
+                    ..
+                },
+                Diagnostic {
+                    error: CommandError::UnknownCommand(_),
+                    ..
+                }
+            ]
+        ));
+        assert_eq!(diagnostics[1].location.line, 3);
+    }
+
+    const BATCH_YAML: &str = r##"
+policy: continue
+steps:
+  - name: Check kernel
+    command: execute
+    arguments: uname -r
+    capture: kernel
+    timeout: 30s
+    retry:
+      count: 2
+      backoff: 500ms
+    expectations:
+      - exit-code 0
+    tags: [kernel, sanity]
+  - if: kernel-version < 6.1
+    then:
+      - command: upgrade
+        arguments: ${release}
+        on-failure:
+          - set: failed
+            value: ${kernel}
+"##;
+
+    const BATCH_TOML: &str = r##"
+[[steps]]
+command = "inspect"
+expectations = ["uptime > 1h"]
+
+[[steps]]
+name = "Print date"
+command = "execute"
+arguments = "date -u"
+policy = "continue"
+"##;
+
+    #[test]
+    fn parse_yaml_definition() {
+        let batch = Batch::from_definition(BATCH_YAML, DefinitionFormat::Yaml).unwrap();
+        assert!(batch.policy.continue_on_failure);
+        let steps = steps(&batch);
+        assert_eq!(steps[0].name.as_deref(), Some("Check kernel"));
+        assert_eq!(steps[0].command, Command::Execute("uname -r".to_string()));
+        assert_eq!(steps[0].capture.as_deref(), Some("kernel"));
+        assert_eq!(steps[0].timeout, Some(Duration::from_secs(30)));
+        assert_eq!(
+            steps[0].retry,
+            Some(RetryPolicy {
+                retries: 2,
+                backoff: Duration::from_millis(500)
+            })
+        );
+        assert_eq!(steps[0].expectations, vec![Expectation::ExitCode(0)]);
+        assert_eq!(steps[0].tags, vec!["kernel", "sanity"]);
+        match &batch.statements[1] {
+            Statement::If { then, .. } => match &then[..] {
+                [Statement::Run(step)] => {
+                    assert_eq!(
+                        step.command,
+                        Command::Upgrade(Some("${release}".to_string()))
+                    );
+                    assert!(matches!(step.on_failure[..], [Statement::Set { .. }]));
+                }
+                _ => panic!("Unexpected statements"),
+            },
+            _ => panic!("Unexpected statement"),
+        }
+    }
+
+    #[test]
+    fn parse_toml_definition() {
+        let batch = Batch::from_definition(BATCH_TOML, DefinitionFormat::Toml).unwrap();
+        let steps = steps(&batch);
+        assert_eq!(steps[0].command, Command::Inspect);
+        assert_eq!(steps[1].name.as_deref(), Some("Print date"));
+        assert!(batch.policy_of(steps[1]).continue_on_failure);
+    }
+
+    #[test]
+    fn report_definition_errors() {
+        let json = r#"{"steps": [{"command": "reboot"}]}"#;
+        assert!(matches!(
+            Batch::from_definition(json, DefinitionFormat::Json),
+            Err(Error::InvalidStep {
+                ref step,
+                source: CommandError::UnknownCommand(_)
+            }) if step == "steps[0]"
+        ));
+        let json = r#"{"steps": [{"set": "a", "timeout": "1s"}]}"#;
+        assert!(matches!(
+            Batch::from_definition(json, DefinitionFormat::Json),
+            Err(Error::InvalidStep {
+                source: CommandError::InvalidStep(_),
+                ..
+            })
+        ));
+        let json = r#"{"steps": [{"command": "inspect", "retries": 3}]}"#;
+        assert!(matches!(
+            Batch::from_definition(json, DefinitionFormat::Json),
+            Err(Error::Definition(_))
+        ));
+    }
+
+    const BATCH_PARALLEL: &str = r##"
+PARALLEL
+    EXECUTE: cat /var/log/syslog -> syslog
+    TIMEOUT 10s
+    EXECUTE: cat /var/log/messages
+END
+EXECUTE: echo ${syslog}
+"##;
+
+    #[test]
+    fn parse_parallel_blocks() {
+        let batch = Batch::from_reader(BATCH_PARALLEL.as_bytes()).unwrap();
+        match &batch.statements[0] {
+            Statement::Parallel { steps, location } => {
+                assert_eq!(location.line, 2);
+                assert_eq!(steps.len(), 2);
+                assert_eq!(steps[0].timeout, Some(Duration::from_secs(10)));
+                assert_eq!(steps[1].location.line, 5);
+            }
+            _ => panic!("Unexpected statement"),
+        }
+        assert!(batch.check_variables(&Variables::new()).is_ok());
+        let res = "PARALLEL; EXECUTE: a -> out; EXECUTE: echo ${out}; END".parse::<Batch>();
+        assert!(res.unwrap().check_variables(&Variables::new()).is_err());
+        let text = batch.to_definition(DefinitionFormat::Yaml).unwrap();
+        let converted = Batch::from_definition(&text, DefinitionFormat::Yaml).unwrap();
+        assert_eq!(converted.statements.len(), 2);
+        assert!(matches!(
+            &converted.statements[0],
+            Statement::Parallel { steps, .. } if steps.len() == 2
+        ));
+    }
+
+    #[test]
+    fn report_parallel_errors() {
+        let res = "PARALLEL; SET a = 1; IF a == 1; INSPECT; END; END".parse::<Batch>();
+        let errors = diagnostics(res);
+        assert_eq!(errors.len(), 2);
+        assert!(matches!(
+            &errors[1].error,
+            CommandError::UnexpectedKeyword(keyword) if keyword == "IF"
+        ));
+        let res = "PARALLEL; INSPECT; ON FAILURE; INSPECT; END; END".parse::<Batch>();
+        assert!(matches!(
+            &diagnostics(res)[0].error,
+            CommandError::UnexpectedKeyword(keyword) if keyword == "ON FAILURE"
+        ));
+        let json = r#"{"steps": [{"parallel": [{"set": "a", "value": "1"}]}]}"#;
+        assert!(matches!(
+            Batch::from_definition(json, DefinitionFormat::Json),
+            Err(Error::InvalidStep { ref step, .. }) if step == "steps[0].parallel[0]"
+        ));
+    }
+
+    #[test]
+    fn convert_to_definitions() {
+        let batch = Batch::from_reader(BATCH_CONDITIONS.as_bytes()).unwrap();
+        for format in [
+            DefinitionFormat::Json,
+            DefinitionFormat::Toml,
+            DefinitionFormat::Yaml,
+        ] {
+            let text = batch.to_definition(format).unwrap();
+            let converted = Batch::from_definition(&text, format).unwrap();
+            assert_eq!(converted.to_definition(format).unwrap(), text);
+        }
+        let batch = "DEFAULT POLICY continue; UPGRADE: 2.0; RETRY 2 10s".parse::<Batch>();
+        let text = batch
+            .unwrap()
+            .to_definition(DefinitionFormat::Json)
+            .unwrap();
+        assert_eq!(
+            text,
+            r#"{
+  "policy": "continue",
+  "steps": [
+    {
+      "command": "upgrade",
+      "arguments": "2.0",
+      "retry": {
+        "count": 2,
+        "backoff": "10s"
+      }
+    }
+  ]
+}"#
+        );
     }
 }
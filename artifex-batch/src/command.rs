@@ -4,7 +4,8 @@
 // SPDX-License-Identifier: MIT
 //
 
-use std::{fmt::Display, str::FromStr};
+use humantime::format_duration;
+use std::{fmt::Display, str::FromStr, time::Duration};
 use thiserror::Error;
 
 /// Errors raised when handling a `Command`.
@@ -12,10 +13,73 @@ use thiserror::Error;
 pub enum Error {
     #[error("Empty string")]
     EmptyString,
+    #[error("Cannot include '{0}': {1}")]
+    Include(String, std::io::Error),
+    #[error("Include cycle: {0}")]
+    IncludeCycle(String),
+    #[error("Invalid assignment: {0}")]
+    InvalidAssignment(String),
+    #[error("Invalid condition: {0}")]
+    InvalidCondition(String),
+    #[error("Invalid call: {0}")]
+    InvalidCall(String),
+    #[error("Invalid duration: {0}")]
+    InvalidDuration(String),
+    #[error("Invalid UTF-8 text")]
+    InvalidEncoding,
+    #[error("Invalid expectation: {0}")]
+    InvalidExpectation(String),
+    #[error("Invalid field name: {0}")]
+    InvalidFieldName(String),
+    #[error("Invalid procedure: {0}")]
+    InvalidProcedure(String),
+    #[error("Invalid retry count: {0}")]
+    InvalidRetry(String),
+    #[error("Invalid step: {0}")]
+    InvalidStep(String),
+    #[error("Invalid variable: {0}")]
+    InvalidVariable(String),
     #[error("Missing argument")]
     MissingArgument,
+    #[error("Modifier not following a command: {0}")]
+    MisplacedModifier(String),
+    #[error("Block not closed by END: {0}")]
+    UnclosedBlock(String),
+    #[error("Unexpected keyword: {0}")]
+    UnexpectedKeyword(String),
     #[error("Unknown command: {0}")]
     UnknownCommand(String),
+    #[error("Unknown procedure: {0}")]
+    UnknownProcedure(String),
+    #[error("Unknown error policy: {0}")]
+    UnknownPolicy(String),
+}
+
+impl Error {
+    /// Return the offending text, if known.
+    pub(crate) fn text(&self) -> Option<&str> {
+        match self {
+            Error::EmptyString | Error::InvalidEncoding | Error::MissingArgument => None,
+            Error::Include(text, _)
+            | Error::IncludeCycle(text)
+            | Error::InvalidAssignment(text)
+            | Error::InvalidCondition(text)
+            | Error::InvalidCall(text)
+            | Error::InvalidDuration(text)
+            | Error::InvalidExpectation(text)
+            | Error::InvalidFieldName(text)
+            | Error::InvalidProcedure(text)
+            | Error::InvalidRetry(text)
+            | Error::InvalidStep(text)
+            | Error::InvalidVariable(text)
+            | Error::MisplacedModifier(text)
+            | Error::UnclosedBlock(text)
+            | Error::UnexpectedKeyword(text)
+            | Error::UnknownCommand(text)
+            | Error::UnknownProcedure(text)
+            | Error::UnknownPolicy(text) => Some(text),
+        }
+    }
 }
 
 /// Represent a command to be execute via a client.
@@ -23,7 +87,7 @@ pub enum Error {
 pub enum Command {
     Execute(String),
     Inspect,
-    Upgrade,
+    Upgrade(Option<String>),
 }
 
 impl FromStr for Command {
@@ -32,17 +96,18 @@ impl FromStr for Command {
         if s.is_empty() {
             return Err(Error::EmptyString);
         }
-        let items = s.trim().split(':').collect::<Vec<&str>>();
-        match items[0] {
-            "EXECUTE" => {
-                if items.len() != 2 {
-                    Err(Error::MissingArgument)
-                } else {
-                    Ok(Command::Execute(items[1].trim().to_string()))
-                }
-            }
-            "INSPECT" => Ok(Command::Inspect),
-            "UPGRADE" => Ok(Command::Upgrade),
+        // Only the first colon ends the keyword, the argument may hold more,
+        // such as an image reference like `registry:5000/os:1.2`.
+        let s = s.trim();
+        let (keyword, argument) = match s.split_once(':') {
+            Some((keyword, argument)) => (keyword, Some(argument.trim())),
+            None => (s, None),
+        };
+        match (keyword, argument) {
+            ("EXECUTE", Some(command)) => Ok(Command::Execute(command.to_string())),
+            ("EXECUTE", None) => Err(Error::MissingArgument),
+            ("INSPECT", _) => Ok(Command::Inspect),
+            ("UPGRADE", target) => Ok(Command::Upgrade(target.map(String::from))),
             _ => Err(Error::UnknownCommand(s.to_string())),
         }
     }
@@ -53,7 +118,168 @@ impl Display for Command {
         match self {
             Command::Execute(command) => write!(f, "EXECUTE: {}", command),
             Command::Inspect => write!(f, "INSPECT"),
-            Command::Upgrade => write!(f, "UPGRADE"),
+            Command::Upgrade(None) => write!(f, "UPGRADE"),
+            Command::Upgrade(Some(target)) => write!(f, "UPGRADE: {}", target),
+        }
+    }
+}
+
+/// Tell how the failure of a command is handled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ErrorPolicy {
+    /// Run the next commands even if the command fails.
+    pub continue_on_failure: bool,
+    /// Do not consider a nonzero exit code as a failure of `EXECUTE`.
+    pub ignore_exit_code: bool,
+}
+
+impl FromStr for ErrorPolicy {
+    type Err = Error;
+
+    /// Parse a list of modifiers among `fail-fast`, `continue` and
+    /// `ignore-exit-code`, separated by spaces or commas.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut policy = ErrorPolicy::default();
+        let mut modifiers = s
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|m| !m.is_empty())
+            .peekable();
+        if modifiers.peek().is_none() {
+            return Err(Error::MissingArgument);
+        }
+        for modifier in modifiers {
+            match modifier {
+                "fail-fast" => policy.continue_on_failure = false,
+                "continue" => policy.continue_on_failure = true,
+                "ignore-exit-code" => policy.ignore_exit_code = true,
+                _ => return Err(Error::UnknownPolicy(modifier.to_string())),
+            }
+        }
+        Ok(policy)
+    }
+}
+
+impl Display for ErrorPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.continue_on_failure {
+            write!(f, "continue")?;
+        } else {
+            write!(f, "fail-fast")?;
+        }
+        if self.ignore_exit_code {
+            write!(f, " ignore-exit-code")?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse a duration such as `1s` or `1m 30s`.
+pub(crate) fn parse_duration(s: &str) -> Result<Duration, Error> {
+    humantime::parse_duration(s.trim()).map_err(|_| Error::InvalidDuration(s.trim().to_string()))
+}
+
+/// Tell how many times a failed command is run again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Maximum number of retries.
+    pub retries: u32,
+    /// Delay before the first retry, doubled before each next retry.
+    pub backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Return the delay before the given retry, starting from 0.
+    pub fn delay(&self, retry: u32) -> Duration {
+        self.backoff
+            .saturating_mul(2u32.saturating_pow(retry.min(16)))
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 0,
+            backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+impl FromStr for RetryPolicy {
+    type Err = Error;
+
+    /// Parse a number of retries, optionally followed by the initial backoff
+    /// delay, such as `3 500ms`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (retries, backoff) = s.split_once(' ').unwrap_or((s, ""));
+        let retries = retries
+            .parse::<u32>()
+            .map_err(|_| Error::InvalidRetry(retries.to_string()))?;
+        let backoff = if backoff.trim().is_empty() {
+            RetryPolicy::default().backoff
+        } else {
+            parse_duration(backoff)?
+        };
+        Ok(RetryPolicy { retries, backoff })
+    }
+}
+
+impl Display for RetryPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.retries, format_duration(self.backoff))
+    }
+}
+
+/// Name of a field of a structured output, in kebab-case such as
+/// `kernel-version`, so that it can be used as is as a key or an element name
+/// in reports.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FieldName(String);
+
+impl FieldName {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for FieldName {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let valid = s.starts_with(|c: char| c.is_ascii_lowercase())
+            && s.split('-').all(|word| {
+                !word.is_empty()
+                    && word
+                        .chars()
+                        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit())
+            });
+        if !valid {
+            return Err(Error::InvalidFieldName(s.to_string()));
+        }
+        Ok(FieldName(s.to_string()))
+    }
+}
+
+impl Display for FieldName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Typed value of a field of a structured output.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FieldValue {
+    Text(String),
+    Integer(u64),
+    Duration(Duration),
+}
+
+impl Display for FieldValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FieldValue::Text(text) => write!(f, "{}", text),
+            FieldValue::Integer(number) => write!(f, "{}", number),
+            FieldValue::Duration(duration) => write!(f, "{}", format_duration(*duration)),
         }
     }
 }
@@ -62,14 +288,33 @@ impl Display for Command {
 #[derive(Debug, PartialEq)]
 pub enum CommandOutput {
     String(String),
+    /// Outputs and exit code of a process.
+    Process {
+        stdout: String,
+        exit_code: i32,
+        stderr: String,
+    },
     Uint32(u32),
+    /// Named fields, such as the ones of an inspection of the machine, in
+    /// the order they were produced.
+    Record(Vec<(FieldName, FieldValue)>),
 }
 
 impl Display for CommandOutput {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             CommandOutput::String(s) => write!(f, "{}", s),
+            CommandOutput::Process { stdout, .. } => write!(f, "{}", stdout),
             CommandOutput::Uint32(u) => write!(f, "{}", u),
+            CommandOutput::Record(fields) => {
+                for (index, (name, value)) in fields.iter().enumerate() {
+                    if index > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{}: {}", name, value)?;
+                }
+                Ok(())
+            }
         }
     }
 }
@@ -78,7 +323,10 @@ impl Display for CommandOutput {
 #[derive(Debug, PartialEq)]
 pub enum CommandStatus {
     Success(Option<CommandOutput>),
-    Failure,
+    /// The command failed, for the given reason.
+    Failure(String),
+    /// The command was not run, as a previous command failed.
+    Skipped,
 }
 
 #[cfg(test)]
@@ -89,6 +337,116 @@ mod tests {
     fn parse_valid_execute() {
         let res = "EXECUTE: date -u".parse::<Command>();
         assert!(res.is_ok());
-        assert_eq!(res.unwrap(), Command::Execute("date -u".to_string()))
+        assert_eq!(res.unwrap(), Command::Execute("date -u".to_string()));
+        let res = "EXECUTE: date +%H:%M".parse::<Command>();
+        assert_eq!(res.unwrap(), Command::Execute("date +%H:%M".to_string()));
+    }
+
+    #[test]
+    fn parse_valid_upgrade() {
+        let res = "UPGRADE".parse::<Command>();
+        assert_eq!(res.unwrap(), Command::Upgrade(None));
+        let res = "UPGRADE: 2.0.1".parse::<Command>();
+        assert_eq!(res.unwrap(), Command::Upgrade(Some("2.0.1".to_string())));
+        let res = "UPGRADE: registry:5000/os:1.2".parse::<Command>();
+        assert_eq!(
+            res.unwrap(),
+            Command::Upgrade(Some("registry:5000/os:1.2".to_string()))
+        );
+        let res = "UPGRADE: os:latest".parse::<Command>();
+        assert_eq!(
+            res.unwrap(),
+            Command::Upgrade(Some("os:latest".to_string()))
+        );
+    }
+
+    #[test]
+    fn parse_retry_policy() {
+        let policy = "3 500ms".parse::<RetryPolicy>().unwrap();
+        assert_eq!(policy.retries, 3);
+        assert_eq!(policy.delay(0), Duration::from_millis(500));
+        assert_eq!(policy.delay(2), Duration::from_secs(2));
+        let policy = "2".parse::<RetryPolicy>().unwrap();
+        assert_eq!(policy.backoff, Duration::from_secs(1));
+        assert!(matches!(
+            "many".parse::<RetryPolicy>(),
+            Err(Error::InvalidRetry(_))
+        ));
+        assert!(matches!(
+            "2 soon".parse::<RetryPolicy>(),
+            Err(Error::InvalidDuration(_))
+        ));
+    }
+
+    #[test]
+    fn parse_error_policy() {
+        let res = "continue, ignore-exit-code".parse::<ErrorPolicy>();
+        assert_eq!(
+            res.unwrap(),
+            ErrorPolicy {
+                continue_on_failure: true,
+                ignore_exit_code: true
+            }
+        );
+        assert_eq!(
+            "fail-fast".parse::<ErrorPolicy>().unwrap(),
+            ErrorPolicy::default()
+        );
+        assert!(matches!(
+            "retry".parse::<ErrorPolicy>(),
+            Err(Error::UnknownPolicy(_))
+        ));
+    }
+
+    #[test]
+    fn display_process_output() {
+        let output = CommandOutput::Process {
+            stdout: "6.1.0\n".to_string(),
+            exit_code: 1,
+            stderr: "warning\n".to_string(),
+        };
+        assert_eq!(output.to_string(), "6.1.0\n");
+    }
+
+    #[test]
+    fn display_record_output() {
+        let output = CommandOutput::Record(vec![
+            (
+                "kernel-version".parse().unwrap(),
+                FieldValue::Text("6.1.0".to_string()),
+            ),
+            (
+                "uptime".parse().unwrap(),
+                FieldValue::Duration(Duration::from_secs(90)),
+            ),
+        ]);
+        assert_eq!(
+            output.to_string(),
+            "kernel-version: 6.1.0\nuptime: 1m 30s"
+        );
+    }
+
+    #[test]
+    fn parse_field_names() {
+        assert_eq!(
+            "kernel-version".parse::<FieldName>().unwrap().as_str(),
+            "kernel-version"
+        );
+        assert!("ipv4-address".parse::<FieldName>().is_ok());
+        for name in [
+            "",
+            "Kernel",
+            "kernel_version",
+            "-uptime",
+            "uptime-",
+            "a--b",
+            "2fa",
+            "a b",
+        ] {
+            assert!(matches!(
+                name.parse::<FieldName>(),
+                Err(Error::InvalidFieldName(_))
+            ));
+        }
     }
 }
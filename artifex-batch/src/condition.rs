@@ -0,0 +1,411 @@
+//
+// Copyright (C) 2024 Eric Le Bihan <eric.le.bihan.dev@free.fr>
+//
+// SPDX-License-Identifier: MIT
+//
+
+use crate::ast::Location;
+use crate::command::Error as CommandError;
+use crate::error::Error;
+use crate::expectation::Observation;
+use crate::variables::{self, Variables};
+use humantime::format_duration;
+use regex::Regex;
+use std::{cmp::Ordering, fmt::Display, str::FromStr, time::Duration};
+
+/// Field of the last observed command outputs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Field {
+    /// Exit code of the last `EXECUTE`.
+    ExitCode,
+    /// Standard output of the last `EXECUTE`.
+    Stdout,
+    /// Standard error of the last `EXECUTE`.
+    Stderr,
+    /// Kernel version reported by the last `INSPECT`.
+    KernelVersion,
+    /// System uptime reported by the last `INSPECT`.
+    Uptime,
+}
+
+impl Field {
+    /// Return the name of the field, also used by the records of `INSPECT`
+    /// and by expectations.
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Field::ExitCode => "exit-code",
+            Field::Stdout => "stdout",
+            Field::Stderr => "stderr",
+            Field::KernelVersion => "kernel-version",
+            Field::Uptime => "uptime",
+        }
+    }
+
+    fn value(&self, observation: &Observation) -> Option<Value> {
+        match self {
+            Field::ExitCode => observation.exit_code.map(|c| Value::from_number(c as i64)),
+            Field::Stdout => observation.stdout.as_deref().map(Value::parse),
+            Field::Stderr => observation.stderr.as_deref().map(Value::parse),
+            Field::KernelVersion => observation.kernel_version.as_deref().map(Value::parse),
+            Field::Uptime => observation.uptime.map(Value::from_duration),
+        }
+    }
+}
+
+/// Operand of a condition.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Operand {
+    Field(Field),
+    /// Text where variables are substituted.
+    Text(String),
+}
+
+impl Operand {
+    fn parse(s: &str) -> Result<Self, CommandError> {
+        let s = s.trim();
+        let field = [
+            Field::ExitCode,
+            Field::Stdout,
+            Field::Stderr,
+            Field::KernelVersion,
+            Field::Uptime,
+        ]
+        .into_iter()
+        .find(|f| f.name() == s);
+        if let Some(field) = field {
+            return Ok(Operand::Field(field));
+        }
+        let text = s
+            .strip_prefix('"')
+            .and_then(|s| s.strip_suffix('"'))
+            .unwrap_or(s);
+        variables::references(text)?;
+        Ok(Operand::Text(text.to_string()))
+    }
+
+    fn value(
+        &self,
+        variables: &Variables,
+        observation: &Observation,
+        location: &Location,
+    ) -> Result<Value, Error> {
+        match self {
+            Operand::Field(field) => {
+                field
+                    .value(observation)
+                    .ok_or_else(|| Error::UnavailableValue {
+                        name: field.name().to_string(),
+                        location: location.clone(),
+                    })
+            }
+            Operand::Text(text) => Ok(Value::parse(&variables.expand(text, location)?)),
+        }
+    }
+}
+
+impl Display for Operand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Operand::Field(field) => write!(f, "{}", field.name()),
+            Operand::Text(text) if text.is_empty() || text.contains(' ') => {
+                write!(f, "\"{}\"", text)
+            }
+            Operand::Text(text) => write!(f, "{}", text),
+        }
+    }
+}
+
+/// Value of an operand, with its interpretations as integer and duration.
+#[derive(Debug, PartialEq)]
+struct Value {
+    text: String,
+    number: Option<i64>,
+    duration: Option<Duration>,
+}
+
+impl Value {
+    /// Parse a text, which may also be an integer or a duration with units
+    /// such as `1h`.
+    fn parse(s: &str) -> Self {
+        Self {
+            text: s.to_string(),
+            number: s.trim().parse().ok(),
+            duration: humantime::parse_duration(s.trim()).ok(),
+        }
+    }
+
+    fn from_number(number: i64) -> Self {
+        Self {
+            text: number.to_string(),
+            number: Some(number),
+            duration: None,
+        }
+    }
+
+    fn from_duration(duration: Duration) -> Self {
+        Self {
+            text: format_duration(duration).to_string(),
+            number: None,
+            duration: Some(duration),
+        }
+    }
+
+    /// Return the value in seconds, if it is a duration or an integer.
+    fn seconds(&self) -> Option<f64> {
+        self.duration
+            .map(|d| d.as_secs_f64())
+            .or(self.number.map(|n| n as f64))
+    }
+
+    /// Compare values by value if both are integers or durations, as
+    /// versions otherwise.
+    fn compare(&self, other: &Value) -> Option<Ordering> {
+        match (self.number, other.number) {
+            (Some(a), Some(b)) => Some(a.cmp(&b)),
+            _ => match (self.seconds(), other.seconds()) {
+                (Some(a), Some(b)) => a.partial_cmp(&b),
+                _ => Some(compare_versions(&self.text, &other.text)),
+            },
+        }
+    }
+
+    /// Check values are equal by value if both are integers or durations,
+    /// as texts otherwise.
+    fn equals(&self, other: &Value) -> bool {
+        match (self.seconds(), other.seconds()) {
+            (Some(_), Some(_)) => self.compare(other) == Some(Ordering::Equal),
+            _ => self.text.trim() == other.text.trim(),
+        }
+    }
+}
+
+/// Compare versions such as `6.1.0-13-amd64`, component by component,
+/// numerically when both components are numbers.
+fn compare_versions(a: &str, b: &str) -> Ordering {
+    let split = |s: &str| -> Vec<String> {
+        s.trim()
+            .split(['.', '-', '+', '~', '_'])
+            .map(String::from)
+            .collect()
+    };
+    let (a, b) = (split(a), split(b));
+    for (x, y) in a.iter().zip(b.iter()) {
+        let ordering = match (x.parse::<u64>(), y.parse::<u64>()) {
+            (Ok(x), Ok(y)) => x.cmp(&y),
+            _ => x.cmp(y),
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    a.len().cmp(&b.len())
+}
+
+/// Comparison operator of a condition.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operator {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    Contains,
+    Matches,
+}
+
+const OPERATORS: [(&str, Operator); 8] = [
+    ("==", Operator::Equal),
+    ("!=", Operator::NotEqual),
+    ("<=", Operator::LessOrEqual),
+    (">=", Operator::GreaterOrEqual),
+    ("<", Operator::Less),
+    (">", Operator::Greater),
+    ("contains", Operator::Contains),
+    ("matches", Operator::Matches),
+];
+
+impl Display for Operator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (symbol, _) = OPERATORS.iter().find(|(_, o)| o == self).unwrap();
+        write!(f, "{}", symbol)
+    }
+}
+
+/// Condition guarding the statements of an `IF` block.
+///
+/// Conditions compare an operand to another, such as
+/// `kernel-version < 6.1`, `exit-code != 0`, `uptime > 1h` or
+/// `${release} matches ^2\.`. Operands are either fields of the outputs of
+/// the last commands, or texts where variables are substituted. Integers and
+/// durations are compared by value. Other texts are ordered as versions, and
+/// are equal only if identical, leading and trailing whitespaces aside. A
+/// condition can be negated with `NOT`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Condition {
+    pub negated: bool,
+    pub left: Operand,
+    pub operator: Operator,
+    pub right: Operand,
+}
+
+impl Condition {
+    /// Evaluate the condition, found at `location` in the batch.
+    pub(crate) fn evaluate(
+        &self,
+        variables: &Variables,
+        observation: &Observation,
+        location: &Location,
+    ) -> Result<bool, Error> {
+        let left = self.left.value(variables, observation, location)?;
+        let right = self.right.value(variables, observation, location)?;
+        let result = match self.operator {
+            Operator::Equal => left.equals(&right),
+            Operator::NotEqual => !left.equals(&right),
+            Operator::Less => left.compare(&right) == Some(Ordering::Less),
+            Operator::LessOrEqual => {
+                matches!(left.compare(&right), Some(Ordering::Less | Ordering::Equal))
+            }
+            Operator::Greater => left.compare(&right) == Some(Ordering::Greater),
+            Operator::GreaterOrEqual => matches!(
+                left.compare(&right),
+                Some(Ordering::Greater | Ordering::Equal)
+            ),
+            Operator::Contains => left.text.contains(&right.text),
+            Operator::Matches => Regex::new(&right.text)
+                .map_err(|e| Error::Syntax {
+                    location: location.clone(),
+                    source: CommandError::InvalidCondition(e.to_string()),
+                })?
+                .is_match(&left.text),
+        };
+        Ok(result != self.negated)
+    }
+
+    /// Return the texts of the condition where variables are substituted.
+    pub(crate) fn texts(&self) -> Vec<&str> {
+        [&self.left, &self.right]
+            .into_iter()
+            .filter_map(|o| match o {
+                Operand::Text(text) => Some(text.as_str()),
+                Operand::Field(_) => None,
+            })
+            .collect()
+    }
+}
+
+impl FromStr for Condition {
+    type Err = CommandError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (negated, s) = match s.strip_prefix("NOT ") {
+            Some(s) => (true, s.trim()),
+            None => (false, s),
+        };
+        let words: Vec<&str> = s.split(' ').collect();
+        for (index, word) in words.iter().enumerate() {
+            if let Some((_, operator)) = OPERATORS.iter().find(|(symbol, _)| symbol == word) {
+                let left = words[..index].join(" ");
+                let right = words[index + 1..].join(" ");
+                if left.trim().is_empty() || right.trim().is_empty() {
+                    break;
+                }
+                return Ok(Condition {
+                    negated,
+                    left: Operand::parse(&left)?,
+                    operator: *operator,
+                    right: Operand::parse(&right)?,
+                });
+            }
+        }
+        Err(CommandError::InvalidCondition(s.to_string()))
+    }
+}
+
+impl Display for Condition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.negated {
+            write!(f, "NOT ")?;
+        }
+        write!(f, "{} {} {}", self.left, self.operator, self.right)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn evaluate(condition: &str, observation: &Observation) -> bool {
+        let mut variables = Variables::new();
+        variables.define("release", "2.1.0");
+        condition
+            .parse::<Condition>()
+            .unwrap()
+            .evaluate(&variables, observation, &Location::default())
+            .unwrap()
+    }
+
+    #[test]
+    fn parse_conditions() {
+        let condition = "NOT kernel-version < \"6.1 rc\""
+            .parse::<Condition>()
+            .unwrap();
+        assert_eq!(
+            condition,
+            Condition {
+                negated: true,
+                left: Operand::Field(Field::KernelVersion),
+                operator: Operator::Less,
+                right: Operand::Text("6.1 rc".to_string()),
+            }
+        );
+        assert_eq!(condition.to_string(), "NOT kernel-version < \"6.1 rc\"");
+        assert!("exit-code".parse::<Condition>().is_err());
+        assert!("== 0".parse::<Condition>().is_err());
+    }
+
+    #[test]
+    fn compare_values() {
+        let observation = Observation {
+            exit_code: Some(2),
+            stdout: Some("ready\n".to_string()),
+            kernel_version: Some("6.1.0-13-amd64".to_string()),
+            uptime: Some(Duration::from_secs(7200)),
+            ..Default::default()
+        };
+        assert!(evaluate("exit-code != 0", &observation));
+        assert!(evaluate("exit-code == 2", &observation));
+        assert!(evaluate("kernel-version < 6.10", &observation));
+        assert!(evaluate("kernel-version >= 6.1.0", &observation));
+        assert!(evaluate("uptime > 1h", &observation));
+        assert!(evaluate("uptime <= 7200", &observation));
+        assert!(evaluate("stdout contains ready", &observation));
+        assert!(evaluate("${release} matches ^2\\.", &observation));
+        assert!(!evaluate("NOT ${release} == 2.1.0", &observation));
+        assert!(evaluate("stdout == ready", &observation));
+    }
+
+    #[test]
+    fn compare_versions_and_texts() {
+        let observation = Observation::default();
+        assert!(evaluate("2.9 < 2.10", &observation));
+        assert!(evaluate("2.10 > 2.9", &observation));
+        assert!(!evaluate("2.10 == 2.1", &observation));
+        assert!(!evaluate("foo.bar == foo-bar", &observation));
+        assert!(evaluate("foo.bar != foo-bar", &observation));
+        assert!(evaluate("${release} != 2.1", &observation));
+        assert!(evaluate("90s == 1m30s", &observation));
+    }
+
+    #[test]
+    fn reject_unavailable_value() {
+        let condition = "uptime > 1h".parse::<Condition>().unwrap();
+        let location = Location::new(None, 3);
+        let res = condition.evaluate(&Variables::new(), &Observation::default(), &location);
+        assert!(matches!(
+            res,
+            Err(Error::UnavailableValue { location, .. }) if location.line == 3
+        ));
+    }
+}
@@ -0,0 +1,369 @@
+//
+// Copyright (C) 2024 Eric Le Bihan <eric.le.bihan.dev@free.fr>
+//
+// SPDX-License-Identifier: MIT
+//
+
+use crate::ast::{Location, Statement, Step};
+use crate::command::{self, Command, Error as CommandError, ErrorPolicy, RetryPolicy};
+use crate::condition::Condition;
+use crate::error::Error;
+use crate::expectation::Expectation;
+use crate::variables;
+use humantime::format_duration;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::path::Path;
+
+/// Format of a structured batch definition.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DefinitionFormat {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl DefinitionFormat {
+    /// Return the format matching the extension of `path`, if any.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "json" => Some(DefinitionFormat::Json),
+            "toml" => Some(DefinitionFormat::Toml),
+            "yaml" | "yml" => Some(DefinitionFormat::Yaml),
+            _ => None,
+        }
+    }
+
+    /// Deserialize a value from `text`, written in the format.
+    pub(crate) fn deserialize<T: DeserializeOwned>(&self, text: &str) -> Result<T, String> {
+        match self {
+            DefinitionFormat::Json => serde_json::from_str(text).map_err(|e| e.to_string()),
+            DefinitionFormat::Toml => toml::from_str(text).map_err(|e| e.to_string()),
+            DefinitionFormat::Yaml => serde_yaml::from_str(text).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+/// Structured definition of a batch, with its default error policy.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct BatchDefinition {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub policy: Option<String>,
+    pub steps: Vec<StepDefinition>,
+}
+
+/// How many times a failed step is run again.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct RetryDefinition {
+    count: u32,
+    /// Delay before the first retry, 1 s if not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    backoff: Option<String>,
+}
+
+/// Structured definition of a statement.
+///
+/// A step either runs a `command`, with its modifiers, sets the variable
+/// named by `set` to `value`, runs the `then` or `else` steps depending
+/// on the condition of `if`, or runs the commands of the `parallel` steps
+/// concurrently.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct StepDefinition {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    /// One of `execute`, `inspect` or `upgrade`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    command: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    arguments: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timeout: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry: Option<RetryDefinition>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    policy: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    expectations: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    capture: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    on_failure: Vec<StepDefinition>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    set: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<String>,
+    #[serde(rename = "if", skip_serializing_if = "Option::is_none")]
+    condition: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    then: Vec<StepDefinition>,
+    #[serde(rename = "else", skip_serializing_if = "Vec::is_empty")]
+    otherwise: Vec<StepDefinition>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    parallel: Vec<StepDefinition>,
+}
+
+impl BatchDefinition {
+    /// Read a definition in the given format.
+    pub(crate) fn parse(text: &str, format: DefinitionFormat) -> Result<Self, Error> {
+        format.deserialize(text).map_err(Error::Definition)
+    }
+
+    /// Write the definition in the given format.
+    pub(crate) fn render(&self, format: DefinitionFormat) -> Result<String, Error> {
+        match format {
+            DefinitionFormat::Json => serde_json::to_string_pretty(self).map_err(|e| e.to_string()),
+            DefinitionFormat::Toml => toml::to_string(self).map_err(|e| e.to_string()),
+            DefinitionFormat::Yaml => serde_yaml::to_string(self).map_err(|e| e.to_string()),
+        }
+        .map_err(Error::Definition)
+    }
+
+    /// Return the statements of the batch and its default error policy.
+    ///
+    /// The statements are located in `location`, as the steps have no line.
+    pub(crate) fn compile(
+        self,
+        location: &Location,
+    ) -> Result<(Vec<Statement>, ErrorPolicy), Error> {
+        let policy = match &self.policy {
+            Some(policy) => policy.parse().map_err(|source| Error::InvalidStep {
+                step: "policy".to_string(),
+                source,
+            })?,
+            None => ErrorPolicy::default(),
+        };
+        let statements = compile_steps(self.steps, "steps", location)?;
+        Ok((statements, policy))
+    }
+
+    /// Build the definition of a batch made of `statements`.
+    pub(crate) fn new(statements: &[Statement], policy: ErrorPolicy) -> Self {
+        Self {
+            policy: (policy != ErrorPolicy::default()).then(|| policy.to_string()),
+            steps: statements.iter().map(StepDefinition::from).collect(),
+        }
+    }
+}
+
+fn compile_steps(
+    steps: Vec<StepDefinition>,
+    path: &str,
+    location: &Location,
+) -> Result<Vec<Statement>, Error> {
+    steps
+        .into_iter()
+        .enumerate()
+        .map(|(index, step)| {
+            let path = format!("{}[{}]", path, index);
+            step.compile(&path, location)
+        })
+        .collect()
+}
+
+impl StepDefinition {
+    /// Return whether the step has settings only allowed for commands.
+    fn has_modifiers(&self) -> bool {
+        self.arguments.is_some()
+            || self.timeout.is_some()
+            || self.retry.is_some()
+            || self.policy.is_some()
+            || !self.expectations.is_empty()
+            || self.capture.is_some()
+            || !self.on_failure.is_empty()
+            || !self.tags.is_empty()
+    }
+
+    /// Return the statement defined by the step, found at `path`.
+    fn compile(self, path: &str, location: &Location) -> Result<Statement, Error> {
+        let invalid = |source| Error::InvalidStep {
+            step: path.to_string(),
+            source,
+        };
+        let sequential = self.parallel.is_empty();
+        match (&self.command, &self.set, &self.condition) {
+            (Some(_), None, None) if sequential => self.compile_run(path, location),
+            (None, Some(name), None) if sequential && !self.has_modifiers() => {
+                if !variables::is_valid_name(name) {
+                    return Err(invalid(CommandError::InvalidVariable(name.to_string())));
+                }
+                let value = self.value.unwrap_or_default();
+                variables::references(&value).map_err(invalid)?;
+                Ok(Statement::Set {
+                    name: name.to_string(),
+                    value,
+                    location: location.clone(),
+                })
+            }
+            (None, None, Some(condition)) if sequential && !self.has_modifiers() => {
+                let condition = condition.parse::<Condition>().map_err(invalid)?;
+                Ok(Statement::If {
+                    condition,
+                    then: compile_steps(self.then, &format!("{}.then", path), location)?,
+                    otherwise: compile_steps(self.otherwise, &format!("{}.else", path), location)?,
+                    location: location.clone(),
+                })
+            }
+            (None, None, None) if !sequential && !self.has_modifiers() => {
+                let path = format!("{}.parallel", path);
+                let mut steps = vec![];
+                for (index, statement) in compile_steps(self.parallel, &path, location)?
+                    .into_iter()
+                    .enumerate()
+                {
+                    match statement {
+                        Statement::Run(step) if step.on_failure.is_empty() => steps.push(step),
+                        _ => {
+                            return Err(Error::InvalidStep {
+                                step: format!("{}[{}]", path, index),
+                                source: CommandError::InvalidStep(
+                                    "only commands without on-failure can run in parallel"
+                                        .to_string(),
+                                ),
+                            })
+                        }
+                    }
+                }
+                Ok(Statement::Parallel {
+                    steps,
+                    location: location.clone(),
+                })
+            }
+            _ => Err(invalid(CommandError::InvalidStep(
+                "expected a command, a variable to set, a condition or parallel steps".to_string(),
+            ))),
+        }
+    }
+
+    fn compile_run(self, path: &str, location: &Location) -> Result<Statement, Error> {
+        let invalid = |source| Error::InvalidStep {
+            step: path.to_string(),
+            source,
+        };
+        let name = self.command.unwrap_or_default();
+        let command = match (name.to_ascii_lowercase().as_str(), self.arguments) {
+            ("execute", Some(command)) => Command::Execute(command),
+            ("execute", None) => return Err(invalid(CommandError::MissingArgument)),
+            ("inspect", None) => Command::Inspect,
+            ("inspect", Some(arguments)) => {
+                return Err(invalid(CommandError::InvalidStep(format!(
+                    "inspect takes no arguments: {}",
+                    arguments
+                ))))
+            }
+            ("upgrade", target) => Command::Upgrade(target),
+            _ => return Err(invalid(CommandError::UnknownCommand(name))),
+        };
+        for text in variables::command_texts(&command) {
+            variables::references(text).map_err(invalid)?;
+        }
+        if let Some(capture) = &self.capture {
+            if !variables::is_valid_name(capture) {
+                return Err(invalid(CommandError::InvalidVariable(capture.to_string())));
+            }
+        }
+        let timeout = self
+            .timeout
+            .as_deref()
+            .map(command::parse_duration)
+            .transpose()
+            .map_err(invalid)?;
+        let retry = match self.retry {
+            Some(retry) => Some(RetryPolicy {
+                retries: retry.count,
+                backoff: match retry.backoff.as_deref() {
+                    Some(backoff) => command::parse_duration(backoff).map_err(invalid)?,
+                    None => RetryPolicy::default().backoff,
+                },
+            }),
+            None => None,
+        };
+        let policy = self
+            .policy
+            .as_deref()
+            .map(str::parse::<ErrorPolicy>)
+            .transpose()
+            .map_err(invalid)?;
+        let mut expectations = vec![];
+        for expectation in &self.expectations {
+            let expectation = expectation.parse::<Expectation>().map_err(invalid)?;
+            if !expectation.applies_to(&command) {
+                return Err(invalid(CommandError::InvalidExpectation(format!(
+                    "'{}' does not apply to {}",
+                    expectation, command
+                ))));
+            }
+            expectations.push(expectation);
+        }
+        let on_failure = compile_steps(self.on_failure, &format!("{}.on-failure", path), location)?;
+        Ok(Statement::Run(Step {
+            name: self.name,
+            command,
+            policy,
+            expectations,
+            retry,
+            timeout,
+            capture: self.capture,
+            on_failure,
+            tags: self.tags,
+            location: location.clone(),
+        }))
+    }
+}
+
+impl From<&Step> for StepDefinition {
+    fn from(step: &Step) -> Self {
+        let (command, arguments) = match &step.command {
+            Command::Execute(command) => ("execute", Some(command.clone())),
+            Command::Inspect => ("inspect", None),
+            Command::Upgrade(target) => ("upgrade", target.clone()),
+        };
+        StepDefinition {
+            name: step.name.clone(),
+            command: Some(command.to_string()),
+            arguments,
+            timeout: step.timeout.map(|t| format_duration(t).to_string()),
+            retry: step.retry.map(|r| RetryDefinition {
+                count: r.retries,
+                backoff: Some(format_duration(r.backoff).to_string()),
+            }),
+            policy: step.policy.map(|p| p.to_string()),
+            expectations: step.expectations.iter().map(|e| e.to_string()).collect(),
+            capture: step.capture.clone(),
+            tags: step.tags.clone(),
+            on_failure: step.on_failure.iter().map(StepDefinition::from).collect(),
+            ..Default::default()
+        }
+    }
+}
+
+impl From<&Statement> for StepDefinition {
+    fn from(statement: &Statement) -> Self {
+        match statement {
+            Statement::Run(step) => StepDefinition::from(step),
+            Statement::Set { name, value, .. } => StepDefinition {
+                set: Some(name.clone()),
+                value: Some(value.clone()),
+                ..Default::default()
+            },
+            Statement::If {
+                condition,
+                then,
+                otherwise,
+                ..
+            } => StepDefinition {
+                condition: Some(condition.to_string()),
+                then: then.iter().map(StepDefinition::from).collect(),
+                otherwise: otherwise.iter().map(StepDefinition::from).collect(),
+                ..Default::default()
+            },
+            Statement::Parallel { steps, .. } => StepDefinition {
+                parallel: steps.iter().map(StepDefinition::from).collect(),
+                ..Default::default()
+            },
+        }
+    }
+}
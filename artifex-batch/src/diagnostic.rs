@@ -0,0 +1,143 @@
+//
+// Copyright (C) 2024 Eric Le Bihan <eric.le.bihan.dev@free.fr>
+//
+// SPDX-License-Identifier: MIT
+//
+
+use crate::ast::Location;
+use crate::command::Error as CommandError;
+use std::fmt::Display;
+use std::ops::{Deref, Range};
+
+/// Error found in the source of a batch, with the offending text.
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub location: Location,
+    /// Columns of the offending text in the line, starting from 1.
+    pub columns: Range<usize>,
+    /// Line of the batch holding the offending text.
+    pub text: String,
+    pub error: CommandError,
+}
+
+impl Diagnostic {
+    /// Create a diagnostic for `error`, found in `text`, pointing at the text
+    /// the error refers to, or at the whole statement if it is not found.
+    pub(crate) fn new(location: Location, text: &str, error: CommandError) -> Self {
+        let statement = text.trim();
+        let indent = text.len() - text.trim_start().len();
+        let (start, end) = match error
+            .text()
+            .filter(|t| !t.is_empty())
+            .and_then(|t| statement.find(t).map(|i| (i, i + t.len())))
+        {
+            Some((start, end)) => (indent + start, indent + end),
+            None => (indent, indent + statement.len()),
+        };
+        let column = |offset: usize| text[..offset].chars().count() + 1;
+        Self {
+            location,
+            columns: column(start)..column(end),
+            text: text.to_string(),
+            error,
+        }
+    }
+}
+
+impl Display for Diagnostic {
+    /// Render the diagnostic like a compiler, with carets under the
+    /// offending text.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let number = self.location.line.to_string();
+        let margin = " ".repeat(number.len());
+        writeln!(f, "error: {}", self.error)?;
+        match &self.location.file {
+            Some(file) => write!(
+                f,
+                "{}--> {}:{}:{}",
+                margin,
+                file.display(),
+                self.location.line,
+                self.columns.start
+            )?,
+            None => write!(
+                f,
+                "{}--> line {}, column {}",
+                margin, self.location.line, self.columns.start
+            )?,
+        }
+        if self.text.is_empty() {
+            return Ok(());
+        }
+        let padding: String = self
+            .text
+            .chars()
+            .take(self.columns.start - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let carets = "^".repeat(self.columns.len().max(1));
+        writeln!(f)?;
+        writeln!(f, "{} |", margin)?;
+        writeln!(f, "{} | {}", number, self.text.trim_end())?;
+        write!(f, "{} | {}{}", margin, padding, carets)
+    }
+}
+
+/// Errors found in the source of a batch, in the order they were found.
+#[derive(Debug, Default)]
+pub struct Diagnostics(pub(crate) Vec<Diagnostic>);
+
+impl Deref for Diagnostics {
+    type Target = [Diagnostic];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl Display for Diagnostics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (index, diagnostic) in self.0.iter().enumerate() {
+            if index > 0 {
+                writeln!(f)?;
+                writeln!(f)?;
+            }
+            write!(f, "{}", diagnostic)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    #[test]
+    fn point_at_offending_text() {
+        let diagnostic = Diagnostic::new(
+            Location::new(Some(Arc::from(Path::new("main.txt"))), 12),
+            "\tTIMEOUT never",
+            CommandError::InvalidDuration("never".to_string()),
+        );
+        assert_eq!(diagnostic.columns, 10..15);
+        assert_eq!(
+            diagnostic.to_string(),
+            "error: Invalid duration: never\n  --> main.txt:12:10\n   |\n12 | \tTIMEOUT never\n   | \t        ^^^^^"
+        );
+    }
+
+    #[test]
+    fn point_at_whole_statement() {
+        let diagnostic = Diagnostic::new(
+            Location::new(None, 3),
+            "  EXECUTE",
+            CommandError::MissingArgument,
+        );
+        assert_eq!(diagnostic.columns, 3..10);
+        assert!(diagnostic
+            .to_string()
+            .starts_with("error: Missing argument\n --> line 3, column 3\n"));
+    }
+}
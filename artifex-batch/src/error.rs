@@ -4,17 +4,53 @@
 // SPDX-License-Identifier: MIT
 //
 
+use super::ast::Location;
+use super::diagnostic::Diagnostics;
 use thiserror::Error;
 
 /// Errors raised when processing a batch.
 #[derive(Debug, Error)]
 pub enum Error {
+    #[error("Invalid batch definition: {0}")]
+    Definition(String),
     #[error("Formatting error: {0}")]
     Fmt(#[from] std::fmt::Error),
+    #[error("Invalid inventory: {0}")]
+    Inventory(String),
     #[error("I/O error: {0}")]
     Io(#[from] std::io::Error),
+    #[error("Invalid selector: {0}")]
+    InvalidSelector(String),
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Invalid step {step}: {source}")]
+    InvalidStep {
+        step: String,
+        source: super::command::Error,
+    },
+    #[error("{0}")]
+    Parse(Diagnostics),
     #[error("RPC error: {0}")]
-    Rpc(#[from] tonic::Status),
-    #[error("Syntax error: {0}")]
-    Syntax(#[from] super::command::Error),
+    Rpc(Box<tonic::Status>),
+    #[error("Syntax error at {location}: {source}")]
+    Syntax {
+        location: Location,
+        source: super::command::Error,
+    },
+    #[error("Invalid token: {0}")]
+    Token(String),
+    #[error("Unknown group: {0}")]
+    UnknownGroup(String),
+    #[error("Unknown target: {0}")]
+    UnknownTarget(String),
+    #[error("No value for '{name}' at {location}, no command produced it")]
+    UnavailableValue { name: String, location: Location },
+    #[error("Undefined variable '{name}' at {location}")]
+    UndefinedVariable { name: String, location: Location },
+}
+
+impl From<tonic::Status> for Error {
+    fn from(status: tonic::Status) -> Self {
+        Error::Rpc(Box::new(status))
+    }
 }
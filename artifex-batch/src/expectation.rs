@@ -0,0 +1,250 @@
+//
+// Copyright (C) 2024 Eric Le Bihan <eric.le.bihan.dev@free.fr>
+//
+// SPDX-License-Identifier: MIT
+//
+
+use crate::command::{Command, Error};
+use humantime::format_duration;
+use regex::Regex;
+use std::{fmt::Display, str::FromStr, time::Duration};
+
+/// Represent what a command is expected to produce.
+#[derive(Clone, Debug)]
+pub enum Expectation {
+    /// The command exits with the given code.
+    ExitCode(i32),
+    /// The standard output contains the given text.
+    StdoutContains(String),
+    /// The standard output matches the given regular expression.
+    StdoutMatches(Regex),
+    /// Nothing is written on the standard error.
+    StderrEmpty,
+    /// The kernel version matches the given regular expression.
+    KernelVersionMatches(Regex),
+    /// The machine has been running for longer than the given duration.
+    UptimeAbove(Duration),
+}
+
+/// Hold what was observed when running a command, to check expectations.
+#[derive(Debug, Default)]
+pub(crate) struct Observation {
+    pub exit_code: Option<i32>,
+    pub stdout: Option<String>,
+    pub stderr: Option<String>,
+    pub kernel_version: Option<String>,
+    pub uptime: Option<Duration>,
+}
+
+impl Observation {
+    /// Replace the values observed by `other`, keeping the others.
+    pub(crate) fn update(&mut self, other: Observation) {
+        if other.exit_code.is_some() {
+            self.exit_code = other.exit_code;
+        }
+        if other.stdout.is_some() {
+            self.stdout = other.stdout;
+        }
+        if other.stderr.is_some() {
+            self.stderr = other.stderr;
+        }
+        if other.kernel_version.is_some() {
+            self.kernel_version = other.kernel_version;
+        }
+        if other.uptime.is_some() {
+            self.uptime = other.uptime;
+        }
+    }
+}
+
+impl Expectation {
+    /// Return whether the expectation can be checked for `command`.
+    pub fn applies_to(&self, command: &Command) -> bool {
+        match self {
+            Expectation::ExitCode(_)
+            | Expectation::StdoutContains(_)
+            | Expectation::StdoutMatches(_)
+            | Expectation::StderrEmpty => matches!(command, Command::Execute(_)),
+            Expectation::KernelVersionMatches(_) | Expectation::UptimeAbove(_) => {
+                matches!(command, Command::Inspect)
+            }
+        }
+    }
+
+    /// Return whether the expectation is met by `observation`.
+    pub(crate) fn check(&self, observation: &Observation) -> bool {
+        match self {
+            Expectation::ExitCode(code) => observation.exit_code == Some(*code),
+            Expectation::StdoutContains(text) => {
+                matches!(&observation.stdout, Some(s) if s.contains(text.as_str()))
+            }
+            Expectation::StdoutMatches(regex) => {
+                matches!(&observation.stdout, Some(s) if regex.is_match(s))
+            }
+            Expectation::StderrEmpty => matches!(&observation.stderr, Some(s) if s.is_empty()),
+            Expectation::KernelVersionMatches(regex) => {
+                matches!(&observation.kernel_version, Some(v) if regex.is_match(v))
+            }
+            Expectation::UptimeAbove(duration) => {
+                matches!(observation.uptime, Some(u) if u > *duration)
+            }
+        }
+    }
+}
+
+fn parse_regex(s: &str) -> Result<Regex, Error> {
+    if s.is_empty() {
+        return Err(Error::InvalidExpectation("empty pattern".to_string()));
+    }
+    Regex::new(s).map_err(|e| Error::InvalidExpectation(e.to_string()))
+}
+
+impl FromStr for Expectation {
+    type Err = Error;
+
+    /// Parse expectations such as `exit-code 0`, `stdout contains TEXT`,
+    /// `stdout matches REGEX`, `stderr empty`, `kernel-version matches REGEX`
+    /// or `uptime > DURATION`. Trailing text is rejected.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (subject, rest) = s.split_once(' ').unwrap_or((s, ""));
+        let (verb, argument) = rest.trim().split_once(' ').unwrap_or((rest.trim(), ""));
+        let argument = argument.trim();
+        match (subject, verb) {
+            ("exit-code" | "stderr", _) if !argument.is_empty() => {
+                Err(Error::InvalidExpectation(s.to_string()))
+            }
+            ("exit-code", code) => code
+                .parse()
+                .map(Expectation::ExitCode)
+                .map_err(|_| Error::InvalidExpectation(s.to_string())),
+            ("stdout", "contains") if !argument.is_empty() => {
+                Ok(Expectation::StdoutContains(argument.to_string()))
+            }
+            ("stdout", "matches") => parse_regex(argument).map(Expectation::StdoutMatches),
+            ("stderr", "empty") => Ok(Expectation::StderrEmpty),
+            ("kernel-version", "matches") => {
+                parse_regex(argument).map(Expectation::KernelVersionMatches)
+            }
+            ("uptime", ">") => humantime::parse_duration(argument)
+                .or_else(|_| argument.parse().map(Duration::from_secs))
+                .map(Expectation::UptimeAbove)
+                .map_err(|_| Error::InvalidExpectation(s.to_string())),
+            _ => Err(Error::InvalidExpectation(s.to_string())),
+        }
+    }
+}
+
+impl Display for Expectation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Expectation::ExitCode(code) => write!(f, "exit-code {}", code),
+            Expectation::StdoutContains(text) => write!(f, "stdout contains {}", text),
+            Expectation::StdoutMatches(regex) => write!(f, "stdout matches {}", regex),
+            Expectation::StderrEmpty => write!(f, "stderr empty"),
+            Expectation::KernelVersionMatches(regex) => {
+                write!(f, "kernel-version matches {}", regex)
+            }
+            Expectation::UptimeAbove(duration) => {
+                write!(f, "uptime > {}", format_duration(*duration))
+            }
+        }
+    }
+}
+
+impl PartialEq for Expectation {
+    /// Compare expectations, regular expressions being compared by pattern.
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Expectation::ExitCode(a), Expectation::ExitCode(b)) => a == b,
+            (Expectation::StdoutContains(a), Expectation::StdoutContains(b)) => a == b,
+            (Expectation::StdoutMatches(a), Expectation::StdoutMatches(b))
+            | (Expectation::KernelVersionMatches(a), Expectation::KernelVersionMatches(b)) => {
+                a.as_str() == b.as_str()
+            }
+            (Expectation::StderrEmpty, Expectation::StderrEmpty) => true,
+            (Expectation::UptimeAbove(a), Expectation::UptimeAbove(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+/// Result of the check of an expectation.
+#[derive(Debug, PartialEq)]
+pub struct Assertion {
+    pub expectation: Expectation,
+    pub passed: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_valid_expectations() {
+        assert_eq!(
+            "exit-code 2".parse::<Expectation>().unwrap(),
+            Expectation::ExitCode(2)
+        );
+        assert_eq!(
+            "stdout contains hello world"
+                .parse::<Expectation>()
+                .unwrap(),
+            Expectation::StdoutContains("hello world".to_string())
+        );
+        assert_eq!(
+            "uptime > 5m".parse::<Expectation>().unwrap(),
+            Expectation::UptimeAbove(Duration::from_secs(300))
+        );
+        assert_eq!(
+            "uptime > 60".parse::<Expectation>().unwrap(),
+            Expectation::UptimeAbove(Duration::from_secs(60))
+        );
+        assert!("stdout matches (".parse::<Expectation>().is_err());
+        assert!("stderr full".parse::<Expectation>().is_err());
+        for s in [
+            "exit-code 0 foo",
+            "stderr empty please",
+            "stdout matches",
+            "stdout matches  ",
+            "kernel-version matches",
+        ] {
+            assert!(matches!(
+                s.parse::<Expectation>(),
+                Err(Error::InvalidExpectation(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn compare_expectations() {
+        let parse = |s: &str| s.parse::<Expectation>().unwrap();
+        assert_eq!(parse("stdout matches ^a+$"), parse("stdout matches ^a+$"));
+        assert_ne!(parse("stdout matches ^a+$"), parse("stdout matches ^a*$"));
+        assert_ne!(
+            parse("stdout matches 6\\.1"),
+            parse("kernel-version matches 6\\.1")
+        );
+        assert_ne!(
+            Expectation::StdoutContains("uptime > 1m".to_string()),
+            parse("uptime > 1m")
+        );
+    }
+
+    #[test]
+    fn check_expectations() {
+        let observation = Observation {
+            exit_code: Some(0),
+            stdout: Some("Linux 6.1.0\n".to_string()),
+            stderr: Some(String::new()),
+            ..Default::default()
+        };
+        let check = |s: &str| s.parse::<Expectation>().unwrap().check(&observation);
+        assert!(check("exit-code 0"));
+        assert!(!check("exit-code 1"));
+        assert!(check("stdout matches ^Linux 6\\."));
+        assert!(!check("stdout contains BSD"));
+        assert!(check("stderr empty"));
+        assert!(!check("kernel-version matches .*"));
+    }
+}
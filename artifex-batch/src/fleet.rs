@@ -0,0 +1,254 @@
+//
+// Copyright (C) 2024 Eric Le Bihan <eric.le.bihan.dev@free.fr>
+//
+// SPDX-License-Identifier: MIT
+//
+
+use crate::{
+    batch::Batch,
+    error::Error,
+    inventory::{Target, TlsSettings},
+    report::{BatchReport, FleetReport, TargetOutcome},
+    runner::BatchRunner,
+    variables::Variables,
+};
+
+use artifex_rpc::artifex_client::ArtifexClient;
+use futures_util::{stream::FuturesUnordered, StreamExt};
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Identity};
+
+/// Tell how many targets a `FleetRunner` runs a batch against at once.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FleetMode {
+    /// Run the batch against up to N targets at once, starting the next
+    /// target as soon as one is done.
+    Parallel(usize),
+    /// Run the batch against N targets at once, waiting for all of them to be
+    /// done before starting the next ones.
+    Rolling(usize),
+}
+
+impl Default for FleetMode {
+    fn default() -> Self {
+        FleetMode::Parallel(1)
+    }
+}
+
+/// Allow to run batches of commands against several targets.
+#[derive(Debug)]
+pub struct FleetRunner {
+    targets: Vec<Target>,
+    variables: Variables,
+    mode: FleetMode,
+    max_failures: Option<usize>,
+}
+
+impl FleetRunner {
+    /// Build a `FleetRunner` for `targets`, run one at a time.
+    pub fn new(targets: Vec<Target>) -> Self {
+        Self {
+            targets,
+            variables: Variables::new(),
+            mode: FleetMode::default(),
+            max_failures: None,
+        }
+    }
+
+    /// Set the variables defined before running the batches.
+    pub fn with_variables(mut self, variables: Variables) -> Self {
+        self.variables = variables;
+        self
+    }
+
+    /// Set how many targets the batches are run against at once.
+    pub fn with_mode(mut self, mode: FleetMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Stop starting new targets once the batch failed on `max_failures`
+    /// targets.
+    pub fn with_max_failures(mut self, max_failures: usize) -> Self {
+        self.max_failures = Some(max_failures);
+        self
+    }
+
+    /// Run a batch against the targets.
+    ///
+    /// A target which cannot be reached, or where the batch cannot be run,
+    /// is recorded as failed in the report. Targets not started as too many
+    /// targets failed are recorded as skipped.
+    ///
+    /// The variables of a target are defined for its batch, unless they are
+    /// already defined by the runner. Nothing is run if the batch references
+    /// variables undefined for any target.
+    pub async fn run(&self, batch: &Batch) -> Result<FleetReport, Error> {
+        let variables: Vec<Variables> = self
+            .targets
+            .iter()
+            .map(|target| {
+                let mut variables = self.variables.clone();
+                for (name, value) in &target.variables {
+                    variables.define_default(name, value);
+                }
+                variables
+            })
+            .collect();
+        for v in &variables {
+            batch.check_variables(v)?;
+        }
+        let (size, rolling) = match self.mode {
+            FleetMode::Parallel(size) => (size.max(1), false),
+            FleetMode::Rolling(size) => (size.max(1), true),
+        };
+        let mut outcomes: Vec<Option<TargetOutcome>> = self.targets.iter().map(|_| None).collect();
+        let mut next = 0;
+        let mut failures = 0;
+        let mut running = FuturesUnordered::new();
+        loop {
+            let stopped = matches!(self.max_failures, Some(max) if failures >= max);
+            if !stopped && (!rolling || running.is_empty()) {
+                while running.len() < size && next < self.targets.len() {
+                    running.push(self.run_target(next, batch, variables[next].clone()));
+                    next += 1;
+                }
+            }
+            match running.next().await {
+                Some((index, outcome)) => {
+                    if !outcome.succeeded() {
+                        failures += 1;
+                    }
+                    outcomes[index] = Some(outcome);
+                }
+                None => break,
+            }
+        }
+        let mut report = FleetReport::new();
+        for (target, outcome) in self.targets.iter().zip(outcomes) {
+            report.push(target.clone(), outcome.unwrap_or(TargetOutcome::Skipped));
+        }
+        Ok(report)
+    }
+
+    /// Run a batch against the target at `index`, returning its index along
+    /// with the outcome.
+    async fn run_target(
+        &self,
+        index: usize,
+        batch: &Batch,
+        variables: Variables,
+    ) -> (usize, TargetOutcome) {
+        let target = &self.targets[index];
+        let outcome = match run_on(target, batch, variables).await {
+            Ok(report) => TargetOutcome::Completed(report),
+            Err(reason) => TargetOutcome::Failed(reason),
+        };
+        (index, outcome)
+    }
+}
+
+/// Run a batch against `target`, authenticating with its token, if any.
+async fn run_on(
+    target: &Target,
+    batch: &Batch,
+    variables: Variables,
+) -> Result<BatchReport, String> {
+    let client = connect(target).await?;
+    let mut runner = BatchRunner::new(client)
+        .with_variables(variables)
+        .with_target(&target.name);
+    if let Some(source) = &target.token {
+        let token = source.read().map_err(|e| e.to_string())?;
+        runner = runner.with_token(&token);
+    }
+    runner.run(batch).await.map_err(|e| e.to_string())
+}
+
+/// Build the TLS configuration of a connection from `settings`.
+fn tls_config(settings: &TlsSettings) -> Result<ClientTlsConfig, std::io::Error> {
+    let mut config = ClientTlsConfig::new();
+    if let Some(ca) = &settings.ca {
+        config = config.ca_certificate(Certificate::from_pem(std::fs::read(ca)?));
+    }
+    match (&settings.cert, &settings.key) {
+        (Some(cert), Some(key)) => {
+            config = config.identity(Identity::from_pem(
+                std::fs::read(cert)?,
+                std::fs::read(key)?,
+            ));
+        }
+        (None, None) => (),
+        _ => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "TLS cert and key must be set together",
+            ))
+        }
+    }
+    if let Some(domain) = &settings.domain {
+        config = config.domain_name(domain);
+    }
+    Ok(config)
+}
+
+/// Connect to the server of `target`, using its TLS settings, if any.
+async fn connect(target: &Target) -> Result<ArtifexClient<Channel>, String> {
+    let failed = |e: &dyn std::fmt::Display| format!("Cannot connect to {}: {}", target.url, e);
+    let mut endpoint = Endpoint::from_shared(target.url.clone()).map_err(|e| failed(&e))?;
+    if let Some(settings) = &target.tls {
+        let config = tls_config(settings).map_err(|e| failed(&e))?;
+        endpoint = endpoint.tls_config(config).map_err(|e| failed(&e))?;
+    }
+    ArtifexClient::connect(endpoint)
+        .await
+        .map_err(|e| failed(&e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unreachable_targets(count: usize) -> Vec<Target> {
+        (0..count)
+            .map(|i| Target::new(&format!("target{}", i), "http://127.0.0.1:1"))
+            .collect()
+    }
+
+    fn statuses(report: &FleetReport) -> Vec<&'static str> {
+        report
+            .entries()
+            .iter()
+            .map(|(_, outcome)| match outcome {
+                TargetOutcome::Completed(_) => "completed",
+                TargetOutcome::Failed(_) => "failed",
+                TargetOutcome::Skipped => "skipped",
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn stop_after_failures() {
+        let batch = "INSPECT".parse::<Batch>().unwrap();
+        let runner = FleetRunner::new(unreachable_targets(5))
+            .with_mode(FleetMode::Rolling(2))
+            .with_max_failures(1);
+        let report = runner.run(&batch).await.unwrap();
+        assert_eq!(
+            statuses(&report),
+            vec!["failed", "failed", "skipped", "skipped", "skipped"]
+        );
+        assert!(!report.succeeded());
+        let runner = FleetRunner::new(unreachable_targets(5))
+            .with_mode(FleetMode::Parallel(2))
+            .with_max_failures(2);
+        let report = runner.run(&batch).await.unwrap();
+        assert_eq!(
+            statuses(&report),
+            vec!["failed", "failed", "failed", "skipped", "skipped"]
+        );
+        assert!(matches!(
+            report.get("target0"),
+            Some(TargetOutcome::Failed(reason)) if reason.starts_with("Cannot connect")
+        ));
+    }
+}
@@ -0,0 +1,622 @@
+//
+// Copyright (C) 2024 Eric Le Bihan <eric.le.bihan.dev@free.fr>
+//
+// SPDX-License-Identifier: MIT
+//
+
+use crate::definition::DefinitionFormat;
+use crate::error::Error;
+use crate::variables;
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Settings of the TLS connection to a target.
+///
+/// Relative paths are resolved from the directory of the inventory file.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsSettings {
+    /// PEM file of the certificate authority of the server.
+    pub ca: Option<PathBuf>,
+    /// PEM file of the certificate of the client, along with `key`.
+    pub cert: Option<PathBuf>,
+    /// PEM file of the private key of the client.
+    pub key: Option<PathBuf>,
+    /// Name of the server to check its certificate against, if not the host
+    /// of the URL.
+    pub domain: Option<String>,
+}
+
+/// Where the authentication token of a target is read from, so that the
+/// inventory holds no secret.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub enum TokenSource {
+    /// Environment variable holding the token, written `env:NAME`.
+    Env(String),
+    /// File holding the token, written `file:PATH`, relative to the
+    /// directory of the inventory file.
+    File(PathBuf),
+}
+
+impl TokenSource {
+    /// Return the token.
+    pub fn read(&self) -> Result<String, Error> {
+        match self {
+            TokenSource::Env(name) => std::env::var(name)
+                .map_err(|_| Error::Token(format!("environment variable {} is not set", name))),
+            TokenSource::File(path) => std::fs::read_to_string(path)
+                .map(|token| token.trim().to_string())
+                .map_err(|e| Error::Token(format!("cannot read {}: {}", path.display(), e))),
+        }
+    }
+}
+
+impl FromStr for TokenSource {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("env", name)) if !name.is_empty() => Ok(TokenSource::Env(name.to_string())),
+            Some(("file", path)) if !path.is_empty() => Ok(TokenSource::File(PathBuf::from(path))),
+            _ => Err(Error::Token(format!(
+                "invalid source {}, expected env:NAME or file:PATH",
+                s
+            ))),
+        }
+    }
+}
+
+impl TryFrom<String> for TokenSource {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl Display for TokenSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TokenSource::Env(name) => write!(f, "env:{}", name),
+            TokenSource::File(path) => write!(f, "file:{}", path.display()),
+        }
+    }
+}
+
+/// Requirement on a label of a target.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Requirement {
+    Equals(String, String),
+    Differs(String, String),
+    Exists(String),
+}
+
+/// Select targets by their labels, such as `role=db,site!=paris,backup`,
+/// where all the requirements must be met.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct Selector {
+    requirements: Vec<Requirement>,
+}
+
+impl Selector {
+    /// Return whether `labels` meet the requirements of the selector.
+    pub fn matches(&self, labels: &BTreeMap<String, String>) -> bool {
+        self.requirements.iter().all(|r| match r {
+            Requirement::Equals(key, value) => labels.get(key) == Some(value),
+            Requirement::Differs(key, value) => labels.get(key) != Some(value),
+            Requirement::Exists(key) => labels.contains_key(key),
+        })
+    }
+}
+
+impl FromStr for Selector {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidSelector(s.to_string());
+        let mut requirements = vec![];
+        for requirement in s.split(',').map(str::trim) {
+            let requirement = if let Some((key, value)) = requirement.split_once("!=") {
+                Requirement::Differs(key.trim().to_string(), value.trim().to_string())
+            } else if let Some((key, value)) = requirement.split_once('=') {
+                Requirement::Equals(key.trim().to_string(), value.trim().to_string())
+            } else {
+                Requirement::Exists(requirement.to_string())
+            };
+            let key = match &requirement {
+                Requirement::Equals(key, _)
+                | Requirement::Differs(key, _)
+                | Requirement::Exists(key) => key,
+            };
+            if key.is_empty() {
+                return Err(invalid());
+            }
+            requirements.push(requirement);
+        }
+        Ok(Selector { requirements })
+    }
+}
+
+impl TryFrom<String> for Selector {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl Display for Selector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (index, requirement) in self.requirements.iter().enumerate() {
+            if index > 0 {
+                write!(f, ",")?;
+            }
+            match requirement {
+                Requirement::Equals(key, value) => write!(f, "{}={}", key, value)?,
+                Requirement::Differs(key, value) => write!(f, "{}!={}", key, value)?,
+                Requirement::Exists(key) => write!(f, "{}", key)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Machine running an Artifex server, which batches can be run against.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Target {
+    /// Name of the target in the inventory.
+    #[serde(skip)]
+    pub name: String,
+    /// URL of the server.
+    pub url: String,
+    #[serde(default)]
+    pub tls: Option<TlsSettings>,
+    /// Where the token sent to the server to authenticate is read from.
+    #[serde(default)]
+    pub token: Option<TokenSource>,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// Variables of the batches run against the target.
+    #[serde(default)]
+    pub variables: BTreeMap<String, String>,
+    /// Names of the groups the target belongs to.
+    #[serde(default)]
+    pub groups: Vec<String>,
+}
+
+impl Target {
+    /// Create a target, belonging to no group.
+    pub fn new(name: &str, url: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            url: url.to_string(),
+            tls: None,
+            token: None,
+            labels: BTreeMap::new(),
+            variables: BTreeMap::new(),
+            groups: vec![],
+        }
+    }
+}
+
+/// Group of targets, whose members are listed by name, selected by their
+/// labels, or belong to the nested groups.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Group {
+    /// Names of the targets of the group.
+    pub targets: Vec<String>,
+    /// Names of the groups nested in the group.
+    pub children: Vec<String>,
+    pub selector: Option<Selector>,
+    /// Variables of the batches run against the members of the group.
+    pub variables: BTreeMap<String, String>,
+}
+
+/// Set of named targets, sorted by name, and groups of them.
+///
+/// An inventory can be read from a TOML, YAML or JSON file, such as:
+///
+/// ```toml
+/// [targets.db1]
+/// url = "https://10.0.0.2:50051"
+/// token = "env:DB1_TOKEN"
+/// labels = { role = "db", site = "paris" }
+/// variables = { release = "2.0" }
+/// tls = { ca = "ca.pem" }
+///
+/// [groups.paris]
+/// selector = "site=paris"
+/// children = ["lyon"]
+/// variables = { mirror = "fr.example.com" }
+/// ```
+///
+/// A target belongs to the groups it lists in `groups`, or whose targets,
+/// selector or nested groups include it. The variables of a target override
+/// the ones of its groups, which override the ones of their parent groups.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Inventory {
+    #[serde(alias = "hosts")]
+    targets: BTreeMap<String, Target>,
+    groups: BTreeMap<String, Group>,
+}
+
+impl Inventory {
+    /// Create an empty inventory.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Read an inventory from a file, whose format is guessed from its
+    /// extension, defaulting to TOML.
+    ///
+    /// Relative paths of the TLS settings and token files are resolved from
+    /// the directory of the inventory file.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)?;
+        let format = DefinitionFormat::from_path(path).unwrap_or(DefinitionFormat::Toml);
+        let mut inventory = Self::parse(&text, format)?;
+        if let Some(directory) = path.parent() {
+            inventory.resolve_paths(directory);
+        }
+        Ok(inventory)
+    }
+
+    /// Make the relative paths of the targets relative to `directory`.
+    fn resolve_paths(&mut self, directory: &Path) {
+        for target in self.targets.values_mut() {
+            if let Some(tls) = target.tls.as_mut() {
+                for path in [&mut tls.ca, &mut tls.cert, &mut tls.key]
+                    .into_iter()
+                    .flatten()
+                {
+                    *path = directory.join(&*path);
+                }
+            }
+            if let Some(TokenSource::File(path)) = target.token.as_mut() {
+                *path = directory.join(&*path);
+            }
+        }
+    }
+
+    /// Read an inventory written in the given format.
+    pub fn parse(text: &str, format: DefinitionFormat) -> Result<Self, Error> {
+        let mut inventory: Inventory = format.deserialize(text).map_err(Error::Inventory)?;
+        for (name, target) in inventory.targets.iter_mut() {
+            target.name = name.clone();
+        }
+        inventory.check()?;
+        Ok(inventory)
+    }
+
+    /// Check that the groups reference existing targets and groups, without
+    /// cycles, that the variables have valid names and that the TLS
+    /// certificates of the clients come with their keys.
+    fn check(&self) -> Result<(), Error> {
+        for target in self.targets.values() {
+            if let Some(tls) = &target.tls {
+                if tls.cert.is_some() != tls.key.is_some() {
+                    return Err(Error::Inventory(format!(
+                        "TLS cert and key of target {} must be set together",
+                        target.name
+                    )));
+                }
+            }
+        }
+        let variables = self
+            .targets
+            .values()
+            .flat_map(|t| t.variables.keys())
+            .chain(self.groups.values().flat_map(|g| g.variables.keys()));
+        for name in variables {
+            if !variables::is_valid_name(name) {
+                return Err(Error::Inventory(format!("invalid variable {}", name)));
+            }
+        }
+        for (name, group) in &self.groups {
+            if let Some(target) = group
+                .targets
+                .iter()
+                .find(|t| !self.targets.contains_key(*t))
+            {
+                return Err(Error::UnknownTarget(target.to_string()));
+            }
+            if let Some(child) = group
+                .children
+                .iter()
+                .find(|c| !self.groups.contains_key(*c))
+            {
+                return Err(Error::UnknownGroup(child.to_string()));
+            }
+            self.check_cycle(name, &mut vec![])?;
+        }
+        Ok(())
+    }
+
+    fn check_cycle<'a>(&'a self, name: &'a str, parents: &mut Vec<&'a str>) -> Result<(), Error> {
+        if parents.contains(&name) {
+            parents.push(name);
+            return Err(Error::Inventory(format!(
+                "group cycle {}",
+                parents.join(" -> ")
+            )));
+        }
+        parents.push(name);
+        for child in &self.groups[name].children {
+            self.check_cycle(child, parents)?;
+        }
+        parents.pop();
+        Ok(())
+    }
+
+    /// Add a target, replacing the one with the same name, if any.
+    pub fn add(&mut self, target: Target) {
+        self.targets.insert(target.name.clone(), target);
+    }
+
+    /// Return the target named `name`, as written in the inventory.
+    pub fn get(&self, name: &str) -> Option<&Target> {
+        self.targets.get(name)
+    }
+
+    /// Return the targets of the inventory, as written in it.
+    pub fn targets(&self) -> impl Iterator<Item = &Target> {
+        self.targets.values()
+    }
+
+    /// Return the names of all the groups, defined by the inventory or
+    /// listed by a target.
+    pub fn groups(&self) -> BTreeSet<&str> {
+        self.groups
+            .keys()
+            .map(String::as_str)
+            .chain(
+                self.targets
+                    .values()
+                    .flat_map(|t| t.groups.iter().map(String::as_str)),
+            )
+            .collect()
+    }
+
+    /// Return whether the group named `name` includes `target`.
+    fn includes(&self, name: &str, target: &Target) -> bool {
+        if target.groups.iter().any(|g| g == name) {
+            return true;
+        }
+        match self.groups.get(name) {
+            Some(group) => {
+                group.targets.contains(&target.name)
+                    || matches!(&group.selector, Some(s) if s.matches(&target.labels))
+                    || group.children.iter().any(|c| self.includes(c, target))
+            }
+            None => false,
+        }
+    }
+
+    /// Add to `variables` the ones of the group named `name` and of its
+    /// nested groups, if they include `target`.
+    fn collect_variables(
+        &self,
+        name: &str,
+        target: &Target,
+        variables: &mut BTreeMap<String, String>,
+    ) {
+        let group = &self.groups[name];
+        if !self.includes(name, target) {
+            return;
+        }
+        variables.extend(group.variables.clone());
+        for child in &group.children {
+            self.collect_variables(child, target, variables);
+        }
+    }
+
+    /// Return the target named `name`, with all the groups it belongs to and
+    /// the variables it inherits from them.
+    pub fn resolve(&self, name: &str) -> Option<Target> {
+        let target = self.targets.get(name)?;
+        let mut resolved = target.clone();
+        resolved.groups = self
+            .groups()
+            .into_iter()
+            .filter(|g| self.includes(g, target))
+            .map(String::from)
+            .collect();
+        let children: BTreeSet<&String> = self.groups.values().flat_map(|g| &g.children).collect();
+        let mut variables = BTreeMap::new();
+        for root in self.groups.keys().filter(|g| !children.contains(g)) {
+            self.collect_variables(root, target, &mut variables);
+        }
+        variables.extend(target.variables.clone());
+        resolved.variables = variables;
+        Some(resolved)
+    }
+
+    /// Return the resolved targets named in `names` or belonging to one of
+    /// `groups`, or all of them if none is given, whose labels match all the
+    /// `selectors`.
+    pub fn select(
+        &self,
+        names: &[String],
+        groups: &[String],
+        selectors: &[Selector],
+    ) -> Result<Vec<Target>, Error> {
+        if let Some(name) = names.iter().find(|n| !self.targets.contains_key(*n)) {
+            return Err(Error::UnknownTarget(name.to_string()));
+        }
+        let known = self.groups();
+        if let Some(group) = groups.iter().find(|g| !known.contains(g.as_str())) {
+            return Err(Error::UnknownGroup(group.to_string()));
+        }
+        let all = names.is_empty() && groups.is_empty();
+        Ok(self
+            .targets
+            .keys()
+            .filter_map(|name| self.resolve(name))
+            .filter(|t| {
+                all || names.contains(&t.name) || t.groups.iter().any(|g| groups.contains(g))
+            })
+            .filter(|t| selectors.iter().all(|s| s.matches(&t.labels)))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INVENTORY: &str = r#"
+[targets.web1]
+url = "http://10.0.0.1:50051"
+groups = ["web"]
+labels = { role = "web", site = "lyon" }
+
+[targets.web2]
+url = "https://10.0.0.3:50051"
+groups = ["web"]
+labels = { role = "web", site = "paris" }
+variables = { mirror = "local" }
+token = "env:WEB2_TOKEN"
+tls = { ca = "ca.pem", domain = "web2.example.com" }
+
+[targets.db1]
+url = "http://10.0.0.2:50051"
+labels = { role = "db", site = "paris" }
+
+[groups.paris]
+selector = "site=paris"
+variables = { mirror = "fr.example.com", release = "2.0" }
+
+[groups.france]
+children = ["paris"]
+targets = ["web1"]
+variables = { mirror = "eu.example.com", country = "fr" }
+"#;
+
+    fn names(targets: &[Target]) -> Vec<&str> {
+        targets.iter().map(|t| t.name.as_str()).collect()
+    }
+
+    fn strings(items: &[&str]) -> Vec<String> {
+        items.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn select_targets() {
+        let inventory = Inventory::parse(INVENTORY, DefinitionFormat::Toml).unwrap();
+        assert_eq!(inventory.get("db1").unwrap().url, "http://10.0.0.2:50051");
+        let all = inventory.select(&[], &[], &[]).unwrap();
+        assert_eq!(names(&all), vec!["db1", "web1", "web2"]);
+        let selected = inventory
+            .select(&strings(&["web1"]), &strings(&["paris"]), &[])
+            .unwrap();
+        assert_eq!(names(&selected), vec!["db1", "web1", "web2"]);
+        let selected = inventory.select(&[], &strings(&["web"]), &[]).unwrap();
+        assert_eq!(names(&selected), vec!["web1", "web2"]);
+        let selector = "role=web,site!=lyon".parse::<Selector>().unwrap();
+        let selected = inventory.select(&[], &[], &[selector]).unwrap();
+        assert_eq!(names(&selected), vec!["web2"]);
+        assert!(matches!(
+            inventory.select(&strings(&["web3"]), &[], &[]),
+            Err(Error::UnknownTarget(_))
+        ));
+        assert!(matches!(
+            inventory.select(&[], &strings(&["lyon"]), &[]),
+            Err(Error::UnknownGroup(_))
+        ));
+    }
+
+    #[test]
+    fn resolve_targets() {
+        let inventory = Inventory::parse(INVENTORY, DefinitionFormat::Toml).unwrap();
+        let web2 = inventory.resolve("web2").unwrap();
+        assert_eq!(web2.groups, strings(&["france", "paris", "web"]));
+        assert_eq!(web2.token, Some(TokenSource::Env("WEB2_TOKEN".to_string())));
+        assert_eq!(
+            web2.tls.unwrap().domain.as_deref(),
+            Some("web2.example.com")
+        );
+        let variables: Vec<(&str, &str)> = web2
+            .variables
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        assert_eq!(
+            variables,
+            vec![("country", "fr"), ("mirror", "local"), ("release", "2.0")]
+        );
+        let db1 = inventory.resolve("db1").unwrap();
+        assert_eq!(db1.variables["mirror"], "fr.example.com");
+        let web1 = inventory.resolve("web1").unwrap();
+        assert_eq!(web1.groups, strings(&["france", "web"]));
+        assert_eq!(web1.variables["mirror"], "eu.example.com");
+        assert!(!web1.variables.contains_key("release"));
+    }
+
+    const INVENTORY_YAML: &str = r#"
+hosts:
+  db1:
+    url: http://10.0.0.2:50051
+    token: file:/etc/artifex/db1.token
+groups:
+  a:
+    children: [b]
+  b:
+    children: [a]
+"#;
+
+    #[test]
+    fn resolve_relative_paths() {
+        let dir = std::env::temp_dir().join(format!("artifex-inventory-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("inventory.toml");
+        std::fs::write(
+            &path,
+            "[targets.db1]\nurl = \"https://db1\"\ntoken = \"file:db1.token\"\n\
+             tls = { ca = \"/etc/ca.pem\", cert = \"db1.pem\", key = \"keys/db1.key\" }\n",
+        )
+        .unwrap();
+        let inventory = Inventory::from_file(&path).unwrap();
+        let target = inventory.get("db1").unwrap();
+        assert_eq!(target.token, Some(TokenSource::File(dir.join("db1.token"))));
+        let tls = target.tls.as_ref().unwrap();
+        assert_eq!(tls.ca, Some(PathBuf::from("/etc/ca.pem")));
+        assert_eq!(tls.cert, Some(dir.join("db1.pem")));
+        assert_eq!(tls.key, Some(dir.join("keys/db1.key")));
+    }
+
+    #[test]
+    fn report_invalid_inventories() {
+        assert!(matches!(
+            Inventory::parse(INVENTORY_YAML, DefinitionFormat::Yaml),
+            Err(Error::Inventory(reason)) if reason == "group cycle a -> b -> a"
+        ));
+        let text = "[targets.db1]\nurl = \"http://db1\"\ntoken = \"vault:db1\"\n";
+        assert!(matches!(
+            Inventory::parse(text, DefinitionFormat::Toml),
+            Err(Error::Inventory(_))
+        ));
+        let text = "[targets.db1]\nurl = \"https://db1\"\ntls = { cert = \"db1.pem\" }\n";
+        assert!(matches!(
+            Inventory::parse(text, DefinitionFormat::Toml),
+            Err(Error::Inventory(reason)) if reason.contains("db1")
+        ));
+        let text = "[groups.db]\ntargets = [\"db2\"]\n";
+        assert!(matches!(
+            Inventory::parse(text, DefinitionFormat::Toml),
+            Err(Error::UnknownTarget(_))
+        ));
+        assert!(matches!(
+            "role=db,".parse::<Selector>(),
+            Err(Error::InvalidSelector(_))
+        ));
+    }
+}
@@ -0,0 +1,364 @@
+//
+// Copyright (C) 2024 Eric Le Bihan <eric.le.bihan.dev@free.fr>
+//
+// SPDX-License-Identifier: MIT
+//
+
+use crate::command::{CommandOutput, CommandStatus, FieldValue};
+use crate::report::{Attempt, BatchReport, FleetReport, ReportEntry, TargetOutcome};
+use schemars::gen::SchemaGenerator;
+use schemars::schema::Schema;
+use schemars::{schema_for, JsonSchema};
+use serde::ser::SerializeMap;
+use serde::{Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Version of the schema of the JSON reports, increased when a change breaks
+/// the readers of the previous version.
+pub const REPORT_SCHEMA_VERSION: u32 = 1;
+
+/// Result of a batch, or of one of its commands.
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    Success,
+    Failure,
+    Skipped,
+}
+
+/// Report of a batch run against a target.
+#[derive(Debug, Serialize, JsonSchema)]
+#[schemars(title = "Artifex batch report")]
+struct ReportDocument {
+    /// Version of the schema of the report.
+    schema_version: u32,
+    title: String,
+    /// Creation date of the report, in RFC 3339 format.
+    date: String,
+    result: Status,
+    /// Commands of the batch, in the order they are declared.
+    commands: Vec<CommandDocument>,
+}
+
+/// Execution of a command of the batch.
+#[derive(Debug, Serialize, JsonSchema)]
+struct CommandDocument {
+    command: String,
+    /// Name of the machine the command was run on.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target: Option<String>,
+    status: Status,
+    /// Reason of the failure of the command.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exit_code: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stderr: Option<String>,
+    /// When the first run of the command started, in RFC 3339 format.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    started: Option<String>,
+    /// When the last run of the command ended, in RFC 3339 format.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ended: Option<String>,
+    /// Time spent running the command, in seconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration_s: Option<f64>,
+    expectations: Vec<ExpectationDocument>,
+    /// Runs of the command, more than one if it was retried.
+    attempts: Vec<AttemptDocument>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output: Option<OutputDocument>,
+}
+
+/// Check of an expectation of a command.
+#[derive(Debug, Serialize, JsonSchema)]
+struct ExpectationDocument {
+    expect: String,
+    passed: bool,
+}
+
+/// Run of a command.
+#[derive(Debug, Serialize, JsonSchema)]
+struct AttemptDocument {
+    status: Status,
+    /// Reason of the failure of the run.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+    /// Time spent in the run, in seconds.
+    duration_s: f64,
+}
+
+/// Output of a command: text, a number, or named fields.
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(untagged)]
+enum OutputDocument {
+    Text(String),
+    Number(u32),
+    Record(RecordDocument),
+}
+
+/// Named fields of an output, serialized as an object whose members are in
+/// the order of the fields.
+#[derive(Debug)]
+struct RecordDocument(Vec<(String, FieldDocument)>);
+
+impl Serialize for RecordDocument {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (name, value) in &self.0 {
+            map.serialize_entry(name, value)?;
+        }
+        map.end()
+    }
+}
+
+impl JsonSchema for RecordDocument {
+    fn is_referenceable() -> bool {
+        BTreeMap::<String, FieldDocument>::is_referenceable()
+    }
+
+    fn schema_name() -> String {
+        BTreeMap::<String, FieldDocument>::schema_name()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        BTreeMap::<String, FieldDocument>::json_schema(gen)
+    }
+}
+
+/// Value of a named field of an output. Durations are objects holding a
+/// number of seconds, to tell them from integers.
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(untagged)]
+enum FieldDocument {
+    Text(String),
+    Integer(u64),
+    Duration { seconds: f64 },
+}
+
+/// Outcome of a batch run against a target of a fleet.
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+enum TargetStatus {
+    Success,
+    Failure,
+    /// The batch could not be run.
+    Error,
+    /// The batch was not run, as too many targets failed.
+    Skipped,
+}
+
+/// Report of a batch run against several targets.
+#[derive(Debug, Serialize, JsonSchema)]
+#[schemars(title = "Artifex fleet report")]
+struct FleetDocument {
+    /// Version of the schema of the report.
+    schema_version: u32,
+    /// Creation date of the report, in RFC 3339 format.
+    date: String,
+    result: Status,
+    targets: Vec<TargetDocument>,
+}
+
+/// Outcome of the batch on a target.
+#[derive(Debug, Serialize, JsonSchema)]
+struct TargetDocument {
+    name: String,
+    url: String,
+    status: TargetStatus,
+    /// Reason why the batch could not be run.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    report: Option<ReportDocument>,
+}
+
+fn status(succeeded: bool) -> Status {
+    if succeeded {
+        Status::Success
+    } else {
+        Status::Failure
+    }
+}
+
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs_f64()
+}
+
+impl From<&Attempt> for AttemptDocument {
+    fn from(attempt: &Attempt) -> Self {
+        Self {
+            status: status(attempt.failure.is_none()),
+            reason: attempt.failure.clone(),
+            duration_s: seconds(attempt.duration),
+        }
+    }
+}
+
+impl From<&CommandOutput> for OutputDocument {
+    fn from(output: &CommandOutput) -> Self {
+        match output {
+            CommandOutput::String(text) | CommandOutput::Process { stdout: text, .. } => {
+                OutputDocument::Text(text.clone())
+            }
+            CommandOutput::Uint32(number) => OutputDocument::Number(*number),
+            CommandOutput::Record(fields) => OutputDocument::Record(RecordDocument(
+                fields
+                    .iter()
+                    .map(|(name, value)| {
+                        let value = match value {
+                            FieldValue::Text(text) => FieldDocument::Text(text.clone()),
+                            FieldValue::Integer(number) => FieldDocument::Integer(*number),
+                            FieldValue::Duration(duration) => FieldDocument::Duration {
+                                seconds: seconds(*duration),
+                            },
+                        };
+                        (name.to_string(), value)
+                    })
+                    .collect(),
+            )),
+        }
+    }
+}
+
+impl From<&ReportEntry> for CommandDocument {
+    fn from(entry: &ReportEntry) -> Self {
+        let (status, reason, output) = match &entry.status {
+            CommandStatus::Success(output) => (Status::Success, None, output.as_ref()),
+            CommandStatus::Failure(reason) => (Status::Failure, Some(reason.clone()), None),
+            CommandStatus::Skipped => (Status::Skipped, None, None),
+        };
+        Self {
+            command: entry.command.clone(),
+            target: entry.target.clone(),
+            status,
+            reason,
+            exit_code: entry.exit_code,
+            stderr: entry.stderr.clone(),
+            started: entry.started.map(|date| date.to_rfc3339()),
+            ended: entry.ended.map(|date| date.to_rfc3339()),
+            duration_s: entry.duration.map(seconds),
+            expectations: entry
+                .assertions
+                .iter()
+                .map(|assertion| ExpectationDocument {
+                    expect: assertion.expectation.to_string(),
+                    passed: assertion.passed,
+                })
+                .collect(),
+            attempts: entry.attempts.iter().map(AttemptDocument::from).collect(),
+            output: output.map(OutputDocument::from),
+        }
+    }
+}
+
+impl From<&BatchReport> for ReportDocument {
+    fn from(report: &BatchReport) -> Self {
+        Self {
+            schema_version: REPORT_SCHEMA_VERSION,
+            title: report.title().to_string(),
+            date: report.date().to_rfc3339(),
+            result: status(report.succeeded()),
+            commands: report.entries().iter().map(CommandDocument::from).collect(),
+        }
+    }
+}
+
+impl From<&FleetReport> for FleetDocument {
+    fn from(report: &FleetReport) -> Self {
+        Self {
+            schema_version: REPORT_SCHEMA_VERSION,
+            date: report.date().to_rfc3339(),
+            result: status(report.succeeded()),
+            targets: report
+                .entries()
+                .iter()
+                .map(|(target, outcome)| {
+                    let (status, reason, report) = match outcome {
+                        TargetOutcome::Completed(report) => {
+                            let status = if report.succeeded() {
+                                TargetStatus::Success
+                            } else {
+                                TargetStatus::Failure
+                            };
+                            (status, None, Some(ReportDocument::from(report)))
+                        }
+                        TargetOutcome::Failed(reason) => {
+                            (TargetStatus::Error, Some(reason.clone()), None)
+                        }
+                        TargetOutcome::Skipped => (TargetStatus::Skipped, None, None),
+                    };
+                    TargetDocument {
+                        name: target.name.clone(),
+                        url: target.url.clone(),
+                        status,
+                        reason,
+                        report,
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Write a report as a JSON document.
+pub(crate) fn to_json(report: &BatchReport) -> Result<String, serde_json::Error> {
+    serde_json::to_string_pretty(&ReportDocument::from(report))
+}
+
+/// Write the report of a fleet as a JSON document.
+pub(crate) fn fleet_to_json(report: &FleetReport) -> Result<String, serde_json::Error> {
+    serde_json::to_string_pretty(&FleetDocument::from(report))
+}
+
+/// Return the JSON Schema of the reports of batches, or of fleets if `fleet`
+/// is set, as rendered by `MarkupKind::Json`.
+pub fn report_schema(fleet: bool) -> String {
+    let schema = if fleet {
+        schema_for!(FleetDocument)
+    } else {
+        schema_for!(ReportDocument)
+    };
+    // Serializing a schema cannot fail, as its maps have string keys.
+    serde_json::to_string_pretty(&schema).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keep_record_order() {
+        let output = CommandOutput::Record(vec![
+            ("restarts".parse().unwrap(), FieldValue::Integer(90)),
+            (
+                "uptime".parse().unwrap(),
+                FieldValue::Duration(Duration::from_millis(90_500)),
+            ),
+            (
+                "kernel-version".parse().unwrap(),
+                FieldValue::Text("6.1.0".to_string()),
+            ),
+        ]);
+        assert_eq!(
+            serde_json::to_string(&OutputDocument::from(&output)).unwrap(),
+            r#"{"restarts":90,"uptime":{"seconds":90.5},"kernel-version":"6.1.0"}"#
+        );
+    }
+
+    #[test]
+    fn publish_schemas() {
+        for (fleet, published) in [
+            (false, include_str!("../schema/report.schema.json")),
+            (true, include_str!("../schema/fleet-report.schema.json")),
+        ] {
+            assert_eq!(
+                report_schema(fleet),
+                published.trim_end(),
+                "Outdated schema, run `artifex-client-cli schema` to update it"
+            );
+        }
+    }
+}
@@ -4,13 +4,30 @@
 // SPDX-License-Identifier: MIT
 //
 
+mod ast;
 mod batch;
 mod command;
+mod condition;
+mod definition;
+mod diagnostic;
 mod error;
+mod expectation;
+mod fleet;
+mod inventory;
+mod json;
+mod parser;
 mod report;
 mod runner;
+mod variables;
 
 pub use batch::Batch;
+pub use definition::DefinitionFormat;
+pub use diagnostic::{Diagnostic, Diagnostics};
 pub use error::Error;
-pub use report::{BatchReport, MarkupKind, MarkupReportRenderer};
+pub use expectation::{Assertion, Expectation};
+pub use fleet::{FleetMode, FleetRunner};
+pub use inventory::{Group, Inventory, Selector, Target, TlsSettings, TokenSource};
+pub use json::{report_schema, REPORT_SCHEMA_VERSION};
+pub use report::{BatchReport, FleetReport, MarkupKind, MarkupReportRenderer, TargetOutcome};
 pub use runner::BatchRunner;
+pub use variables::Variables;
@@ -0,0 +1,582 @@
+//
+// Copyright (C) 2024 Eric Le Bihan <eric.le.bihan.dev@free.fr>
+//
+// SPDX-License-Identifier: MIT
+//
+
+use crate::ast::{Location, Statement, Step};
+use crate::command::{self, Command, Error as CommandError, ErrorPolicy};
+use crate::condition::{Condition, Operand};
+use crate::diagnostic::{Diagnostic, Diagnostics};
+use crate::error::Error;
+use crate::expectation::Expectation;
+use crate::variables;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Parse `name = value`.
+fn parse_assignment(s: &str) -> Result<(String, String), CommandError> {
+    let (name, value) = s
+        .split_once('=')
+        .ok_or_else(|| CommandError::InvalidAssignment(s.to_string()))?;
+    let name = name.trim();
+    if !variables::is_valid_name(name) {
+        return Err(CommandError::InvalidVariable(name.to_string()));
+    }
+    let value = value.trim();
+    variables::references(value)?;
+    Ok((name.to_string(), value.to_string()))
+}
+
+/// Parse a command, optionally followed by `-> name` to capture its output.
+///
+/// The arrow must be preceded by a whitespace and followed by a variable name
+/// only, otherwise it is part of the command, as in `EXECUTE: echo a->b`.
+fn parse_command(s: &str) -> Result<(Command, Option<String>), CommandError> {
+    let capture = s.rsplit_once("->").filter(|(command, name)| {
+        command.ends_with(char::is_whitespace) && variables::is_valid_name(name.trim())
+    });
+    let (s, capture) = match capture {
+        Some((command, name)) => (command.trim(), Some(name.trim().to_string())),
+        None => (s, None),
+    };
+    let command = Command::from_str(s)?;
+    for text in variables::command_texts(&command) {
+        variables::references(text)?;
+    }
+    Ok((command, capture))
+}
+
+/// Parse `name(arg1, arg2)`, where the parentheses are optional when there
+/// are no arguments.
+fn parse_signature(s: &str) -> Option<(String, Vec<String>)> {
+    let s = s.trim();
+    let (name, args) = match s.split_once('(') {
+        Some((name, args)) => (name.trim(), args.trim().strip_suffix(')')?),
+        None => (s, ""),
+    };
+    if !variables::is_valid_name(name) {
+        return None;
+    }
+    let args = match args.trim() {
+        "" => vec![],
+        args => args
+            .split(',')
+            .map(|a| {
+                let a = a.trim();
+                a.strip_prefix('"')
+                    .and_then(|a| a.strip_suffix('"'))
+                    .unwrap_or(a)
+                    .to_string()
+            })
+            .collect(),
+    };
+    Some((name.to_string(), args))
+}
+
+/// Procedure declared by `DEFINE`, with the names of its parameters.
+#[derive(Debug)]
+struct Procedure {
+    params: Vec<String>,
+    body: Vec<Statement>,
+}
+
+/// Substitute the arguments of a call to the parameters referenced in
+/// `statements`.
+fn instantiate(
+    statements: &mut [Statement],
+    args: &HashMap<&str, &str>,
+) -> Result<(), CommandError> {
+    for statement in statements {
+        match statement {
+            Statement::Run(step) => {
+                instantiate_command(&mut step.command, args)?;
+                instantiate(&mut step.on_failure, args)?;
+            }
+            Statement::Set { value, .. } => *value = variables::substitute(value, args)?,
+            Statement::If {
+                condition,
+                then,
+                otherwise,
+                ..
+            } => {
+                for operand in [&mut condition.left, &mut condition.right] {
+                    if let Operand::Text(text) = operand {
+                        *text = variables::substitute(text, args)?;
+                    }
+                }
+                instantiate(then, args)?;
+                instantiate(otherwise, args)?;
+            }
+            Statement::Parallel { steps, .. } => {
+                for step in steps {
+                    instantiate_command(&mut step.command, args)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn instantiate_command(
+    command: &mut Command,
+    args: &HashMap<&str, &str>,
+) -> Result<(), CommandError> {
+    match command {
+        Command::Execute(text) | Command::Upgrade(Some(text)) => {
+            *text = variables::substitute(text, args)?;
+        }
+        Command::Inspect | Command::Upgrade(None) => {}
+    }
+    Ok(())
+}
+
+/// Kind of block being parsed.
+#[derive(Debug)]
+enum Block {
+    Root,
+    Then {
+        condition: Condition,
+    },
+    Else {
+        condition: Condition,
+        then: Vec<Statement>,
+    },
+    OnFailure,
+    Define {
+        name: String,
+        params: Vec<String>,
+    },
+    Parallel,
+    /// Block whose opening line is invalid, discarded when closed.
+    Invalid(&'static str),
+}
+
+impl Block {
+    fn keyword(&self) -> &'static str {
+        match self {
+            Block::Root => "",
+            Block::Then { .. } | Block::Else { .. } => "IF",
+            Block::OnFailure => "ON FAILURE",
+            Block::Define { .. } => "DEFINE",
+            Block::Parallel => "PARALLEL",
+            Block::Invalid(keyword) => keyword,
+        }
+    }
+}
+
+/// Return the keyword of the block opened by `line`, if any.
+fn opened_block(line: &str) -> Option<&'static str> {
+    if line.starts_with("IF ") {
+        Some("IF")
+    } else if line.starts_with("DEFINE ") {
+        Some("DEFINE")
+    } else if line == "ON FAILURE" {
+        Some("ON FAILURE")
+    } else if line == "PARALLEL" {
+        Some("PARALLEL")
+    } else {
+        None
+    }
+}
+
+/// Keywords of the statements which cannot be part of a `PARALLEL` block.
+const SEQUENTIAL_KEYWORDS: [&str; 9] = [
+    "IF ",
+    "ELSE",
+    "DEFINE ",
+    "CALL ",
+    "SET ",
+    "ON FAILURE",
+    "PARALLEL",
+    "DEFAULT POLICY",
+    "INCLUDE ",
+];
+
+/// Return whether `line` modifies the command preceding it.
+fn is_modifier(line: &str) -> bool {
+    line == "ON FAILURE"
+        || ["POLICY ", "RETRY ", "TIMEOUT ", "EXPECT "]
+            .iter()
+            .any(|keyword| line.starts_with(keyword))
+}
+
+/// Block being parsed, with the location and the line where it starts and
+/// its statements.
+#[derive(Debug)]
+struct Frame {
+    block: Block,
+    location: Location,
+    text: String,
+    statements: Vec<Statement>,
+}
+
+/// Build the statements of a batch, line by line.
+///
+/// Included files are parsed by a nested parser, sharing the procedures
+/// defined so far.
+///
+/// Parsing goes on after an invalid line, so that all the errors of the batch
+/// are reported. The modifiers following an invalid command are ignored, and
+/// a block whose opening line is invalid is discarded, to avoid reporting
+/// errors caused by previous ones.
+#[derive(Debug)]
+pub(crate) struct Parser {
+    stack: Vec<Frame>,
+    policy: ErrorPolicy,
+    /// File being parsed, if any.
+    file: Option<Arc<Path>>,
+    procedures: HashMap<String, Procedure>,
+    /// Canonical paths of the files being included, to detect cycles.
+    includes: Vec<PathBuf>,
+    /// Line being parsed, as found in the batch.
+    text: String,
+    /// Whether the last statement is invalid.
+    recovering: bool,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Parser {
+    pub(crate) fn new() -> Self {
+        Self {
+            stack: vec![Frame {
+                block: Block::Root,
+                location: Location::default(),
+                text: String::new(),
+                statements: vec![],
+            }],
+            policy: ErrorPolicy::default(),
+            file: None,
+            procedures: HashMap::new(),
+            includes: vec![],
+            text: String::new(),
+            recovering: false,
+            diagnostics: vec![],
+        }
+    }
+
+    /// Create a parser for the lines of the file at `path`, resolving
+    /// includes relative to it.
+    pub(crate) fn with_file(path: &Path) -> Self {
+        let mut parser = Self::new();
+        parser.file = Some(Arc::from(path));
+        parser.includes.extend(path.canonicalize().ok());
+        parser
+    }
+
+    fn location(&self, line: usize) -> Location {
+        Location::new(self.file.clone(), line)
+    }
+
+    fn statements_mut(&mut self) -> &mut Vec<Statement> {
+        &mut self.stack.last_mut().unwrap().statements
+    }
+
+    /// Return the step the modifier in `line` applies to.
+    fn last_step_mut(&mut self, line: &str) -> Result<&mut Step, CommandError> {
+        match self.statements_mut().last_mut() {
+            Some(Statement::Run(step)) => Ok(step),
+            _ => Err(CommandError::MisplacedModifier(line.to_string())),
+        }
+    }
+
+    fn push_block(&mut self, block: Block, line: usize) {
+        let location = self.location(line);
+        self.stack.push(Frame {
+            block,
+            location,
+            text: self.text.clone(),
+            statements: vec![],
+        });
+    }
+
+    /// Close the innermost block.
+    fn end_block(&mut self) -> Result<(), CommandError> {
+        if self.stack.len() == 1 {
+            return Err(CommandError::UnexpectedKeyword("END".to_string()));
+        }
+        let frame = self.stack.pop().unwrap();
+        let statement = match frame.block {
+            Block::Root => unreachable!(),
+            Block::Then { condition } => Statement::If {
+                condition,
+                then: frame.statements,
+                otherwise: vec![],
+                location: frame.location,
+            },
+            Block::Else { condition, then } => Statement::If {
+                condition,
+                then,
+                otherwise: frame.statements,
+                location: frame.location,
+            },
+            Block::OnFailure => {
+                let step = self.last_step_mut("ON FAILURE")?;
+                step.on_failure = frame.statements;
+                return Ok(());
+            }
+            Block::Define { name, params } => {
+                let body = frame.statements;
+                self.procedures.insert(name, Procedure { params, body });
+                return Ok(());
+            }
+            Block::Parallel => Statement::Parallel {
+                steps: frame
+                    .statements
+                    .into_iter()
+                    .filter_map(|statement| match statement {
+                        Statement::Run(step) => Some(step),
+                        _ => None,
+                    })
+                    .collect(),
+                location: frame.location,
+            },
+            Block::Invalid(_) => return Ok(()),
+        };
+        self.statements_mut().push(statement);
+        Ok(())
+    }
+
+    /// Parse the lines of a batch.
+    pub(crate) fn parse_lines<I: IntoIterator<Item = String>>(&mut self, lines: I) {
+        for (index, line) in lines.into_iter().enumerate() {
+            self.parse_line(&line, index + 1);
+        }
+    }
+
+    /// Parse a line of a batch, found at line `number`, skipping blank lines
+    /// and comments.
+    pub(crate) fn parse_line(&mut self, line: &str, number: usize) {
+        self.text = line.to_string();
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return;
+        }
+        if self.recovering && is_modifier(line) {
+            if line == "ON FAILURE" {
+                self.push_block(Block::Invalid("ON FAILURE"), number);
+            }
+            return;
+        }
+        let depth = self.stack.len();
+        let res = self
+            .check_block(line)
+            .and_then(|()| match line.strip_prefix("INCLUDE ") {
+                Some(path) => self.include(path.trim()),
+                None => self.parse_statement(line, number),
+            });
+        match res {
+            Ok(()) => {
+                if !is_modifier(line) {
+                    self.recovering = false;
+                }
+            }
+            Err(error) => {
+                if let Some(keyword) = opened_block(line).filter(|_| self.stack.len() == depth) {
+                    self.push_block(Block::Invalid(keyword), number);
+                }
+                if !is_modifier(line) {
+                    self.recovering = true;
+                }
+                self.report(number, error);
+            }
+        }
+    }
+
+    /// Check that the statement in `line` can be part of the innermost block,
+    /// a `PARALLEL` block only holding commands and their modifiers.
+    fn check_block(&self, line: &str) -> Result<(), CommandError> {
+        if !matches!(self.stack.last().unwrap().block, Block::Parallel) {
+            return Ok(());
+        }
+        match SEQUENTIAL_KEYWORDS.iter().find(|k| line.starts_with(*k)) {
+            Some(keyword) => Err(CommandError::UnexpectedKeyword(
+                keyword.trim_end().to_string(),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Report that the line at `number` cannot be read.
+    pub(crate) fn reject_line(&mut self, number: usize, error: CommandError) {
+        self.text.clear();
+        self.recovering = true;
+        self.report(number, error);
+    }
+
+    fn report(&mut self, number: usize, error: CommandError) {
+        let location = self.location(number);
+        self.diagnostics
+            .push(Diagnostic::new(location, &self.text, error));
+    }
+
+    /// Parse the file at `path`, relative to the file being parsed, and add
+    /// its statements to the current block.
+    fn include(&mut self, name: &str) -> Result<(), CommandError> {
+        let path = match self.file.as_deref().and_then(Path::parent) {
+            Some(parent) => parent.join(name),
+            None => PathBuf::from(name),
+        };
+        let read = |path: &Path| {
+            let canonical = path.canonicalize()?;
+            std::fs::read_to_string(path).map(|text| (canonical, text))
+        };
+        let (canonical, text) =
+            read(&path).map_err(|e| CommandError::Include(name.to_string(), e))?;
+        if self.includes.contains(&canonical) {
+            return Err(CommandError::IncludeCycle(name.to_string()));
+        }
+        let mut parser = Self::with_file(&path);
+        parser.policy = self.policy;
+        parser.procedures = std::mem::take(&mut self.procedures);
+        parser.includes = self.includes.clone();
+        parser.includes.push(canonical);
+        parser.parse_lines(text.lines().map(String::from));
+        let statements = parser.close();
+        self.procedures = parser.procedures;
+        self.policy = parser.policy;
+        self.diagnostics.append(&mut parser.diagnostics);
+        self.statements_mut().extend(statements);
+        Ok(())
+    }
+
+    /// Return the statements of the procedure called by `call`.
+    fn call(&self, call: &str) -> Result<Vec<Statement>, CommandError> {
+        let (name, args) =
+            parse_signature(call).ok_or_else(|| CommandError::InvalidCall(call.to_string()))?;
+        let procedure = self
+            .procedures
+            .get(&name)
+            .ok_or(CommandError::UnknownProcedure(name))?;
+        if args.len() != procedure.params.len() {
+            return Err(CommandError::InvalidCall(format!(
+                "{} expects {} argument(s)",
+                call,
+                procedure.params.len()
+            )));
+        }
+        let args: HashMap<&str, &str> = procedure
+            .params
+            .iter()
+            .map(String::as_str)
+            .zip(args.iter().map(String::as_str))
+            .collect();
+        let mut body = procedure.body.clone();
+        instantiate(&mut body, &args)?;
+        Ok(body)
+    }
+
+    fn parse_statement(&mut self, line: &str, number: usize) -> Result<(), CommandError> {
+        if let Some(condition) = line.strip_prefix("IF ") {
+            let condition = condition.parse()?;
+            self.push_block(Block::Then { condition }, number);
+        } else if line == "ELSE" {
+            let frame = self.stack.last_mut().unwrap();
+            match std::mem::replace(&mut frame.block, Block::Root) {
+                Block::Then { condition } => {
+                    let then = std::mem::take(&mut frame.statements);
+                    frame.block = Block::Else { condition, then };
+                }
+                Block::Invalid("IF") => frame.block = Block::Invalid("IF"),
+                block => {
+                    frame.block = block;
+                    return Err(CommandError::UnexpectedKeyword(line.to_string()));
+                }
+            }
+        } else if let Some(signature) = line.strip_prefix("DEFINE ") {
+            let (name, params) = parse_signature(signature)
+                .filter(|(_, params)| params.iter().all(|p| variables::is_valid_name(p)))
+                .ok_or_else(|| CommandError::InvalidProcedure(signature.to_string()))?;
+            if self.stack.len() > 1 {
+                return Err(CommandError::UnexpectedKeyword("DEFINE".to_string()));
+            }
+            if self.procedures.contains_key(&name) {
+                return Err(CommandError::InvalidProcedure(format!(
+                    "{} is already defined",
+                    name
+                )));
+            }
+            self.push_block(Block::Define { name, params }, number);
+        } else if let Some(call) = line.strip_prefix("CALL ") {
+            let statements = self.call(call)?;
+            self.statements_mut().extend(statements);
+        } else if line == "PARALLEL" {
+            self.push_block(Block::Parallel, number);
+        } else if line == "END" {
+            self.end_block()?;
+        } else if line == "ON FAILURE" {
+            let step = self.last_step_mut(line)?;
+            if !step.on_failure.is_empty() {
+                return Err(CommandError::UnexpectedKeyword(line.to_string()));
+            }
+            self.push_block(Block::OnFailure, number);
+        } else if let Some(policy) = line.strip_prefix("DEFAULT POLICY ") {
+            if self.stack.len() > 1 || !self.stack[0].statements.is_empty() {
+                return Err(CommandError::UnexpectedKeyword(
+                    "DEFAULT POLICY".to_string(),
+                ));
+            }
+            self.policy = policy.parse()?;
+        } else if let Some(policy) = line.strip_prefix("POLICY ") {
+            let step = self.last_step_mut(line)?;
+            step.policy = Some(policy.parse()?);
+        } else if let Some(retry) = line.strip_prefix("RETRY ") {
+            let step = self.last_step_mut(line)?;
+            step.retry = Some(retry.parse()?);
+        } else if let Some(timeout) = line.strip_prefix("TIMEOUT ") {
+            let step = self.last_step_mut(line)?;
+            step.timeout = Some(command::parse_duration(timeout)?);
+        } else if let Some(assignment) = line.strip_prefix("SET ") {
+            let (name, value) = parse_assignment(assignment)?;
+            let location = self.location(number);
+            self.statements_mut().push(Statement::Set {
+                name,
+                value,
+                location,
+            });
+        } else if let Some(expectation) = line.strip_prefix("EXPECT ") {
+            let step = self.last_step_mut(line)?;
+            let expectation = expectation.parse::<Expectation>()?;
+            if !expectation.applies_to(&step.command) {
+                return Err(CommandError::InvalidExpectation(format!(
+                    "'{}' does not apply to {}",
+                    expectation, step.command
+                )));
+            }
+            step.expectations.push(expectation);
+        } else {
+            let (command, capture) = parse_command(line)?;
+            let location = self.location(number);
+            self.statements_mut().push(Statement::Run(Step {
+                capture,
+                location,
+                ..Step::from(command)
+            }));
+        }
+        Ok(())
+    }
+
+    /// Return the statements of the root block, reporting the blocks not
+    /// closed by `END`.
+    fn close(&mut self) -> Vec<Statement> {
+        while self.stack.len() > 1 {
+            let frame = self.stack.pop().unwrap();
+            let error = CommandError::UnclosedBlock(frame.block.keyword().to_string());
+            self.diagnostics
+                .push(Diagnostic::new(frame.location, &frame.text, error));
+        }
+        std::mem::take(&mut self.stack[0].statements)
+    }
+
+    /// Return the statements of the batch and its default error policy,
+    /// once all the lines are parsed, or all the errors found in them.
+    pub(crate) fn finish(mut self) -> Result<(Vec<Statement>, ErrorPolicy), Error> {
+        let statements = self.close();
+        if !self.diagnostics.is_empty() {
+            return Err(Error::Parse(Diagnostics(self.diagnostics)));
+        }
+        Ok((statements, self.policy))
+    }
+}
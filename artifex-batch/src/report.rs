@@ -5,16 +5,95 @@
 //
 
 use chrono::prelude::*;
+use humantime::format_duration;
 use std::io::Write;
+use std::time::Duration;
 
-use crate::command::{Command, CommandOutput, CommandStatus};
+use crate::command::{CommandOutput, CommandStatus, FieldValue};
 use crate::error::Error;
+use crate::expectation::Assertion;
+use crate::inventory::Target;
+use crate::json;
 
 /// Hold information about the execution of a command.
+///
+/// The exit code and error output of a process are held here rather than in
+/// its `CommandOutput`, as they are also reported when the command fails,
+/// which leaves it without output.
 #[derive(Debug)]
 pub struct ReportEntry {
-    pub command: Command,
+    /// Text of the command, or of the statement which failed before any
+    /// command could run.
+    pub command: String,
+    /// Name of the machine the command was run on, if known.
+    pub target: Option<String>,
     pub status: CommandStatus,
+    /// Exit code of the last run of the command, if it executed a process.
+    pub exit_code: Option<i32>,
+    /// Error output of the last run of the command, if it executed a process.
+    pub stderr: Option<String>,
+    /// Results of the checks of the expectations of the command.
+    pub assertions: Vec<Assertion>,
+    /// Runs of the command, more than one if it was retried.
+    pub attempts: Vec<Attempt>,
+    /// When the first run of the command started, if it was run.
+    pub started: Option<DateTime<Utc>>,
+    /// When the last run of the command ended, if it was run.
+    pub ended: Option<DateTime<Utc>>,
+    /// Time spent running the command, including the delays between retries.
+    pub duration: Option<Duration>,
+}
+
+impl ReportEntry {
+    /// Return an entry for `command`, which was not run.
+    pub(crate) fn new<T: ToString>(command: T, status: CommandStatus) -> Self {
+        Self {
+            command: command.to_string(),
+            target: None,
+            status,
+            exit_code: None,
+            stderr: None,
+            assertions: vec![],
+            attempts: vec![],
+            started: None,
+            ended: None,
+            duration: None,
+        }
+    }
+}
+
+/// Hold information about a run of a command.
+#[derive(Debug)]
+pub struct Attempt {
+    /// Reason of the failure of the run, if any.
+    pub failure: Option<String>,
+    pub duration: Duration,
+}
+
+impl Attempt {
+    fn status(&self) -> &'static str {
+        if self.failure.is_some() {
+            "failure"
+        } else {
+            "success"
+        }
+    }
+
+    /// Return the duration of the run, rounded to the millisecond.
+    fn rounded_duration(&self) -> String {
+        rounded_duration(self.duration)
+    }
+}
+
+/// Format a duration as a number of seconds, such as `90` or `1.5`.
+fn seconds(duration: Duration) -> String {
+    duration.as_secs_f64().to_string()
+}
+
+/// Format `duration`, rounded to the millisecond.
+fn rounded_duration(duration: Duration) -> String {
+    let duration = Duration::from_millis(duration.as_millis() as u64);
+    format_duration(duration).to_string()
 }
 
 #[derive(Debug)]
@@ -49,6 +128,15 @@ impl BatchReport {
         &self.entries
     }
 
+    /// Return whether all the commands of a `Report` succeeded.
+    ///
+    /// Failures of commands allowed to continue still fail the batch.
+    pub fn succeeded(&self) -> bool {
+        self.entries
+            .iter()
+            .all(|e| matches!(e.status, CommandStatus::Success(_)))
+    }
+
     /// Return the title of a `Report`.
     pub fn title(&self) -> &str {
         &self.title
@@ -62,6 +150,108 @@ impl BatchReport {
     }
 }
 
+/// Outcome of running a batch against a target of a fleet.
+#[derive(Debug)]
+pub enum TargetOutcome {
+    /// The batch was run, as told by its report.
+    Completed(BatchReport),
+    /// The batch could not be run, for the given reason.
+    Failed(String),
+    /// The batch was not run, as too many targets failed.
+    Skipped,
+}
+
+impl TargetOutcome {
+    /// Return whether the batch was run against the target and succeeded.
+    pub fn succeeded(&self) -> bool {
+        matches!(self, TargetOutcome::Completed(report) if report.succeeded())
+    }
+
+    fn status(&self) -> &'static str {
+        match self {
+            TargetOutcome::Completed(report) => result_name(report),
+            TargetOutcome::Failed(_) => "error",
+            TargetOutcome::Skipped => "skipped",
+        }
+    }
+}
+
+/// Hold the outcomes of running a batch against several targets.
+#[derive(Debug)]
+pub struct FleetReport {
+    date: DateTime<Utc>,
+    entries: Vec<(Target, TargetOutcome)>,
+}
+
+impl FleetReport {
+    /// Create a new report.
+    pub(crate) fn new() -> Self {
+        Self {
+            date: Utc::now(),
+            entries: vec![],
+        }
+    }
+
+    /// Append the outcome of running the batch against `target`.
+    pub(crate) fn push(&mut self, target: Target, outcome: TargetOutcome) {
+        self.entries.push((target, outcome));
+    }
+
+    /// Return the creation date of a `FleetReport`.
+    pub fn date(&self) -> &DateTime<Utc> {
+        &self.date
+    }
+
+    /// Return the targets of a `FleetReport`, with their outcome.
+    pub fn entries(&self) -> &[(Target, TargetOutcome)] {
+        &self.entries
+    }
+
+    /// Return the outcome for the target named `name`.
+    pub fn get(&self, name: &str) -> Option<&TargetOutcome> {
+        self.entries
+            .iter()
+            .find(|(target, _)| target.name == name)
+            .map(|(_, outcome)| outcome)
+    }
+
+    /// Return whether the batch succeeded on all the targets.
+    pub fn succeeded(&self) -> bool {
+        self.entries.iter().all(|(_, outcome)| outcome.succeeded())
+    }
+
+    #[cfg(test)]
+    pub(crate) fn spoof_date(&mut self, date: &str) -> Result<(), chrono::format::ParseError> {
+        let date = DateTime::parse_from_rfc3339(date)?;
+        self.date = date.into();
+        Ok(())
+    }
+}
+
+fn assertion_result(assertion: &Assertion) -> &'static str {
+    if assertion.passed {
+        "pass"
+    } else {
+        "fail"
+    }
+}
+
+fn result_name(report: &BatchReport) -> &'static str {
+    if report.succeeded() {
+        "success"
+    } else {
+        "failure"
+    }
+}
+
+fn fleet_result_name(report: &FleetReport) -> &'static str {
+    if report.succeeded() {
+        "success"
+    } else {
+        "failure"
+    }
+}
+
 /// Convert a `Report` to a text representation using a markup format.
 #[derive(Debug)]
 pub struct MarkupReportRenderer {
@@ -71,6 +261,8 @@ pub struct MarkupReportRenderer {
 /// Kind of supported markup formats.
 #[derive(Debug)]
 pub enum MarkupKind {
+    /// JSON document, following the schema returned by `report_schema()`.
+    Json,
     Xml,
     Yaml,
 }
@@ -84,38 +276,191 @@ impl MarkupReportRenderer {
     /// Render a report.
     pub fn render<W: Write>(&self, writer: &mut W, report: &BatchReport) -> Result<(), Error> {
         match self.markup_kind {
+            MarkupKind::Json => Ok(writeln!(writer, "{}", json::to_json(report)?)?),
             MarkupKind::Xml => Self::render_as_xml(writer, report),
             MarkupKind::Yaml => Self::render_as_yaml(writer, report),
         }
     }
 
+    /// Render the report of a batch run against several targets, including
+    /// the report of each target.
+    pub fn render_fleet<W: Write>(
+        &self,
+        writer: &mut W,
+        report: &FleetReport,
+    ) -> Result<(), Error> {
+        match self.markup_kind {
+            MarkupKind::Json => Ok(writeln!(writer, "{}", json::fleet_to_json(report)?)?),
+            MarkupKind::Xml => Self::render_fleet_as_xml(writer, report),
+            MarkupKind::Yaml => Self::render_fleet_as_yaml(writer, report),
+        }
+    }
+
+    fn render_fleet_as_yaml<W: Write>(writer: &mut W, report: &FleetReport) -> Result<(), Error> {
+        writeln!(writer, "# Artifex fleet report")?;
+        write!(
+            writer,
+            "date    : {}\nresult  : {}\ntargets :\n",
+            report.date().to_rfc3339(),
+            fleet_result_name(report)
+        )?;
+        for (target, outcome) in report.entries() {
+            writeln!(writer, "  '{}':", target.name.replace('\'', "''"))?;
+            writeln!(writer, "    url    : '{}'", target.url.replace('\'', "''"))?;
+            writeln!(writer, "    status : {}", outcome.status())?;
+            match outcome {
+                TargetOutcome::Completed(report) => {
+                    let mut buffer = vec![];
+                    Self::render_as_yaml(&mut buffer, report)?;
+                    writeln!(writer, "    report :")?;
+                    for line in String::from_utf8_lossy(&buffer).lines().skip(1) {
+                        writeln!(writer, "      {}", line)?;
+                    }
+                }
+                TargetOutcome::Failed(reason) => {
+                    writeln!(writer, "    reason : '{}'", reason.replace('\'', "''"))?;
+                }
+                TargetOutcome::Skipped => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Render the report of each target as is, as indenting it would alter
+    /// the multiline outputs.
+    fn render_fleet_as_xml<W: Write>(writer: &mut W, report: &FleetReport) -> Result<(), Error> {
+        writeln!(writer, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>")?;
+        writeln!(writer, "<fleet-report>")?;
+        writeln!(
+            writer,
+            "  <date>{}</date>\n  <result>{}</result>\n  <targets>",
+            report.date().to_rfc3339(),
+            fleet_result_name(report)
+        )?;
+        for (target, outcome) in report.entries() {
+            writeln!(
+                writer,
+                "    <target>\n      <name><![CDATA[{}]]></name>\n      <url><![CDATA[{}]]></url>",
+                target.name, target.url
+            )?;
+            writeln!(writer, "      <status>{}</status>", outcome.status())?;
+            match outcome {
+                TargetOutcome::Completed(report) => {
+                    let mut buffer = vec![];
+                    Self::render_as_xml(&mut buffer, report)?;
+                    for line in String::from_utf8_lossy(&buffer).lines().skip(1) {
+                        writeln!(writer, "{}", line)?;
+                    }
+                }
+                TargetOutcome::Failed(reason) => {
+                    writeln!(writer, "      <reason><![CDATA[{}]]></reason>", reason)?;
+                }
+                TargetOutcome::Skipped => {}
+            }
+            writeln!(writer, "    </target>")?;
+        }
+        writeln!(writer, "  </targets>")?;
+        writeln!(writer, "</fleet-report>")?;
+        Ok(())
+    }
+
     fn render_as_yaml<W: Write>(writer: &mut W, report: &BatchReport) -> Result<(), Error> {
         writeln!(writer, "# Artifex batch report")?;
         write!(
             writer,
-            "title   : {}\ndate    : {}\ncommands:\n",
+            "title   : {}\ndate    : {}\nresult  : {}\ncommands:\n",
             report.title(),
-            report.date().to_rfc3339()
+            report.date().to_rfc3339(),
+            result_name(report)
         )?;
         for entry in report.entries() {
-            writeln!(writer, "- command: '{}'", entry.command)?;
-            let (status, output) = match &entry.status {
-                CommandStatus::Failure => ("failure", None),
-                CommandStatus::Success(output) => ("success", output.as_ref()),
+            writeln!(writer, "- command  : '{}'", entry.command)?;
+            if let Some(target) = &entry.target {
+                writeln!(writer, "  target   : '{}'", target.replace('\'', "''"))?;
+            }
+            let (status, output, reason) = match &entry.status {
+                CommandStatus::Failure(reason) => ("failure", None, Some(reason)),
+                CommandStatus::Success(output) => ("success", output.as_ref(), None),
+                CommandStatus::Skipped => ("skipped", None, None),
             };
-            writeln!(writer, "  status : {}", status)?;
-            if let Some(output) = output {
-                writeln!(writer, "  output : |")?;
-                match output {
-                    CommandOutput::String(text) => {
-                        for line in text.lines() {
-                            writeln!(writer, "    {}", line)?;
-                        }
+            writeln!(writer, "  status   : {}", status)?;
+            if let Some(reason) = reason {
+                writeln!(writer, "  reason   : '{}'", reason.replace('\'', "''"))?;
+            }
+            if let Some(code) = entry.exit_code {
+                writeln!(writer, "  exit-code: {}", code)?;
+            }
+            if let Some(started) = entry.started {
+                writeln!(writer, "  started  : {}", started.to_rfc3339())?;
+            }
+            if let Some(ended) = entry.ended {
+                writeln!(writer, "  ended    : {}", ended.to_rfc3339())?;
+            }
+            if let Some(duration) = entry.duration {
+                writeln!(writer, "  elapsed  : {}", rounded_duration(duration))?;
+            }
+            if !entry.attempts.is_empty() {
+                writeln!(writer, "  tries    : {}", entry.attempts.len())?;
+            }
+            if !entry.assertions.is_empty() {
+                writeln!(writer, "  expectations:")?;
+                for assertion in &entry.assertions {
+                    writeln!(
+                        writer,
+                        "  - expect: '{}'\n    result: {}",
+                        assertion.expectation.to_string().replace('\'', "''"),
+                        assertion_result(assertion)
+                    )?;
+                }
+            }
+            if entry.attempts.len() > 1 {
+                writeln!(writer, "  attempts:")?;
+                for attempt in &entry.attempts {
+                    writeln!(
+                        writer,
+                        "  - status  : {}\n    duration: {}",
+                        attempt.status(),
+                        attempt.rounded_duration()
+                    )?;
+                    if let Some(reason) = &attempt.failure {
+                        writeln!(writer, "    reason  : '{}'", reason.replace('\'', "''"))?;
                     }
-                    CommandOutput::Uint32(number) => {
-                        writeln!(writer, "    {}", number)?;
+                }
+            }
+            match output {
+                Some(CommandOutput::String(text))
+                | Some(CommandOutput::Process { stdout: text, .. }) => {
+                    writeln!(writer, "  output   : |")?;
+                    for line in text.lines() {
+                        writeln!(writer, "    {}", line)?;
+                    }
+                }
+                Some(CommandOutput::Uint32(number)) => {
+                    writeln!(writer, "  output   : |\n    {}", number)?;
+                }
+                Some(CommandOutput::Record(fields)) => {
+                    writeln!(writer, "  output   :")?;
+                    for (name, value) in fields {
+                        match value {
+                            FieldValue::Text(text) => {
+                                writeln!(writer, "    {}: '{}'", name, text.replace('\'', "''"))?
+                            }
+                            FieldValue::Integer(number) => {
+                                writeln!(writer, "    {}: {}", name, number)?
+                            }
+                            FieldValue::Duration(duration) => {
+                                writeln!(writer, "    {}: {}", name, seconds(*duration))?
+                            }
+                        }
                     }
                 }
+                None => {}
+            }
+            if let Some(stderr) = entry.stderr.as_ref().filter(|s| !s.is_empty()) {
+                writeln!(writer, "  stderr   : |")?;
+                for line in stderr.lines() {
+                    writeln!(writer, "    {}", line)?;
+                }
             }
         }
         Ok(())
@@ -126,9 +471,10 @@ impl MarkupReportRenderer {
         writeln!(writer, "<report>")?;
         writeln!(
             writer,
-            "  <title>{}</title>\n  <date>{}</date>\n  <commands>",
+            "  <title>{}</title>\n  <date>{}</date>\n  <result>{}</result>\n  <commands>",
             report.title(),
-            report.date().to_rfc3339()
+            report.date().to_rfc3339(),
+            result_name(report)
         )?;
         for entry in &report.entries {
             writeln!(
@@ -136,20 +482,100 @@ impl MarkupReportRenderer {
                 "    <command>\n      <input><![CDATA[{}]]></input>",
                 entry.command
             )?;
-            let (status, output) = match &entry.status {
-                CommandStatus::Failure => ("failure", None),
-                CommandStatus::Success(output) => ("success", output.as_ref()),
+            if let Some(target) = &entry.target {
+                writeln!(writer, "      <target><![CDATA[{}]]></target>", target)?;
+            }
+            let (status, output, reason) = match &entry.status {
+                CommandStatus::Failure(reason) => ("failure", None, Some(reason)),
+                CommandStatus::Success(output) => ("success", output.as_ref(), None),
+                CommandStatus::Skipped => ("skipped", None, None),
             };
             writeln!(writer, "      <status>{}</status>", status)?;
-            if let Some(output) = output {
-                match output {
-                    CommandOutput::String(text) => {
-                        writeln!(writer, "      <output><![CDATA[{}]]></output>", text)?;
+            if let Some(reason) = reason {
+                writeln!(writer, "      <reason><![CDATA[{}]]></reason>", reason)?;
+            }
+            if let Some(code) = entry.exit_code {
+                writeln!(writer, "      <exit-code>{}</exit-code>", code)?;
+            }
+            if let Some(started) = entry.started {
+                writeln!(writer, "      <started>{}</started>", started.to_rfc3339())?;
+            }
+            if let Some(ended) = entry.ended {
+                writeln!(writer, "      <ended>{}</ended>", ended.to_rfc3339())?;
+            }
+            if let Some(duration) = entry.duration {
+                writeln!(
+                    writer,
+                    "      <elapsed>{}</elapsed>",
+                    rounded_duration(duration)
+                )?;
+            }
+            if !entry.attempts.is_empty() {
+                writeln!(writer, "      <tries>{}</tries>", entry.attempts.len())?;
+            }
+            if !entry.assertions.is_empty() {
+                writeln!(writer, "      <expectations>")?;
+                for assertion in &entry.assertions {
+                    writeln!(
+                        writer,
+                        "        <expectation result=\"{}\"><![CDATA[{}]]></expectation>",
+                        assertion_result(assertion),
+                        assertion.expectation
+                    )?;
+                }
+                writeln!(writer, "      </expectations>")?;
+            }
+            if entry.attempts.len() > 1 {
+                writeln!(writer, "      <attempts>")?;
+                for attempt in &entry.attempts {
+                    write!(
+                        writer,
+                        "        <attempt status=\"{}\" duration=\"{}\"",
+                        attempt.status(),
+                        attempt.rounded_duration()
+                    )?;
+                    match &attempt.failure {
+                        Some(reason) => writeln!(writer, "><![CDATA[{}]]></attempt>", reason)?,
+                        None => writeln!(writer, "/>")?,
                     }
-                    CommandOutput::Uint32(number) => {
-                        writeln!(writer, "      <output>{}</output>", number)?;
+                }
+                writeln!(writer, "      </attempts>")?;
+            }
+            match output {
+                Some(CommandOutput::String(text))
+                | Some(CommandOutput::Process { stdout: text, .. }) => {
+                    writeln!(writer, "      <output><![CDATA[{}]]></output>", text)?;
+                }
+                Some(CommandOutput::Uint32(number)) => {
+                    writeln!(writer, "      <output>{}</output>", number)?;
+                }
+                Some(CommandOutput::Record(fields)) => {
+                    writeln!(writer, "      <output>")?;
+                    for (name, value) in fields {
+                        match value {
+                            FieldValue::Text(text) => writeln!(
+                                writer,
+                                "        <{}><![CDATA[{}]]></{}>",
+                                name, text, name
+                            )?,
+                            FieldValue::Integer(number) => {
+                                writeln!(writer, "        <{}>{}</{}>", name, number, name)?
+                            }
+                            FieldValue::Duration(duration) => writeln!(
+                                writer,
+                                "        <{}>{}</{}>",
+                                name,
+                                seconds(*duration),
+                                name
+                            )?,
+                        }
                     }
-                };
+                    writeln!(writer, "      </output>")?;
+                }
+                None => {}
+            }
+            if let Some(stderr) = entry.stderr.as_ref().filter(|s| !s.is_empty()) {
+                writeln!(writer, "      <stderr><![CDATA[{}]]></stderr>", stderr)?;
             }
             writeln!(writer, "    </command>")?;
         }
@@ -162,7 +588,12 @@ impl MarkupReportRenderer {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::command::CommandOutput;
+    use crate::command::{Command, CommandOutput};
+    use crate::expectation::Expectation;
+
+    fn date(text: &str) -> Option<DateTime<Utc>> {
+        Some(DateTime::parse_from_rfc3339(text).unwrap().into())
+    }
 
     fn setup_report() -> BatchReport {
         let mut report = BatchReport::new("Dummy Report");
@@ -170,14 +601,88 @@ mod tests {
             .spoof_date("2023-05-07T09:17:58.133639582+00:00")
             .unwrap();
         report.push(ReportEntry {
-            command: Command::Execute("date -u".to_string()),
-            status: CommandStatus::Success(Some(CommandOutput::String(
-                "Sun May  7 09:17:58 UTC 2023".to_string(),
-            ))),
+            command: Command::Execute("date -u".to_string()).to_string(),
+            target: Some("web1".to_string()),
+            status: CommandStatus::Success(Some(CommandOutput::Process {
+                stdout: "Sun May  7 09:17:58 UTC 2023".to_string(),
+                exit_code: 0,
+                stderr: String::new(),
+            })),
+            exit_code: Some(0),
+            stderr: Some(String::new()),
+            assertions: vec![],
+            attempts: vec![
+                Attempt {
+                    failure: Some("RPC error Unavailable: connection dropped".to_string()),
+                    duration: Duration::from_micros(1_500_300),
+                },
+                Attempt {
+                    failure: None,
+                    duration: Duration::from_millis(20),
+                },
+            ],
+            started: date("2023-05-07T09:17:56.500+00:00"),
+            ended: date("2023-05-07T09:17:59.020+00:00"),
+            duration: Some(Duration::from_micros(2_520_400)),
         });
         report.push(ReportEntry {
-            command: Command::Upgrade,
-            status: CommandStatus::Failure,
+            command: Command::Inspect.to_string(),
+            target: Some("web1".to_string()),
+            status: CommandStatus::Success(Some(CommandOutput::Record(vec![
+                (
+                    "kernel-version".parse().unwrap(),
+                    FieldValue::Text("6.1.0-13-amd64".to_string()),
+                ),
+                (
+                    "uptime".parse().unwrap(),
+                    FieldValue::Duration(Duration::from_secs(7260)),
+                ),
+            ]))),
+            attempts: vec![Attempt {
+                failure: None,
+                duration: Duration::from_millis(3),
+            }],
+            started: date("2023-05-07T09:17:59.020+00:00"),
+            ended: date("2023-05-07T09:17:59.023+00:00"),
+            duration: Some(Duration::from_millis(3)),
+            ..ReportEntry::new(Command::Inspect, CommandStatus::Skipped)
+        });
+        report.push(ReportEntry {
+            command: Command::Execute("uname -s".to_string()).to_string(),
+            target: Some("web1".to_string()),
+            status: CommandStatus::Failure("Expectation failed: stdout contains BSD".to_string()),
+            exit_code: Some(0),
+            stderr: Some("uname: using fallback\nuname: done\n".to_string()),
+            assertions: vec![
+                Assertion {
+                    expectation: Expectation::ExitCode(0),
+                    passed: true,
+                },
+                Assertion {
+                    expectation: Expectation::StdoutContains("BSD".to_string()),
+                    passed: false,
+                },
+            ],
+            attempts: vec![Attempt {
+                failure: Some("Expectation failed: stdout contains BSD".to_string()),
+                duration: Duration::from_millis(5),
+            }],
+            started: date("2023-05-07T09:17:59.020+00:00"),
+            ended: date("2023-05-07T09:17:59.025+00:00"),
+            duration: Some(Duration::from_millis(5)),
+        });
+        report.push(ReportEntry {
+            target: Some("web1".to_string()),
+            ..ReportEntry::new(
+                Command::Upgrade(None),
+                CommandStatus::Failure(
+                    "InvalidSignature in download phase: Invalid bundle signature".to_string(),
+                ),
+            )
+        });
+        report.push(ReportEntry {
+            target: Some("web1".to_string()),
+            ..ReportEntry::new(Command::Inspect, CommandStatus::Skipped)
         });
         report
     }
@@ -195,13 +700,58 @@ mod tests {
     const REPORT_YAML: &str = r#"# Artifex batch report
 title   : Dummy Report
 date    : 2023-05-07T09:17:58.133639582+00:00
+result  : failure
 commands:
-- command: 'EXECUTE: date -u'
-  status : success
-  output : |
+- command  : 'EXECUTE: date -u'
+  target   : 'web1'
+  status   : success
+  exit-code: 0
+  started  : 2023-05-07T09:17:56.500+00:00
+  ended    : 2023-05-07T09:17:59.020+00:00
+  elapsed  : 2s 520ms
+  tries    : 2
+  attempts:
+  - status  : failure
+    duration: 1s 500ms
+    reason  : 'RPC error Unavailable: connection dropped'
+  - status  : success
+    duration: 20ms
+  output   : |
     Sun May  7 09:17:58 UTC 2023
-- command: 'UPGRADE'
-  status : failure
+- command  : 'INSPECT'
+  target   : 'web1'
+  status   : success
+  started  : 2023-05-07T09:17:59.020+00:00
+  ended    : 2023-05-07T09:17:59.023+00:00
+  elapsed  : 3ms
+  tries    : 1
+  output   :
+    kernel-version: '6.1.0-13-amd64'
+    uptime: 7260
+- command  : 'EXECUTE: uname -s'
+  target   : 'web1'
+  status   : failure
+  reason   : 'Expectation failed: stdout contains BSD'
+  exit-code: 0
+  started  : 2023-05-07T09:17:59.020+00:00
+  ended    : 2023-05-07T09:17:59.025+00:00
+  elapsed  : 5ms
+  tries    : 1
+  expectations:
+  - expect: 'exit-code 0'
+    result: pass
+  - expect: 'stdout contains BSD'
+    result: fail
+  stderr   : |
+    uname: using fallback
+    uname: done
+- command  : 'UPGRADE'
+  target   : 'web1'
+  status   : failure
+  reason   : 'InvalidSignature in download phase: Invalid bundle signature'
+- command  : 'INSPECT'
+  target   : 'web1'
+  status   : skipped
 "#;
     #[test]
     fn render_to_yaml() {
@@ -212,15 +762,64 @@ commands:
 <report>
   <title>Dummy Report</title>
   <date>2023-05-07T09:17:58.133639582+00:00</date>
+  <result>failure</result>
   <commands>
     <command>
       <input><![CDATA[EXECUTE: date -u]]></input>
+      <target><![CDATA[web1]]></target>
       <status>success</status>
+      <exit-code>0</exit-code>
+      <started>2023-05-07T09:17:56.500+00:00</started>
+      <ended>2023-05-07T09:17:59.020+00:00</ended>
+      <elapsed>2s 520ms</elapsed>
+      <tries>2</tries>
+      <attempts>
+        <attempt status="failure" duration="1s 500ms"><![CDATA[RPC error Unavailable: connection dropped]]></attempt>
+        <attempt status="success" duration="20ms"/>
+      </attempts>
       <output><![CDATA[Sun May  7 09:17:58 UTC 2023]]></output>
     </command>
+    <command>
+      <input><![CDATA[INSPECT]]></input>
+      <target><![CDATA[web1]]></target>
+      <status>success</status>
+      <started>2023-05-07T09:17:59.020+00:00</started>
+      <ended>2023-05-07T09:17:59.023+00:00</ended>
+      <elapsed>3ms</elapsed>
+      <tries>1</tries>
+      <output>
+        <kernel-version><![CDATA[6.1.0-13-amd64]]></kernel-version>
+        <uptime>7260</uptime>
+      </output>
+    </command>
+    <command>
+      <input><![CDATA[EXECUTE: uname -s]]></input>
+      <target><![CDATA[web1]]></target>
+      <status>failure</status>
+      <reason><![CDATA[Expectation failed: stdout contains BSD]]></reason>
+      <exit-code>0</exit-code>
+      <started>2023-05-07T09:17:59.020+00:00</started>
+      <ended>2023-05-07T09:17:59.025+00:00</ended>
+      <elapsed>5ms</elapsed>
+      <tries>1</tries>
+      <expectations>
+        <expectation result="pass"><![CDATA[exit-code 0]]></expectation>
+        <expectation result="fail"><![CDATA[stdout contains BSD]]></expectation>
+      </expectations>
+      <stderr><![CDATA[uname: using fallback
+uname: done
+]]></stderr>
+    </command>
     <command>
       <input><![CDATA[UPGRADE]]></input>
+      <target><![CDATA[web1]]></target>
       <status>failure</status>
+      <reason><![CDATA[InvalidSignature in download phase: Invalid bundle signature]]></reason>
+    </command>
+    <command>
+      <input><![CDATA[INSPECT]]></input>
+      <target><![CDATA[web1]]></target>
+      <status>skipped</status>
     </command>
   </commands>
 </report>
@@ -230,4 +829,154 @@ commands:
     fn render_to_xml() {
         render_to_markup(MarkupKind::Xml, REPORT_XML);
     }
+
+    const REPORT_JSON: &str = r#"{
+  "schema_version": 1,
+  "title": "Dummy Report",
+  "date": "2023-05-07T09:17:58.133639582+00:00",
+  "result": "failure",
+  "commands": [
+    {
+      "command": "EXECUTE: date -u",
+      "target": "web1",
+      "status": "success",
+      "exit_code": 0,
+      "stderr": "",
+      "started": "2023-05-07T09:17:56.500+00:00",
+      "ended": "2023-05-07T09:17:59.020+00:00",
+      "duration_s": 2.5204,
+      "expectations": [],
+      "attempts": [
+        {
+          "status": "failure",
+          "reason": "RPC error Unavailable: connection dropped",
+          "duration_s": 1.5003
+        },
+        {
+          "status": "success",
+          "duration_s": 0.02
+        }
+      ],
+      "output": "Sun May  7 09:17:58 UTC 2023"
+    },
+    {
+      "command": "INSPECT",
+      "target": "web1",
+      "status": "success",
+      "started": "2023-05-07T09:17:59.020+00:00",
+      "ended": "2023-05-07T09:17:59.023+00:00",
+      "duration_s": 0.003,
+      "expectations": [],
+      "attempts": [
+        {
+          "status": "success",
+          "duration_s": 0.003
+        }
+      ],
+      "output": {
+        "kernel-version": "6.1.0-13-amd64",
+        "uptime": {
+          "seconds": 7260.0
+        }
+      }
+    },
+    {
+      "command": "EXECUTE: uname -s",
+      "target": "web1",
+      "status": "failure",
+      "reason": "Expectation failed: stdout contains BSD",
+      "exit_code": 0,
+      "stderr": "uname: using fallback\nuname: done\n",
+      "started": "2023-05-07T09:17:59.020+00:00",
+      "ended": "2023-05-07T09:17:59.025+00:00",
+      "duration_s": 0.005,
+      "expectations": [
+        {
+          "expect": "exit-code 0",
+          "passed": true
+        },
+        {
+          "expect": "stdout contains BSD",
+          "passed": false
+        }
+      ],
+      "attempts": [
+        {
+          "status": "failure",
+          "reason": "Expectation failed: stdout contains BSD",
+          "duration_s": 0.005
+        }
+      ]
+    },
+    {
+      "command": "UPGRADE",
+      "target": "web1",
+      "status": "failure",
+      "reason": "InvalidSignature in download phase: Invalid bundle signature",
+      "expectations": [],
+      "attempts": []
+    },
+    {
+      "command": "INSPECT",
+      "target": "web1",
+      "status": "skipped",
+      "expectations": [],
+      "attempts": []
+    }
+  ]
+}
+"#;
+    #[test]
+    fn render_to_json() {
+        render_to_markup(MarkupKind::Json, REPORT_JSON);
+    }
+
+    const FLEET_REPORT_YAML: &str = r#"# Artifex fleet report
+date    : 2023-05-07T09:17:58.133639582+00:00
+result  : failure
+targets :
+  'web1':
+    url    : 'http://10.0.0.1:50051'
+    status : failure
+    report :
+      title   : Dummy Report
+      date    : 2023-05-07T09:17:58.133639582+00:00
+      result  : failure
+      commands:
+      - command  : 'INSPECT'
+        status   : skipped
+  'db1':
+    url    : 'http://10.0.0.2:50051'
+    status : error
+    reason : 'Cannot connect to http://10.0.0.2:50051'
+  'db2':
+    url    : 'http://10.0.0.3:50051'
+    status : skipped
+"#;
+
+    #[test]
+    fn render_fleet_to_yaml() {
+        let date = "2023-05-07T09:17:58.133639582+00:00";
+        let mut batch_report = BatchReport::new("Dummy Report");
+        batch_report.spoof_date(date).unwrap();
+        batch_report.push(ReportEntry::new(Command::Inspect, CommandStatus::Skipped));
+        let mut report = FleetReport::new();
+        report.spoof_date(date).unwrap();
+        report.push(
+            Target::new("web1", "http://10.0.0.1:50051"),
+            TargetOutcome::Completed(batch_report),
+        );
+        report.push(
+            Target::new("db1", "http://10.0.0.2:50051"),
+            TargetOutcome::Failed("Cannot connect to http://10.0.0.2:50051".to_string()),
+        );
+        report.push(
+            Target::new("db2", "http://10.0.0.3:50051"),
+            TargetOutcome::Skipped,
+        );
+        let renderer = MarkupReportRenderer::new(MarkupKind::Yaml);
+        let mut buffer: Vec<u8> = vec![];
+        renderer.render_fleet(&mut buffer, &report).unwrap();
+        assert_eq!(FLEET_REPORT_YAML, String::from_utf8(buffer).unwrap());
+    }
 }
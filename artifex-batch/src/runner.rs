@@ -5,60 +5,304 @@
 //
 
 use crate::{
+    ast::{Statement, Step},
     batch::Batch,
-    command::{Command, CommandOutput, CommandStatus},
+    command::{Command, CommandOutput, CommandStatus, ErrorPolicy, FieldName, FieldValue},
+    condition::Field,
     error::Error,
-    report::{BatchReport, ReportEntry},
+    expectation::{Assertion, Expectation, Observation},
+    report::{Attempt, BatchReport, ReportEntry},
+    variables::Variables,
 };
 
-use artifex_rpc::{artifex_client::ArtifexClient, ExecuteRequest, InspectRequest, UpgradeRequest};
-use futures_util::StreamExt;
+use artifex_rpc::{
+    artifex_client::ArtifexClient, upgrade_reply, ExecuteRequest, InspectRequest, UpgradeRequest,
+};
+use chrono::Utc;
+use futures_util::{future::join_all, StreamExt};
 use humantime::format_duration;
-use std::{fmt::Write, time::Duration};
+use std::{
+    fmt::Write,
+    time::{Duration, Instant},
+};
+use tonic::{metadata::MetadataValue, Request};
 use uuid::Uuid;
 
 /// Run commands via a client.
-#[derive(Debug)]
+///
+/// Clones share the channel of the client, so that they can run commands
+/// concurrently over it.
+#[derive(Clone, Debug)]
 pub(crate) struct CommandRunner {
     client: ArtifexClient<tonic::transport::Channel>,
+    /// Token sent to the server to authenticate, if any.
+    token: Option<String>,
+    /// Name of the machine of the server, recorded in the report entries.
+    target: Option<String>,
 }
 
 impl CommandRunner {
-    /// Run a command and return its output.
-    pub(crate) async fn run(&mut self, command: &Command) -> Result<CommandStatus, Error> {
+    /// Build a request, with `timeout` as deadline, carrying the token.
+    fn request<T>(&self, message: T, timeout: Option<Duration>) -> Result<Request<T>, Error> {
+        let mut request = Request::new(message);
+        if let Some(timeout) = timeout {
+            request.set_timeout(timeout);
+        }
+        if let Some(token) = &self.token {
+            let value = MetadataValue::try_from(format!("Bearer {}", token))
+                .map_err(|_| Error::Token("invalid characters".to_string()))?;
+            request.metadata_mut().insert("authorization", value);
+        }
+        Ok(request)
+    }
+
+    /// Return the report entry of a step which is not run.
+    fn skipped(&self, step: &Step) -> ReportEntry {
+        ReportEntry {
+            target: self.target.clone(),
+            ..ReportEntry::new(&step.command, CommandStatus::Skipped)
+        }
+    }
+
+    /// Record the steps of `statements` as not run.
+    fn skip(&self, statements: &[Statement], report: &mut BatchReport) {
+        for statement in statements {
+            match statement {
+                Statement::Set { .. } => {}
+                Statement::If {
+                    then, otherwise, ..
+                } => {
+                    self.skip(then, report);
+                    self.skip(otherwise, report);
+                }
+                Statement::Run(step) => report.push(self.skipped(step)),
+                Statement::Parallel { steps, .. } => steps
+                    .iter()
+                    .for_each(|step| report.push(self.skipped(step))),
+            }
+        }
+    }
+
+    /// Return the report entry of `statement`, which failed with `error`
+    /// before any command could run.
+    fn failed<T: ToString>(&self, statement: T, error: Error) -> ReportEntry {
+        ReportEntry {
+            target: self.target.clone(),
+            ..ReportEntry::new(statement, CommandStatus::Failure(error.to_string()))
+        }
+    }
+
+    /// Run `command`, the command of `step` whose variables are substituted,
+    /// until it succeeds or its retries are exhausted, using `policy` to tell
+    /// whether it failed.
+    ///
+    /// Return the report entry of the step, and what was observed by the last
+    /// run of the command.
+    pub(crate) async fn run_step(
+        &mut self,
+        step: &Step,
+        command: &Command,
+        policy: &ErrorPolicy,
+    ) -> Result<(ReportEntry, Observation), Error> {
+        let retry = step.retry.unwrap_or_default();
+        let started = Utc::now();
+        let begin = Instant::now();
+        let mut attempts = vec![];
+        loop {
+            let start = Instant::now();
+            let mut observation = Observation::default();
+            let (status, assertions) = self
+                .attempt(step, command, policy, &mut observation)
+                .await?;
+            let failure = match &status {
+                CommandStatus::Failure(reason) => Some(reason.clone()),
+                _ => None,
+            };
+            let retried = attempts.len() as u32;
+            attempts.push(Attempt {
+                failure,
+                duration: start.elapsed(),
+            });
+            if !matches!(status, CommandStatus::Failure(_)) || retried >= retry.retries {
+                let entry = ReportEntry {
+                    command: command.to_string(),
+                    target: self.target.clone(),
+                    status,
+                    exit_code: observation.exit_code,
+                    stderr: observation.stderr.clone(),
+                    assertions,
+                    attempts,
+                    started: Some(started),
+                    ended: Some(Utc::now()),
+                    duration: Some(begin.elapsed()),
+                };
+                return Ok((entry, observation));
+            }
+            tokio::time::sleep(retry.delay(retried)).await;
+        }
+    }
+
+    /// Run `command` once and check the expectations of `step`.
+    ///
+    /// Errors of the RPC, including exceeded deadlines, fail the command.
+    async fn attempt(
+        &mut self,
+        step: &Step,
+        command: &Command,
+        policy: &ErrorPolicy,
+        observation: &mut Observation,
+    ) -> Result<(CommandStatus, Vec<Assertion>), Error> {
+        let result = match step.timeout {
+            Some(timeout) => {
+                let run = self.run(command, Some(timeout), observation);
+                tokio::time::timeout(timeout, run)
+                    .await
+                    .unwrap_or_else(|_| {
+                        Ok(CommandStatus::Failure(format!(
+                            "Timed out after {}",
+                            format_duration(timeout)
+                        )))
+                    })
+            }
+            None => self.run(command, None, observation).await,
+        };
+        let status = match result {
+            Ok(status) => status,
+            Err(Error::Rpc(status)) => {
+                return Ok((
+                    CommandStatus::Failure(format!(
+                        "RPC error {:?}: {}",
+                        status.code(),
+                        status.message()
+                    )),
+                    vec![],
+                ))
+            }
+            Err(e) => return Err(e),
+        };
+        let assertions: Vec<Assertion> = step
+            .expectations
+            .iter()
+            .map(|e| Assertion {
+                expectation: e.clone(),
+                passed: e.check(observation),
+            })
+            .collect();
+        if !matches!(status, CommandStatus::Success(_)) {
+            return Ok((status, assertions));
+        }
+        let failed: Vec<String> = assertions
+            .iter()
+            .filter(|a| !a.passed)
+            .map(|a| a.expectation.to_string())
+            .collect();
+        if !failed.is_empty() {
+            let reason = format!("Expectation failed: {}", failed.join(", "));
+            return Ok((CommandStatus::Failure(reason), assertions));
+        }
+        let skip_exit_code_check = policy.ignore_exit_code
+            || step
+                .expectations
+                .iter()
+                .any(|e| matches!(e, Expectation::ExitCode(_)));
+        match observation.exit_code {
+            Some(code) if code != 0 && !skip_exit_code_check => Ok((
+                CommandStatus::Failure(format!("Exited with code {}", code)),
+                assertions,
+            )),
+            _ => Ok((status, assertions)),
+        }
+    }
+
+    /// Run a command and return its output, recording what can be checked by
+    /// expectations in `observation`.
+    ///
+    /// The `timeout` is sent to the server as the deadline of the RPC.
+    async fn run(
+        &mut self,
+        command: &Command,
+        timeout: Option<Duration>,
+        observation: &mut Observation,
+    ) -> Result<CommandStatus, Error> {
         let status = match command {
             Command::Execute(command) => {
-                let response = self
-                    .client
-                    .execute(ExecuteRequest {
+                let request = self.request(
+                    ExecuteRequest {
                         command: command.to_string(),
-                    })
-                    .await?;
+                    },
+                    timeout,
+                )?;
+                let response = self.client.execute(request).await?;
                 let reply = response.into_inner();
-                CommandStatus::Success(Some(CommandOutput::String(reply.stdout)))
+                observation.exit_code = Some(reply.code);
+                observation.stdout = Some(reply.stdout.clone());
+                observation.stderr = Some(reply.stderr.clone());
+                CommandStatus::Success(Some(CommandOutput::Process {
+                    stdout: reply.stdout,
+                    exit_code: reply.code,
+                    stderr: reply.stderr,
+                }))
             }
             Command::Inspect => {
-                let response = self.client.inspect(InspectRequest {}).await?;
+                let request = self.request(InspectRequest {}, timeout)?;
+                let response = self.client.inspect(request).await?;
                 let reply = response.into_inner();
-                let output = format!(
-                    "kernel version: {}\nsystem uptime: {}",
-                    reply.kernel_version,
-                    format_duration(Duration::from_secs(reply.system_uptime))
-                );
-                CommandStatus::Success(Some(CommandOutput::String(output)))
+                observation.kernel_version = Some(reply.kernel_version.clone());
+                observation.uptime = Some(Duration::from_secs(reply.system_uptime));
+                let fields = vec![
+                    field(Field::KernelVersion, FieldValue::Text(reply.kernel_version)),
+                    field(
+                        Field::Uptime,
+                        FieldValue::Duration(Duration::from_secs(reply.system_uptime)),
+                    ),
+                ];
+                CommandStatus::Success(Some(CommandOutput::Record(fields)))
             }
-            Command::Upgrade => {
-                let response = self.client.upgrade(UpgradeRequest {}).await?;
+            Command::Upgrade(target) => {
+                let upgrade = UpgradeRequest {
+                    target: target.clone().unwrap_or_default(),
+                };
+                let request = self.request(upgrade, timeout)?;
+                let response = self.client.upgrade(request).await?;
                 let mut output = String::new();
                 let mut stream = response.into_inner();
                 while let Some(reply) = stream.next().await {
                     let progress = reply?;
-                    writeln!(
+                    write!(
                         output,
                         "Upgrade progress: {:?}, {}%",
                         progress.status(),
                         progress.position
                     )?;
+                    if !progress.phase.is_empty() {
+                        write!(
+                            output,
+                            ", phase {} {}%",
+                            progress.phase, progress.phase_position
+                        )?;
+                    }
+                    if progress.bytes_processed > 0 {
+                        write!(output, ", {} bytes", progress.bytes_processed)?;
+                    }
+                    if let Some(eta) = progress.eta {
+                        write!(
+                            output,
+                            ", {} remaining",
+                            format_duration(Duration::from_secs(eta))
+                        )?;
+                    }
+                    if !progress.message.is_empty() {
+                        write!(output, ": {}", progress.message)?;
+                    }
+                    writeln!(output)?;
+                    if progress.status() == upgrade_reply::Status::Failure {
+                        let mut reason = format!("{:?}", progress.error_code());
+                        if !progress.failed_phase.is_empty() {
+                            write!(reason, " in {} phase", progress.failed_phase)?;
+                        }
+                        write!(reason, ": {}", progress.reason)?;
+                        return Ok(CommandStatus::Failure(reason));
+                    }
                 }
                 CommandStatus::Success(Some(CommandOutput::String(output)))
             }
@@ -71,27 +315,250 @@ impl CommandRunner {
 #[derive(Debug)]
 pub struct BatchRunner {
     inner: CommandRunner,
+    variables: Variables,
 }
 
 impl BatchRunner {
     /// Build a `BatchRunner` associated to a `ArtifexClient`
     pub fn new(client: ArtifexClient<tonic::transport::Channel>) -> Self {
         Self {
-            inner: CommandRunner { client },
+            inner: CommandRunner {
+                client,
+                token: None,
+                target: None,
+            },
+            variables: Variables::new(),
         }
     }
 
-    /// Run a batch of commands
+    /// Set the token sent to the server to authenticate, as a bearer token.
+    pub fn with_token(mut self, token: &str) -> Self {
+        self.inner.token = Some(token.to_string());
+        self
+    }
+
+    /// Set the variables defined before running the batches.
+    pub fn with_variables(mut self, variables: Variables) -> Self {
+        self.variables = variables;
+        self
+    }
+
+    /// Set the name of the machine of the server, recorded in the report.
+    pub fn with_target(mut self, target: &str) -> Self {
+        self.inner.target = Some(target.to_string());
+        self
+    }
+
+    /// Run a batch of commands.
+    ///
+    /// The commands of a parallel block are run concurrently over the channel
+    /// of the client, and recorded in the report in the order of the batch,
+    /// once all of them are done.
+    ///
+    /// A failing command, including one whose RPC fails, is recorded in the
+    /// report. Its `ON FAILURE` statements are run, then, unless its error
+    /// policy says to continue, the remaining commands are recorded as
+    /// skipped.
+    ///
+    /// A statement which fails before its command is run, like one using a
+    /// variable without value, is recorded as a failing command, with the
+    /// location of the statement in the reason. The default error policy
+    /// applies to `SET` and `IF` statements, whose branches are skipped.
+    ///
+    /// Nothing is run if the batch references undefined variables.
     pub async fn run(&mut self, batch: &Batch) -> Result<BatchReport, Error> {
+        batch.check_variables(&self.variables)?;
         let title = format!("Report - {}", Uuid::new_v4());
         let mut report = BatchReport::new(&title);
-        for command in &batch.commands {
-            let status = self.inner.run(command).await?;
-            report.push(ReportEntry {
-                command: command.clone(),
-                status,
-            });
+        let mut variables = self.variables.clone();
+        let mut observation = Observation::default();
+        let mut aborted = false;
+        let mut pending = vec![Pending::Statements(batch.statements.iter())];
+        while let Some(top) = pending.last_mut() {
+            let statement = match top {
+                Pending::Statements(statements) => match statements.next() {
+                    Some(statement) => statement,
+                    None => {
+                        pending.pop();
+                        continue;
+                    }
+                },
+                Pending::Abort => {
+                    pending.pop();
+                    aborted = true;
+                    continue;
+                }
+            };
+            match statement {
+                Statement::Set { .. } if aborted => {}
+                Statement::Set {
+                    name,
+                    value,
+                    location,
+                } => match variables.expand(value, location) {
+                    Ok(value) => variables.define(name, &value),
+                    Err(e) => {
+                        let statement = format!("SET {} = {}", name, value);
+                        report.push(self.inner.failed(statement, e));
+                        if !batch.policy.continue_on_failure {
+                            pending.push(Pending::Abort);
+                        }
+                    }
+                },
+                Statement::If {
+                    then, otherwise, ..
+                } if aborted => {
+                    pending.push(Pending::Statements(otherwise.iter()));
+                    pending.push(Pending::Statements(then.iter()));
+                }
+                Statement::If {
+                    condition,
+                    then,
+                    otherwise,
+                    location,
+                } => match condition.evaluate(&variables, &observation, location) {
+                    Ok(true) => pending.push(Pending::Statements(then.iter())),
+                    Ok(false) => pending.push(Pending::Statements(otherwise.iter())),
+                    Err(e) => {
+                        let statement = format!("IF {}", condition);
+                        report.push(self.inner.failed(statement, e));
+                        self.inner.skip(then, &mut report);
+                        self.inner.skip(otherwise, &mut report);
+                        if !batch.policy.continue_on_failure {
+                            pending.push(Pending::Abort);
+                        }
+                    }
+                },
+                Statement::Run(step) if aborted => report.push(self.inner.skipped(step)),
+                Statement::Run(step) => {
+                    let policy = batch.policy_of(step);
+                    let res = match variables.expand_command(&step.command, &step.location) {
+                        Ok(command) => self.inner.run_step(step, &command, &policy).await,
+                        Err(e) => Err(e),
+                    };
+                    let (entry, observed) = res.unwrap_or_else(|e| {
+                        (self.inner.failed(&step.command, e), Observation::default())
+                    });
+                    observation.update(observed);
+                    capture(&entry, step, &mut variables);
+                    if matches!(entry.status, CommandStatus::Failure(_)) {
+                        if !policy.continue_on_failure {
+                            pending.push(Pending::Abort);
+                        }
+                        pending.push(Pending::Statements(step.on_failure.iter()));
+                    }
+                    report.push(entry);
+                }
+                Statement::Parallel { steps, .. } if aborted => {
+                    steps
+                        .iter()
+                        .for_each(|step| report.push(self.inner.skipped(step)));
+                }
+                Statement::Parallel { steps, .. } => {
+                    let mut runs = vec![];
+                    for step in steps {
+                        let command = variables.expand_command(&step.command, &step.location);
+                        let policy = batch.policy_of(step);
+                        let mut runner = self.inner.clone();
+                        runs.push(async move {
+                            let res = match command {
+                                Ok(command) => runner.run_step(step, &command, &policy).await,
+                                Err(e) => Err(e),
+                            };
+                            (res, policy)
+                        });
+                    }
+                    let mut abort = false;
+                    for (step, (res, policy)) in steps.iter().zip(join_all(runs).await) {
+                        let (entry, observed) = res.unwrap_or_else(|e| {
+                            (self.inner.failed(&step.command, e), Observation::default())
+                        });
+                        observation.update(observed);
+                        capture(&entry, step, &mut variables);
+                        if matches!(entry.status, CommandStatus::Failure(_)) {
+                            abort |= !policy.continue_on_failure;
+                        }
+                        report.push(entry);
+                    }
+                    if abort {
+                        pending.push(Pending::Abort);
+                    }
+                }
+            }
         }
         Ok(report)
     }
 }
+
+/// Return a field of the output of a command, named as in conditions.
+fn field(name: Field, value: FieldValue) -> (FieldName, FieldValue) {
+    // The names of the fields are constants, written in kebab-case.
+    (name.name().parse().unwrap(), value)
+}
+
+/// Set the variable capturing the output of `step`, if it succeeded.
+fn capture(entry: &ReportEntry, step: &Step, variables: &mut Variables) {
+    if let (CommandStatus::Success(Some(output)), Some(name)) = (&entry.status, &step.capture) {
+        let output = output.to_string();
+        variables.define(name, output.trim_end_matches('\n'));
+    }
+}
+
+/// Statements left to run by a `BatchRunner`.
+enum Pending<'a> {
+    Statements(std::slice::Iter<'a, Statement>),
+    /// Skip the next statements, after a failure.
+    Abort,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tonic::transport::Endpoint;
+
+    fn unreachable_runner() -> BatchRunner {
+        let channel = Endpoint::from_static("http://127.0.0.1:1").connect_lazy();
+        BatchRunner::new(ArtifexClient::new(channel))
+    }
+
+    fn statuses(report: &BatchReport) -> Vec<(&str, &CommandStatus)> {
+        report
+            .entries()
+            .iter()
+            .map(|e| (e.command.as_str(), &e.status))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn record_failing_statements() {
+        let batch = "IF uptime > 1h; EXECUTE: reboot; END; EXECUTE: date"
+            .parse::<Batch>()
+            .unwrap();
+        let report = unreachable_runner().run(&batch).await.unwrap();
+        let entries = statuses(&report);
+        assert_eq!(entries.len(), 3);
+        assert!(matches!(
+            entries[0],
+            ("IF uptime > 1h", CommandStatus::Failure(reason)) if reason.contains("line 1")
+        ));
+        assert_eq!(entries[1], ("EXECUTE: reboot", &CommandStatus::Skipped));
+        assert_eq!(entries[2], ("EXECUTE: date", &CommandStatus::Skipped));
+        assert!(!report.succeeded());
+
+        let batch =
+            "DEFAULT POLICY continue; EXECUTE: uname -r -> k; SET v = ${k}; EXECUTE: echo ${v}"
+                .parse::<Batch>()
+                .unwrap();
+        let report = unreachable_runner().run(&batch).await.unwrap();
+        let entries = statuses(&report);
+        assert_eq!(entries.len(), 3);
+        assert!(matches!(
+            entries[1],
+            ("SET v = ${k}", CommandStatus::Failure(reason)) if reason.contains("'k'")
+        ));
+        assert!(matches!(
+            entries[2],
+            ("EXECUTE: echo ${v}", CommandStatus::Failure(reason)) if reason.contains("'v'")
+        ));
+    }
+}
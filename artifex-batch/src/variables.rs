@@ -0,0 +1,262 @@
+//
+// Copyright (C) 2024 Eric Le Bihan <eric.le.bihan.dev@free.fr>
+//
+// SPDX-License-Identifier: MIT
+//
+
+use crate::ast::Location;
+use crate::command::{Command, Error as CommandError};
+use crate::error::Error;
+use std::collections::HashMap;
+
+/// Prefix of the environment variables defining variables of a batch.
+const ENV_PREFIX: &str = "ARTIFEX_VAR_";
+
+/// Part of a text where variables are substituted.
+#[derive(Debug, PartialEq)]
+enum Part<'a> {
+    Text(&'a str),
+    Variable(&'a str),
+}
+
+/// Return whether `name` is a valid variable name.
+pub(crate) fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Split `text` into literal parts and references to variables, written as
+/// `${name}`. `$${` is written as a literal `${`.
+fn parse_template(text: &str) -> Result<Vec<Part<'_>>, CommandError> {
+    let mut parts = vec![];
+    let mut rest = text;
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            parts.push(Part::Text(&rest[..start - 1]));
+            parts.push(Part::Text("${"));
+            rest = &rest[start + 2..];
+            continue;
+        }
+        parts.push(Part::Text(&rest[..start]));
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| CommandError::InvalidVariable(rest[start..].to_string()))?;
+        let name = &rest[start + 2..start + end];
+        if !is_valid_name(name) {
+            return Err(CommandError::InvalidVariable(name.to_string()));
+        }
+        parts.push(Part::Variable(name));
+        rest = &rest[start + end + 1..];
+    }
+    parts.push(Part::Text(rest));
+    Ok(parts)
+}
+
+/// Return the names of the variables referenced in `text`.
+pub(crate) fn references(text: &str) -> Result<Vec<&str>, CommandError> {
+    Ok(parse_template(text)?
+        .into_iter()
+        .filter_map(|p| match p {
+            Part::Variable(name) => Some(name),
+            Part::Text(_) => None,
+        })
+        .collect())
+}
+
+/// Substitute the references in `text` to the variables of `values`,
+/// keeping the other references and the escaped `${`.
+pub(crate) fn substitute(text: &str, values: &HashMap<&str, &str>) -> Result<String, CommandError> {
+    let mut substituted = String::new();
+    for part in parse_template(text)? {
+        match part {
+            Part::Text(text) => substituted.push_str(&text.replace("${", "$${")),
+            Part::Variable(name) => match values.get(name) {
+                Some(value) => substituted.push_str(value),
+                None => substituted.push_str(&format!("${{{}}}", name)),
+            },
+        }
+    }
+    Ok(substituted)
+}
+
+/// Return the texts of `command` where variables can be substituted.
+pub(crate) fn command_texts(command: &Command) -> Vec<&str> {
+    match command {
+        Command::Execute(command) => vec![command],
+        Command::Upgrade(Some(target)) => vec![target],
+        Command::Inspect | Command::Upgrade(None) => vec![],
+    }
+}
+
+/// Hold the values of the variables available to a batch.
+///
+/// Values set by the batch itself, with `SET` or by capturing the output of
+/// a command, override the ones defined before running it. Those can be
+/// defined explicitly, or taken from the environment of the process, where
+/// the variable `name` is defined by `ARTIFEX_VAR_name`.
+#[derive(Clone, Debug, Default)]
+pub struct Variables {
+    values: HashMap<String, String>,
+    environment: bool,
+}
+
+impl Variables {
+    /// Create an empty set of variables.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a set of variables falling back to the environment variables
+    /// of the process prefixed by `ARTIFEX_VAR_`. The other environment
+    /// variables are not available to batches.
+    pub fn from_env() -> Self {
+        Self {
+            values: HashMap::new(),
+            environment: true,
+        }
+    }
+
+    /// Return whether `name` can be used as a variable name, made of ASCII
+    /// letters, digits and underscores, not starting with a digit.
+    pub fn is_valid_name(name: &str) -> bool {
+        is_valid_name(name)
+    }
+
+    /// Set the value of a variable.
+    pub fn define(&mut self, name: &str, value: &str) {
+        self.values.insert(name.to_string(), value.to_string());
+    }
+
+    /// Set the value of a variable, unless it is already defined explicitly.
+    ///
+    /// It overrides the variable taken from the environment, if any.
+    pub fn define_default(&mut self, name: &str, value: &str) {
+        self.values
+            .entry(name.to_string())
+            .or_insert_with(|| value.to_string());
+    }
+
+    /// Return the value of a variable.
+    pub fn get(&self, name: &str) -> Option<String> {
+        self.values.get(name).cloned().or_else(|| {
+            self.environment
+                .then(|| std::env::var(format!("{}{}", ENV_PREFIX, name)).ok())
+                .flatten()
+        })
+    }
+
+    /// Return whether a variable is defined.
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Substitute the variables referenced in `text`, found at `location` in
+    /// the batch.
+    pub fn expand(&self, text: &str, location: &Location) -> Result<String, Error> {
+        let mut expanded = String::new();
+        let parts = parse_template(text).map_err(|source| Error::Syntax {
+            location: location.clone(),
+            source,
+        })?;
+        for part in parts {
+            match part {
+                Part::Text(text) => expanded.push_str(text),
+                Part::Variable(name) => {
+                    let value = self.get(name).ok_or_else(|| Error::UndefinedVariable {
+                        name: name.to_string(),
+                        location: location.clone(),
+                    })?;
+                    expanded.push_str(&value);
+                }
+            }
+        }
+        Ok(expanded)
+    }
+
+    /// Substitute the variables referenced in the arguments of `command`.
+    pub fn expand_command(&self, command: &Command, location: &Location) -> Result<Command, Error> {
+        let command = match command {
+            Command::Execute(command) => Command::Execute(self.expand(command, location)?),
+            Command::Upgrade(Some(target)) => {
+                Command::Upgrade(Some(self.expand(target, location)?))
+            }
+            command => command.clone(),
+        };
+        Ok(command)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_references() {
+        assert_eq!(
+            references("echo ${greeting}, ${name_2} $HOME").unwrap(),
+            vec!["greeting", "name_2"]
+        );
+        assert!(references("echo $${literal}").unwrap().is_empty());
+        assert!(references("echo ${unclosed").is_err());
+        assert!(references("echo ${2fast}").is_err());
+    }
+
+    #[test]
+    fn check_names() {
+        assert!(Variables::is_valid_name("_version2"));
+        for name in ["", "1st", "a b", "a-b"] {
+            assert!(!Variables::is_valid_name(name));
+        }
+    }
+
+    #[test]
+    fn substitute_variables() {
+        let values = HashMap::from([("host", "db1")]);
+        assert_eq!(
+            substitute("ping ${host} ${count} $${host}", &values).unwrap(),
+            "ping db1 ${count} $${host}"
+        );
+    }
+
+    #[test]
+    fn expand_variables() {
+        let mut variables = Variables::new();
+        variables.define("version", "2.0");
+        assert_eq!(
+            variables
+                .expand("upgrade to ${version} $${version}", &Location::default())
+                .unwrap(),
+            "upgrade to 2.0 ${version}"
+        );
+        let res = variables.expand("${missing}", &Location::new(None, 7));
+        assert!(matches!(
+            res,
+            Err(Error::UndefinedVariable { ref name, ref location }) if name == "missing" && location.line == 7
+        ));
+    }
+
+    #[test]
+    fn fall_back_to_environment() {
+        std::env::set_var("ARTIFEX_VAR_batch_test", "from-env");
+        std::env::set_var("ARTIFEX_BATCH_SECRET", "secret");
+        let mut variables = Variables::from_env();
+        assert_eq!(variables.get("batch_test").unwrap(), "from-env");
+        assert!(!variables.contains("ARTIFEX_VAR_batch_test"));
+        assert!(!variables.contains("ARTIFEX_BATCH_SECRET"));
+        variables.define("batch_test", "defined");
+        assert_eq!(variables.get("batch_test").unwrap(), "defined");
+        assert!(!Variables::new().contains("batch_test"));
+    }
+
+    #[test]
+    fn keep_explicit_definitions() {
+        std::env::set_var("ARTIFEX_VAR_batch_default", "from-env");
+        let mut variables = Variables::from_env();
+        variables.define("release", "explicit");
+        variables.define_default("release", "default");
+        variables.define_default("batch_default", "default");
+        assert_eq!(variables.get("release").unwrap(), "explicit");
+        assert_eq!(variables.get("batch_default").unwrap(), "default");
+    }
+}
@@ -4,16 +4,25 @@
 // SPDX-License-Identifier: MIT
 //
 
-use anyhow::{Context, Result};
-use artifex_batch::{Batch, BatchRunner, MarkupKind, MarkupReportRenderer};
-use artifex_rpc::artifex_client::ArtifexClient;
-use clap::{Parser, ValueEnum};
+use anyhow::{bail, Context, Result};
+use artifex_batch::{
+    report_schema, Batch, BatchRunner, DefinitionFormat, FleetMode, FleetRunner, Inventory,
+    MarkupKind, MarkupReportRenderer, Selector, Target, Variables,
+};
+use artifex_rpc::{
+    artifex_client::ArtifexClient, GetUpgradeHistoryRequest, TailFileRequest, WatchUpgradeRequest,
+};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use futures::StreamExt;
+use humantime::format_rfc3339_seconds;
 use std::{
+    collections::BTreeMap,
     fs::File,
-    io::{Read, Write},
-    path::PathBuf,
+    io::Write,
+    path::{Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
 };
-use tonic::transport::Endpoint;
+use tonic::transport::{Channel, Endpoint};
 
 const BATCH_DEFAULT: &str = r#"
 INSPECT
@@ -25,6 +34,7 @@ EXECUTE: uptime
 /// Format of the report
 #[derive(Clone, Debug, ValueEnum)]
 enum ReportFormat {
+    Json,
     Xml,
     Yaml,
 }
@@ -32,38 +42,282 @@ enum ReportFormat {
 impl From<ReportFormat> for MarkupKind {
     fn from(val: ReportFormat) -> Self {
         match val {
+            ReportFormat::Json => MarkupKind::Json,
             ReportFormat::Xml => MarkupKind::Xml,
             ReportFormat::Yaml => MarkupKind::Yaml,
         }
     }
 }
 
+/// Format of a structured batch definition
+#[derive(Clone, Debug, ValueEnum)]
+enum BatchFormat {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl From<BatchFormat> for DefinitionFormat {
+    fn from(val: BatchFormat) -> Self {
+        match val {
+            BatchFormat::Json => DefinitionFormat::Json,
+            BatchFormat::Toml => DefinitionFormat::Toml,
+            BatchFormat::Yaml => DefinitionFormat::Yaml,
+        }
+    }
+}
+
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
-    #[arg(short, long, value_enum, default_value_t = ReportFormat::Yaml)]
-    format: ReportFormat,
     #[arg(
         short,
         long,
+        global = true,
         help = "URL of the server",
         default_value = "http://127.0.0.1:50051"
     )]
     url: String,
+    #[command(flatten)]
+    run: RunArgs,
+    #[command(subcommand)]
+    command: Option<Commands>,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Print the last lines of a file of the server machine
+    Tail(TailArgs),
+    /// Follow the progression of an upgrade of the server machine
+    WatchUpgrade(WatchUpgradeArgs),
+    /// List the past upgrades of the server machine
+    History,
+    /// Check a batch file without connecting to the server
+    Validate(ValidateArgs),
+    /// Convert a batch file to a structured format
+    Convert(ConvertArgs),
+    /// Inspect the targets of an inventory
+    Inventory(InventoryArgs),
+    /// Print the JSON Schema of the JSON reports
+    Schema(SchemaArgs),
+}
+
+#[derive(Args)]
+struct RunArgs {
+    #[arg(short, long, value_enum, default_value_t = ReportFormat::Yaml)]
+    format: ReportFormat,
     #[arg(short, long, help = "Path to report file")]
     report: Option<PathBuf>,
+    #[arg(
+        short = 'D',
+        long = "define",
+        value_name = "NAME=VALUE",
+        value_parser = parse_definition,
+        help = "Define a variable of the batch, overriding ARTIFEX_VAR_NAME from the environment"
+    )]
+    definitions: Vec<(String, String)>,
+    #[arg(short, long, help = "Path to inventory file")]
+    inventory: Option<PathBuf>,
+    #[arg(
+        short,
+        long = "target",
+        value_name = "TARGET",
+        help = "Run the batch against a target of the inventory, or an URL if there is none"
+    )]
+    targets: Vec<String>,
+    #[arg(
+        short,
+        long = "group",
+        value_name = "GROUP",
+        help = "Run the batch against the targets of a group of the inventory"
+    )]
+    groups: Vec<String>,
+    #[arg(
+        long = "select",
+        value_name = "SELECTOR",
+        value_parser = parse_selector,
+        help = "Run the batch against the targets of the inventory whose labels match, such as role=db,site=paris"
+    )]
+    selectors: Vec<Selector>,
+    #[arg(
+        long,
+        help = "Number of targets to run the batch against at once",
+        default_value_t = 1
+    )]
+    parallel: usize,
+    #[arg(
+        long,
+        help = "Wait for the targets run at once to be done before starting the next ones"
+    )]
+    rolling: bool,
+    #[arg(
+        long,
+        value_name = "COUNT",
+        help = "Stop starting targets once the batch failed on that many of them"
+    )]
+    max_failures: Option<usize>,
     #[arg(help = "Path to batch file")]
     batch: Option<PathBuf>,
 }
 
-impl Cli {
-    fn batch(&self) -> Result<Box<dyn Read>, std::io::Error> {
-        match &self.batch {
-            Some(path) if path.as_os_str() == "-" => Ok(Box::new(std::io::stdin())),
-            Some(path) => File::open(path).map(|f| Box::new(f) as Box<dyn Read>),
-            None => Ok(Box::new(BATCH_DEFAULT.as_bytes())),
+#[derive(Args)]
+struct ValidateArgs {
+    #[arg(
+        short = 'D',
+        long = "define",
+        value_name = "NAME=VALUE",
+        value_parser = parse_definition,
+        help = "Define a variable of the batch, overriding ARTIFEX_VAR_NAME from the environment"
+    )]
+    definitions: Vec<(String, String)>,
+    #[arg(help = "Path to batch file")]
+    batch: PathBuf,
+}
+
+#[derive(Args)]
+struct ConvertArgs {
+    #[arg(short, long, value_enum, help = "Format of the converted batch")]
+    to: BatchFormat,
+    #[arg(help = "Path to batch file")]
+    batch: PathBuf,
+}
+
+#[derive(Args)]
+struct SchemaArgs {
+    #[arg(long, help = "Print the schema of the reports of fleets")]
+    fleet: bool,
+}
+
+#[derive(Args)]
+struct InventoryArgs {
+    #[arg(short, long, help = "Path to inventory file")]
+    inventory: PathBuf,
+    #[command(subcommand)]
+    command: InventoryCommands,
+}
+
+#[derive(Subcommand)]
+enum InventoryCommands {
+    /// List the targets of the inventory
+    List(ListArgs),
+    /// Print a target with its groups and the variables it inherits from them
+    Resolve(ResolveArgs),
+}
+
+#[derive(Args)]
+struct ListArgs {
+    #[arg(
+        short,
+        long = "group",
+        value_name = "GROUP",
+        help = "List the targets of a group"
+    )]
+    groups: Vec<String>,
+    #[arg(
+        long = "select",
+        value_name = "SELECTOR",
+        value_parser = parse_selector,
+        help = "List the targets whose labels match, such as role=db,site=paris"
+    )]
+    selectors: Vec<Selector>,
+}
+
+#[derive(Args)]
+struct ResolveArgs {
+    #[arg(help = "Name of the target")]
+    target: String,
+}
+
+fn parse_selector(s: &str) -> Result<Selector, String> {
+    s.parse().map_err(|e: artifex_batch::Error| e.to_string())
+}
+
+fn parse_definition(s: &str) -> Result<(String, String), String> {
+    let (name, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("invalid definition {}, expected NAME=VALUE", s))?;
+    if !Variables::is_valid_name(name) {
+        return Err(format!("invalid variable name {}", name));
+    }
+    Ok((name.to_string(), value.to_string()))
+}
+
+#[derive(Args)]
+struct TailArgs {
+    #[arg(
+        short = 'n',
+        long,
+        help = "Number of lines to print",
+        default_value_t = 10
+    )]
+    lines: u32,
+    #[arg(short, long, help = "Keep printing lines appended to the file")]
+    follow: bool,
+    #[arg(help = "Path to the file on the server machine")]
+    path: String,
+}
+
+#[derive(Args)]
+struct WatchUpgradeArgs {
+    #[arg(help = "Identifier of the upgrade session, defaults to the last one")]
+    session: Option<String>,
+}
+
+/// Load the batch at `path`, or the default one, printing the errors found
+/// in it.
+fn load_batch(path: Option<&Path>) -> Result<Batch> {
+    let res = match path {
+        Some(path) if path.as_os_str() == "-" => Batch::from_reader(std::io::stdin()),
+        Some(path) => Batch::from_file(path),
+        None => Batch::from_reader(BATCH_DEFAULT.as_bytes()),
+    };
+    match res {
+        Err(artifex_batch::Error::Parse(diagnostics)) => {
+            eprintln!("{}\n", diagnostics);
+            bail!("invalid batch, {} error(s) found", diagnostics.len());
         }
+        res => res.with_context(|| "failed to open batch"),
     }
+}
+
+/// Return the variables defined by the `ARTIFEX_VAR_` environment variables,
+/// overridden by `definitions`.
+fn variables(definitions: &[(String, String)]) -> Variables {
+    let mut variables = Variables::from_env();
+    for (name, value) in definitions {
+        variables.define(name, value);
+    }
+    variables
+}
+
+impl RunArgs {
+    /// Return whether the batch is run against several targets.
+    fn is_fleet(&self) -> bool {
+        self.inventory.is_some()
+            || !self.targets.is_empty()
+            || !self.groups.is_empty()
+            || !self.selectors.is_empty()
+    }
+
+    fn fleet_targets(&self) -> Result<Vec<Target>> {
+        match &self.inventory {
+            Some(path) => Inventory::from_file(path)
+                .with_context(|| "failed to read inventory")?
+                .select(&self.targets, &self.groups, &self.selectors)
+                .with_context(|| "failed to select targets"),
+            None => {
+                if !self.groups.is_empty() || !self.selectors.is_empty() {
+                    bail!("groups and selectors can only be used with an inventory");
+                }
+                Ok(self
+                    .targets
+                    .iter()
+                    .map(|url| Target::new(url, url))
+                    .collect())
+            }
+        }
+    }
+
     fn report(&self) -> Result<Box<dyn Write>, std::io::Error> {
         match &self.report {
             Some(path) => File::create(path).map(|f| Box::new(f) as Box<dyn Write>),
@@ -72,17 +326,24 @@ impl Cli {
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let args = Cli::parse();
-    let input = args.batch().with_context(|| "failed to open input")?;
-    let mut output = args.report().with_context(|| "failed to create report")?;
-    let endpoint = Endpoint::from_shared(args.url)?;
-    let client = ArtifexClient::connect(endpoint)
+async fn connect(url: String) -> Result<ArtifexClient<Channel>> {
+    let endpoint = Endpoint::from_shared(url)?;
+    ArtifexClient::connect(endpoint)
         .await
-        .with_context(|| "failed to connect to server")?;
-    let mut runner = BatchRunner::new(client);
-    let batch = Batch::from_reader(input).with_context(|| "failed to open batch")?;
+        .with_context(|| "failed to connect to server")
+}
+
+async fn run_batch(url: String, args: RunArgs) -> Result<()> {
+    if args.is_fleet() {
+        return run_fleet(args).await;
+    }
+    let batch = load_batch(args.batch.as_deref())?;
+    let mut output = args.report().with_context(|| "failed to create report")?;
+    let client = connect(url.clone()).await?;
+    let variables = variables(&args.definitions);
+    let mut runner = BatchRunner::new(client)
+        .with_variables(variables)
+        .with_target(&url);
     let report = runner
         .run(&batch)
         .await
@@ -91,5 +352,204 @@ async fn main() -> Result<()> {
     renderer
         .render(&mut output, &report)
         .with_context(|| "failed to render report")?;
+    if !report.succeeded() {
+        bail!("batch failed");
+    }
     Ok(())
 }
+
+async fn run_fleet(args: RunArgs) -> Result<()> {
+    let batch = load_batch(args.batch.as_deref())?;
+    let targets = args.fleet_targets()?;
+    let mut output = args.report().with_context(|| "failed to create report")?;
+    let mode = if args.rolling {
+        FleetMode::Rolling(args.parallel)
+    } else {
+        FleetMode::Parallel(args.parallel)
+    };
+    let mut runner = FleetRunner::new(targets)
+        .with_variables(variables(&args.definitions))
+        .with_mode(mode);
+    if let Some(max_failures) = args.max_failures {
+        runner = runner.with_max_failures(max_failures);
+    }
+    let report = runner
+        .run(&batch)
+        .await
+        .with_context(|| "failed to run batch")?;
+    let renderer = MarkupReportRenderer::new(args.format.into());
+    renderer
+        .render_fleet(&mut output, &report)
+        .with_context(|| "failed to render report")?;
+    if !report.succeeded() {
+        bail!("batch failed");
+    }
+    Ok(())
+}
+
+async fn tail_file(url: String, args: TailArgs) -> Result<()> {
+    let mut client = connect(url).await?;
+    let request = TailFileRequest {
+        path: args.path,
+        lines: args.lines,
+        follow: args.follow,
+    };
+    let mut stream = client
+        .tail_file(request)
+        .await
+        .with_context(|| "failed to tail file")?
+        .into_inner();
+    let mut stdout = std::io::stdout().lock();
+    while let Some(reply) = stream.next().await {
+        let reply = reply.with_context(|| "failed to receive lines")?;
+        for line in reply.lines {
+            writeln!(stdout, "{}", line)?;
+        }
+        stdout.flush()?;
+    }
+    Ok(())
+}
+
+async fn watch_upgrade(url: String, args: WatchUpgradeArgs) -> Result<()> {
+    let mut client = connect(url).await?;
+    let request = WatchUpgradeRequest {
+        session_id: args.session.unwrap_or_default(),
+    };
+    let mut stream = client
+        .watch_upgrade(request)
+        .await
+        .with_context(|| "failed to watch upgrade")?
+        .into_inner();
+    let mut stdout = std::io::stdout().lock();
+    while let Some(reply) = stream.next().await {
+        let reply = reply.with_context(|| "failed to receive upgrade progress")?;
+        write!(stdout, "{:?}: {}%", reply.status(), reply.position)?;
+        if !reply.phase.is_empty() {
+            write!(stdout, ", phase {} {}%", reply.phase, reply.phase_position)?;
+        }
+        if !reply.message.is_empty() {
+            write!(stdout, ": {}", reply.message)?;
+        }
+        if !reply.reason.is_empty() {
+            write!(stdout, ": {}", reply.reason)?;
+        }
+        writeln!(stdout)?;
+    }
+    Ok(())
+}
+
+async fn list_upgrades(url: String) -> Result<()> {
+    let mut client = connect(url).await?;
+    let reply = client
+        .get_upgrade_history(GetUpgradeHistoryRequest {})
+        .await
+        .with_context(|| "failed to get upgrade history")?
+        .into_inner();
+    let mut stdout = std::io::stdout().lock();
+    for record in reply.upgrades {
+        let started = UNIX_EPOCH + Duration::from_secs(record.started_at);
+        write!(
+            stdout,
+            "{} {} {} {:?}",
+            format_rfc3339_seconds(started),
+            record.session_id,
+            record.target,
+            record.outcome()
+        )?;
+        if !record.phase.is_empty() {
+            write!(stdout, " ({} phase, {}%)", record.phase, record.position)?;
+        }
+        if !record.reason.is_empty() {
+            write!(stdout, ": {}", record.reason)?;
+        }
+        writeln!(stdout)?;
+    }
+    Ok(())
+}
+
+fn validate_batch(args: ValidateArgs) -> Result<()> {
+    let batch = load_batch(Some(args.batch.as_path()))?;
+    batch
+        .check_variables(&variables(&args.definitions))
+        .with_context(|| "invalid batch")
+}
+
+fn convert_batch(args: ConvertArgs) -> Result<()> {
+    let batch = load_batch(Some(args.batch.as_path()))?;
+    let text = batch
+        .to_definition(args.to.into())
+        .with_context(|| "failed to convert batch")?;
+    println!("{}", text.trim_end());
+    Ok(())
+}
+
+/// Format a map as a comma-separated list of `key=value` items.
+fn format_map(map: &BTreeMap<String, String>) -> String {
+    map.iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn inspect_inventory(args: InventoryArgs) -> Result<()> {
+    let inventory =
+        Inventory::from_file(&args.inventory).with_context(|| "failed to read inventory")?;
+    let mut stdout = std::io::stdout().lock();
+    match args.command {
+        InventoryCommands::List(list_args) => {
+            let targets = inventory
+                .select(&[], &list_args.groups, &list_args.selectors)
+                .with_context(|| "failed to select targets")?;
+            for target in targets {
+                write!(stdout, "{} {}", target.name, target.url)?;
+                if !target.labels.is_empty() {
+                    write!(stdout, " {}", format_map(&target.labels))?;
+                }
+                writeln!(stdout)?;
+            }
+        }
+        InventoryCommands::Resolve(resolve_args) => {
+            let target = inventory
+                .resolve(&resolve_args.target)
+                .with_context(|| format!("unknown target {}", resolve_args.target))?;
+            writeln!(stdout, "name: {}", target.name)?;
+            writeln!(stdout, "url: {}", target.url)?;
+            writeln!(stdout, "groups: {}", target.groups.join(","))?;
+            writeln!(stdout, "labels: {}", format_map(&target.labels))?;
+            writeln!(stdout, "variables: {}", format_map(&target.variables))?;
+            if let Some(token) = &target.token {
+                writeln!(stdout, "token: {}", token)?;
+            }
+            if let Some(tls) = &target.tls {
+                let files = [("ca", &tls.ca), ("cert", &tls.cert), ("key", &tls.key)];
+                for (name, path) in files {
+                    if let Some(path) = path {
+                        writeln!(stdout, "tls {}: {}", name, path.display())?;
+                    }
+                }
+                if let Some(domain) = &tls.domain {
+                    writeln!(stdout, "tls domain: {}", domain)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Cli::parse();
+    match args.command {
+        Some(Commands::Tail(tail_args)) => tail_file(args.url, tail_args).await,
+        Some(Commands::WatchUpgrade(watch_args)) => watch_upgrade(args.url, watch_args).await,
+        Some(Commands::History) => list_upgrades(args.url).await,
+        Some(Commands::Validate(validate_args)) => validate_batch(validate_args),
+        Some(Commands::Convert(convert_args)) => convert_batch(convert_args),
+        Some(Commands::Inventory(inventory_args)) => inspect_inventory(inventory_args),
+        Some(Commands::Schema(schema_args)) => {
+            println!("{}", report_schema(schema_args.fleet));
+            Ok(())
+        }
+        None => run_batch(args.url, args.run).await,
+    }
+}
@@ -0,0 +1,170 @@
+//
+// Copyright (C) 2024 Eric Le Bihan <eric.le.bihan.dev@free.fr>
+//
+// SPDX-License-Identifier: MIT
+//
+
+use std::collections::VecDeque;
+
+use futures_util::stream::{abortable, AbortHandle, StreamExt};
+use tonic::codec::Streaming;
+use web_sys::HtmlInputElement;
+use yew::prelude::*;
+
+use crate::{
+    contexts::ServerContext,
+    rpc::{create_client, TailFileReply, TailFileRequest},
+};
+
+/// Number of lines requested from the end of the file.
+const INITIAL_LINES: u32 = 20;
+/// Maximum number of lines kept in the panel.
+const MAX_LINES: usize = 1000;
+
+pub enum Msg {
+    Follow,
+    LinesReceived(Vec<String>),
+    PathChanged(String),
+    ServerUpdated(ServerContext),
+    Stop,
+    TailFailed(String),
+    TailStarted(Box<Streaming<TailFileReply>>),
+}
+
+pub struct LogPanel {
+    server: ServerContext,
+    _listener: ContextHandle<ServerContext>,
+    path: String,
+    lines: VecDeque<String>,
+    error: Option<String>,
+    handle: Option<AbortHandle>,
+}
+
+impl LogPanel {
+    fn stop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            handle.abort();
+        }
+    }
+}
+
+impl Component for LogPanel {
+    type Message = Msg;
+    type Properties = ();
+
+    fn create(ctx: &Context<Self>) -> Self {
+        let (server, listener) = ctx
+            .link()
+            .context(ctx.link().callback(Msg::ServerUpdated))
+            .expect("No server provided");
+        Self {
+            server,
+            _listener: listener,
+            path: String::new(),
+            lines: VecDeque::new(),
+            error: None,
+            handle: None,
+        }
+    }
+
+    fn update(&mut self, ctx: &Context<Self>, msg: Self::Message) -> bool {
+        match msg {
+            Msg::Follow => {
+                self.stop();
+                let mut client = create_client(&self.server.url);
+                let request = TailFileRequest {
+                    path: self.path.clone(),
+                    lines: INITIAL_LINES,
+                    follow: true,
+                };
+                ctx.link().send_future(async move {
+                    match client.tail_file(request).await {
+                        Ok(stream) => Msg::TailStarted(Box::new(stream.into_inner())),
+                        Err(e) => Msg::TailFailed(e.message().to_string()),
+                    }
+                });
+                self.lines.clear();
+                self.error = None;
+                true
+            }
+            Msg::LinesReceived(lines) => {
+                self.lines.extend(lines);
+                let excess = self.lines.len().saturating_sub(MAX_LINES);
+                self.lines.drain(..excess);
+                true
+            }
+            Msg::PathChanged(path) => {
+                self.path = path;
+                false
+            }
+            Msg::ServerUpdated(server) => {
+                self.server = server;
+                true
+            }
+            Msg::Stop => {
+                self.stop();
+                true
+            }
+            Msg::TailFailed(message) => {
+                self.stop();
+                self.error = Some(message);
+                true
+            }
+            Msg::TailStarted(stream) => {
+                let (stream, handle) = abortable(*stream);
+                ctx.link().send_stream(stream.map(|item| match item {
+                    Ok(reply) => Msg::LinesReceived(reply.lines),
+                    Err(e) => Msg::TailFailed(e.message().to_string()),
+                }));
+                self.handle = Some(handle);
+                true
+            }
+        }
+    }
+
+    fn destroy(&mut self, _ctx: &Context<Self>) {
+        self.stop();
+    }
+
+    fn view(&self, ctx: &Context<Self>) -> Html {
+        let oninput = ctx.link().callback(|e: InputEvent| {
+            let input: HtmlInputElement = e.target_unchecked_into();
+            Msg::PathChanged(input.value())
+        });
+        let onclick = if self.handle.is_some() {
+            ctx.link().callback(|e: MouseEvent| {
+                e.prevent_default();
+                Msg::Stop
+            })
+        } else {
+            ctx.link().callback(|e: MouseEvent| {
+                e.prevent_default();
+                Msg::Follow
+            })
+        };
+        let label = if self.handle.is_some() {
+            "Stop"
+        } else {
+            "Follow"
+        };
+        let output = match &self.error {
+            Some(message) => format!("Tail failed: {}", message),
+            None => self
+                .lines
+                .iter()
+                .cloned()
+                .collect::<Vec<String>>()
+                .join("\n"),
+        };
+        html! {
+            <div class="server-operations">
+              <form>
+                <label for="log-path">{"Follow file:"}</label>
+                <input id="log-path" type="text" { oninput }/>
+                <button id="follow" { onclick }>{ label }</button>
+              </form>
+              <textarea rows=20 cols=80 readonly=true value={ output } />
+            </div>
+        }
+    }
+}
@@ -6,6 +6,7 @@
 
 mod execution;
 mod inspection;
+mod log_panel;
 mod settings;
 mod tab;
 mod tab_list;
@@ -13,6 +14,7 @@ mod upgrade;
 
 pub use execution::Execution;
 pub use inspection::Inspection;
+pub use log_panel::LogPanel;
 pub use settings::Settings;
 pub use tab::Tab;
 pub use tab_list::TabList;
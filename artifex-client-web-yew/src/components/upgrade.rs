@@ -6,26 +6,28 @@
 
 use futures_util::StreamExt;
 use tonic::codec::Streaming;
+use web_sys::HtmlInputElement;
 use yew::prelude::*;
 
 use crate::{
     contexts::ServerContext,
-    rpc::{create_client, UpgradeReply, UpgradeRequest},
+    rpc::{create_client, upgrade_reply, UpgradeReply, UpgradeRequest},
 };
 
 pub enum UpgradeState {
     Complete,
     Failure(String),
     Idle,
-    Progressed(usize),
+    Progressed(UpgradeReply),
     Started,
 }
 
 pub enum Msg {
     ServerUpdated(ServerContext),
+    TargetChanged(String),
     Upgrade,
     UpgradeFailed(String),
-    UpgradeProgressed(usize),
+    UpgradeProgressed(UpgradeReply),
     UpgradeStarted(Streaming<UpgradeReply>),
 }
 
@@ -33,6 +35,7 @@ pub struct Upgrade {
     server: ServerContext,
     state: UpgradeState,
     _listener: ContextHandle<ServerContext>,
+    target: String,
 }
 
 impl Component for Upgrade {
@@ -48,6 +51,7 @@ impl Component for Upgrade {
             server,
             state: UpgradeState::Idle,
             _listener: listener,
+            target: String::new(),
         }
     }
 
@@ -57,10 +61,15 @@ impl Component for Upgrade {
                 self.server = server;
                 true
             }
+            Msg::TargetChanged(target) => {
+                self.target = target;
+                false
+            }
             Msg::Upgrade => {
                 let mut client = create_client(&self.server.url);
+                let target = self.target.clone();
                 ctx.link().send_future(async move {
-                    match client.upgrade(UpgradeRequest {}).await {
+                    match client.upgrade(UpgradeRequest { target }).await {
                         Ok(stream) => Msg::UpgradeStarted(stream.into_inner()),
                         Err(e) => Msg::UpgradeFailed(e.to_string()),
                     }
@@ -71,17 +80,24 @@ impl Component for Upgrade {
                 self.state = UpgradeState::Failure(message);
                 true
             }
-            Msg::UpgradeProgressed(position) => {
-                self.state = if position == 100 {
+            Msg::UpgradeProgressed(reply) => {
+                self.state = if reply.status() == upgrade_reply::Status::Success {
                     UpgradeState::Complete
                 } else {
-                    UpgradeState::Progressed(position)
+                    UpgradeState::Progressed(reply)
                 };
                 true
             }
             Msg::UpgradeStarted(stream) => {
                 ctx.link().send_stream(stream.map(|item| match item {
-                    Ok(reply) => Msg::UpgradeProgressed(reply.position as usize),
+                    Ok(reply) if reply.status() == upgrade_reply::Status::Failure => {
+                        Msg::UpgradeFailed(if reply.failed_phase.is_empty() {
+                            reply.reason
+                        } else {
+                            format!("{} ({} phase)", reply.reason, reply.failed_phase)
+                        })
+                    }
+                    Ok(reply) => Msg::UpgradeProgressed(reply),
                     Err(e) => Msg::UpgradeFailed(e.message().to_string()),
                 }));
                 self.state = UpgradeState::Started;
@@ -91,6 +107,10 @@ impl Component for Upgrade {
     }
 
     fn view(&self, ctx: &Context<Self>) -> Html {
+        let oninput = ctx.link().callback(|e: InputEvent| {
+            let input: HtmlInputElement = e.target_unchecked_into();
+            Msg::TargetChanged(input.value())
+        });
         let onclick = ctx.link().callback(|e: MouseEvent| {
             e.prevent_default();
             Msg::Upgrade
@@ -99,17 +119,35 @@ impl Component for Upgrade {
             UpgradeState::Complete => (100, "Success".to_string()),
             UpgradeState::Failure(message) => (0, format!("Error: {}", message)),
             UpgradeState::Idle => (0, "".to_string()),
-            UpgradeState::Progressed(position) => (*position, format!("{}%", *position)),
+            UpgradeState::Progressed(reply) => (reply.position, format!("{}%", reply.position)),
             UpgradeState::Started => (0, "Started".to_string()),
         };
+        let details = match &self.state {
+            UpgradeState::Progressed(reply) => {
+                let mut details = format!("{}: {}%", reply.phase, reply.phase_position);
+                if reply.bytes_processed > 0 {
+                    details.push_str(&format!(", {} bytes", reply.bytes_processed));
+                }
+                if let Some(eta) = reply.eta {
+                    details.push_str(&format!(", {}s remaining", eta));
+                }
+                if !reply.message.is_empty() {
+                    details.push_str(&format!(" - {}", reply.message));
+                }
+                details
+            }
+            _ => String::new(),
+        };
         html! {
             <div class="server-operations">
               <form>
-                <label for="start">{"Perform upgrade"}</label>
+                <label for="target">{"Upgrade to:"}</label>
+                <input id="target" type="text" { oninput }/>
                 <button id="start" { onclick }>{"Start"}</button>
               </form>
               <progress id="progress-bar" role="progress" max="100" value={ value.to_string() } />
               <label for="progress-bar">{ text }</label>
+              <p id="progress-details">{ details }</p>
             </div>
         }
     }
@@ -7,7 +7,7 @@
 use yew::prelude::*;
 
 use artifex_client_web_yew::{
-    components::{Execution, Inspection, Settings, Tab, TabList, Upgrade},
+    components::{Execution, Inspection, LogPanel, Settings, Tab, TabList, Upgrade},
     contexts::ServerProvider,
 };
 
@@ -29,6 +29,9 @@ fn App() -> Html {
                 <Tab title="Upgrade" >
                   <Upgrade />
                 </Tab>
+                <Tab title="Logs" >
+                  <LogPanel />
+                </Tab>
               </TabList>
             </ServerProvider>
           </div>
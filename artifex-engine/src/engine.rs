@@ -4,11 +4,14 @@
 // SPDX-License-Identifier: MIT
 //
 
-use crate::error::Result;
+use crate::error::{Error, Result};
 use crate::machine::{get_machine_info, MachineInfo};
-use rand::{thread_rng, Rng};
-use random_progression::RandomProgression;
+use crate::upgrade::{
+    BundleVerifier, EtaEstimator, PhaseProgress, SimulationBackend, UpgradeBackend, UpgradePhase,
+    UpgradeProgress,
+};
 use std::ffi::OsStr;
+use std::time::SystemTime;
 
 pub struct ProgramOutput {
     pub code: i32,
@@ -16,10 +19,36 @@ pub struct ProgramOutput {
     pub stderr: String,
 }
 
-#[derive(Default)]
-pub struct Engine;
+pub struct Engine {
+    upgrade_backend: Box<dyn UpgradeBackend>,
+    bundle_verifier: Option<BundleVerifier>,
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Self::new(Box::new(SimulationBackend::new()))
+    }
+}
 
 impl Engine {
+    /// Create an engine performing upgrades using `upgrade_backend`.
+    pub fn new(upgrade_backend: Box<dyn UpgradeBackend>) -> Self {
+        Self {
+            upgrade_backend,
+            bundle_verifier: None,
+        }
+    }
+
+    /// Only upgrade using bundles accepted by `verifier`.
+    ///
+    /// The upgrade target is then the path to a bundle, whose payload is
+    /// handed to the upgrade backend once verified. Without a verifier, the
+    /// target is handed as is to the upgrade backend, which may not
+    /// authenticate it.
+    pub fn set_bundle_verifier(&mut self, verifier: BundleVerifier) {
+        self.bundle_verifier = Some(verifier);
+    }
+
     pub fn inspect(&self) -> Result<MachineInfo> {
         get_machine_info()
     }
@@ -37,32 +66,116 @@ impl Engine {
         })
     }
 
-    pub fn upgrade<F>(&self, notify: F) -> Result<()>
+    /// Upgrade the system to `target`, reporting the progression via `notify`.
+    ///
+    /// Each phase of the upgrade accounts for the same share of the overall
+    /// progression. On failure, an `Error::UpgradeFailed` holding the failing
+    /// phase and the cause of the failure is returned. The verification of the
+    /// bundle is part of the download phase.
+    pub fn upgrade<F>(&mut self, target: &str, notify: F) -> Result<()>
     where
-        F: Fn(u8),
+        F: Fn(&UpgradeProgress),
     {
-        let progression = RandomProgression::new();
-        let mut rng = thread_rng();
-        let delay: u16 = rng.gen_range(500..2000);
-        let duration = std::time::Duration::from_millis(delay as u64);
-        for position in progression {
-            std::thread::sleep(duration);
-            notify(position);
+        // The bundle must outlive the upgrade, as it owns the verified copy
+        // of the payload.
+        let bundle;
+        let payload;
+        let target = match &self.bundle_verifier {
+            Some(verifier) => {
+                bundle = verifier
+                    .verify(target)
+                    .map_err(|e| Error::UpgradeFailed(UpgradePhase::Download, Box::new(e)))?;
+                payload = bundle.payload();
+                payload.to_string_lossy()
+            }
+            None => target.into(),
+        };
+        let estimator = EtaEstimator::new();
+        for phase in UpgradePhase::ALL {
+            let notify = |progress: PhaseProgress| {
+                let position = phase.overall_position(progress.position);
+                notify(&UpgradeProgress {
+                    phase,
+                    phase_position: progress.position,
+                    position,
+                    message: progress.message,
+                    bytes_processed: progress.bytes_processed,
+                    eta: estimator.estimate(position),
+                });
+                Ok(())
+            };
+            let backend = &mut self.upgrade_backend;
+            match phase {
+                UpgradePhase::Download => backend.download(&target, &notify),
+                UpgradePhase::Install => backend.install(&notify),
+                UpgradePhase::Verify => backend.verify(&notify),
+                UpgradePhase::Finalize => backend.finalize(&notify),
+            }
+            .map_err(|e| Error::UpgradeFailed(phase, Box::new(e)))?;
         }
         Ok(())
     }
+
+    /// Make the last upgrade permanent.
+    pub fn confirm_upgrade(&mut self) -> Result<()> {
+        self.upgrade_backend.confirm()
+    }
+
+    /// Revert the last upgrade.
+    pub fn rollback_upgrade(&mut self) -> Result<()> {
+        self.upgrade_backend.rollback()
+    }
+
+    /// Return the time before which the last upgrade must be confirmed.
+    pub fn confirmation_deadline(&self) -> Result<Option<SystemTime>> {
+        self.upgrade_backend.confirmation_deadline()
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::upgrade::tests::{create_bundle, public_key};
+    use random_progression::{Clock, VirtualClock};
+    use std::cell::RefCell;
+    use std::sync::Arc;
+    use std::time::Duration;
 
-    #[test]
-    fn do_progressive_stuff() {
-        let engine = Engine {};
-        let res = engine.upgrade(|position| {
-            println!("Progression: {}%", position);
+    fn simulate_upgrade(seed: u64, clock: &VirtualClock) -> Vec<u8> {
+        let backend = SimulationBackend::new()
+            .with_seed(seed)
+            .with_clock(Arc::new(clock.clone()));
+        let mut engine = Engine::new(Box::new(backend));
+        let positions = RefCell::new(vec![]);
+        let res = engine.upgrade("", |progress| {
+            positions.borrow_mut().push(progress.position);
         });
         assert!(res.is_ok());
+        positions.into_inner()
+    }
+
+    #[test]
+    fn do_progressive_stuff() {
+        let clock = VirtualClock::new();
+        let positions = simulate_upgrade(42, &clock);
+        assert_eq!(positions, simulate_upgrade(42, &VirtualClock::new()));
+        assert_eq!(positions.last(), Some(&100));
+        assert!(positions.windows(2).all(|w| w[0] <= w[1]));
+        assert!(clock.elapsed() >= Duration::from_millis(500));
+    }
+
+    #[test]
+    fn reject_unsigned_bundle() {
+        let path = create_bundle("engine", b"payload", &[1; 32]);
+        let mut engine = Engine::default();
+        engine.set_bundle_verifier(BundleVerifier::new(&[public_key()]).unwrap());
+        let res = engine.upgrade(path.to_str().unwrap(), |_| {});
+        match res {
+            Err(Error::UpgradeFailed(phase, e)) => {
+                assert_eq!(phase, UpgradePhase::Download);
+                assert!(matches!(*e, Error::InvalidSignature));
+            }
+            _ => panic!("unexpected result"),
+        }
     }
 }
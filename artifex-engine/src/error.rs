@@ -4,16 +4,49 @@
 // SPDX-License-Identifier: MIT
 //
 
+use crate::upgrade::UpgradePhase;
 use thiserror::Error;
 
 #[derive(Debug, Error)]
 pub enum Error {
+    #[error("Upgrade backend error: {0}")]
+    Backend(#[source] Box<dyn std::error::Error + Send + Sync>),
+    #[error("Checksum mismatch for {0}")]
+    ChecksumMismatch(String),
+    #[error("Bundle not compatible with this machine: {0}")]
+    IncompatibleBundle(String),
+    #[error("Injected failure at {0}%")]
+    InjectedFault(u8),
+    #[error("Invalid bundle: {0}")]
+    InvalidBundle(String),
+    #[error("Invalid public key: {0}")]
+    InvalidKey(String),
+    #[error("Invalid bundle signature")]
+    InvalidSignature,
+    #[error("Invalid slot: {0}")]
+    InvalidSlot(String),
     #[error("I/O error: {0}")]
     Io(#[from] std::io::Error),
     #[error("Unix error: {0}")]
     Nix(#[from] nix::Error),
+    #[error("No upgrade in progress")]
+    NoUpgradeInProgress,
+    #[error("No upgrade pending confirmation")]
+    NoUpgradePending,
+    #[error("Script {0} failed with exit code {1}")]
+    ScriptFailed(String, i32),
     #[error("Unknown error")]
     Unknown,
+    #[error("Unknown upgrade phase: {0}")]
+    UnknownPhase(String),
+    #[error("Operation not supported by upgrade backend")]
+    Unsupported,
+    #[error("Upgrade failed during {0} phase: {1}")]
+    UpgradeFailed(UpgradePhase, Box<Error>),
+    #[error("Upgraded system not booted yet")]
+    UpgradeNotBooted,
+    #[error("Previous upgrade pending confirmation")]
+    UpgradePending,
     #[error("UTF-8 decoding/encoding error")]
     Utf8(#[from] std::str::Utf8Error),
 }
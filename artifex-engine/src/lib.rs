@@ -7,7 +7,15 @@
 mod engine;
 mod error;
 mod machine;
+mod tail;
+mod upgrade;
 
 pub use engine::Engine;
 pub use error::{Error, Result};
 pub use machine::MachineInfo;
+pub use tail::FileTail;
+pub use upgrade::{
+    AbSlotBackend, BootControl, Bundle, BundleFile, BundleManifest, BundleVerifier, FailurePoint,
+    FaultInjectionBackend, FileBootControl, PhaseProgress, ScriptBackend, ScriptStep,
+    SimulationBackend, Slot, UpgradeBackend, UpgradePhase, UpgradeProgress,
+};
@@ -0,0 +1,189 @@
+//
+// Copyright (C) 2024 Eric Le Bihan <eric.le.bihan.dev@free.fr>
+//
+// SPDX-License-Identifier: MIT
+//
+
+use crate::error::Result;
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::os::fd::AsFd;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const BLOCK_SIZE: u64 = 4096;
+
+/// Read the last lines of a file, then follow the lines appended to it.
+///
+/// Following survives log rotation: the file is reopened when its inode
+/// changes and read again from its beginning when it gets truncated.
+pub struct FileTail {
+    path: PathBuf,
+    file: File,
+    inode: u64,
+    position: u64,
+    pending: Vec<u8>,
+    inotify: Inotify,
+}
+
+impl FileTail {
+    /// Open a file to tail.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = File::open(&path)?;
+        let inode = file.metadata()?.ino();
+        let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)?;
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        inotify.add_watch(
+            parent,
+            AddWatchFlags::IN_MODIFY
+                | AddWatchFlags::IN_ATTRIB
+                | AddWatchFlags::IN_CREATE
+                | AddWatchFlags::IN_MOVED_TO
+                | AddWatchFlags::IN_CLOSE_WRITE,
+        )?;
+        Ok(Self {
+            path,
+            file,
+            inode,
+            position: 0,
+            pending: vec![],
+            inotify,
+        })
+    }
+
+    /// Return the last `count` lines of the file and move to its end.
+    pub fn read_last(&mut self, count: usize) -> Result<Vec<String>> {
+        let length = self.file.seek(SeekFrom::End(0))?;
+        let mut start = length;
+        let mut buffer: Vec<u8> = vec![];
+        while start > 0 {
+            let size = BLOCK_SIZE.min(start);
+            start -= size;
+            let mut block = vec![0; size as usize];
+            self.file.seek(SeekFrom::Start(start))?;
+            self.file.read_exact(&mut block)?;
+            block.append(&mut buffer);
+            buffer = block;
+            let body = buffer.strip_suffix(b"\n").unwrap_or(&buffer);
+            if body.iter().filter(|&&b| b == b'\n').count() >= count {
+                break;
+            }
+        }
+        self.position = length;
+        self.pending.clear();
+        let text = String::from_utf8_lossy(&buffer);
+        let lines: Vec<&str> = text.lines().collect();
+        let skipped = lines.len().saturating_sub(count);
+        Ok(lines[skipped..].iter().map(|l| l.to_string()).collect())
+    }
+
+    /// Wait up to `timeout` for the file to change, then return the lines
+    /// appended to it since last read.
+    ///
+    /// An empty list is returned if nothing was appended in time.
+    pub fn follow(&mut self, timeout: Duration) -> Result<Vec<String>> {
+        self.wait(timeout)?;
+        if self.file.metadata()?.len() < self.position {
+            self.position = 0;
+            self.pending.clear();
+        }
+        let mut lines = self.read_appended()?;
+        match std::fs::metadata(&self.path) {
+            Ok(metadata) if metadata.ino() != self.inode => {
+                self.file = File::open(&self.path)?;
+                self.inode = self.file.metadata()?.ino();
+                self.position = 0;
+                if !self.pending.is_empty() {
+                    lines.push(String::from_utf8_lossy(&self.pending).to_string());
+                    self.pending.clear();
+                }
+                lines.extend(self.read_appended()?);
+            }
+            _ => {}
+        }
+        Ok(lines)
+    }
+
+    fn wait(&mut self, timeout: Duration) -> Result<()> {
+        let millis = timeout.as_millis().min(u16::MAX as u128) as u16;
+        let mut fds = [PollFd::new(self.inotify.as_fd(), PollFlags::POLLIN)];
+        if poll(&mut fds, PollTimeout::from(millis))? > 0 {
+            // Events are only used to wake up: the file state is checked anyway.
+            let _ = self.inotify.read_events()?;
+        }
+        Ok(())
+    }
+
+    fn read_appended(&mut self) -> Result<Vec<String>> {
+        let mut buffer = vec![];
+        self.file.seek(SeekFrom::Start(self.position))?;
+        self.position += self.file.read_to_end(&mut buffer)? as u64;
+        self.pending.append(&mut buffer);
+        let mut lines = vec![];
+        while let Some(index) = self.pending.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.pending.drain(..=index).collect();
+            lines.push(String::from_utf8_lossy(&line[..index]).to_string());
+        }
+        Ok(lines)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    const TIMEOUT: Duration = Duration::from_millis(100);
+
+    fn create_log(name: &str, text: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("artifex-tail-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("test.log");
+        std::fs::write(&path, text).unwrap();
+        path
+    }
+
+    fn append(path: &Path, text: &str) {
+        let mut file = std::fs::OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(text.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn read_last_lines() {
+        let path = create_log("last", "one\ntwo\nthree\nfour\n");
+        let mut tail = FileTail::open(&path).unwrap();
+        assert_eq!(tail.read_last(2).unwrap(), vec!["three", "four"]);
+        assert_eq!(tail.read_last(10).unwrap().len(), 4);
+        assert!(tail.read_last(0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn follow_rotation() {
+        let path = create_log("rotation", "one\n");
+        let mut tail = FileTail::open(&path).unwrap();
+        tail.read_last(10).unwrap();
+        append(&path, "two\nthr");
+        assert_eq!(tail.follow(TIMEOUT).unwrap(), vec!["two"]);
+        append(&path, "ee\n");
+        assert_eq!(tail.follow(TIMEOUT).unwrap(), vec!["three"]);
+
+        std::fs::write(&path, "").unwrap();
+        assert!(tail.follow(TIMEOUT).unwrap().is_empty());
+        append(&path, "truncated\n");
+        assert_eq!(tail.follow(TIMEOUT).unwrap(), vec!["truncated"]);
+
+        append(&path, "last\n");
+        std::fs::rename(&path, path.with_extension("log.1")).unwrap();
+        std::fs::write(&path, "rotated\n").unwrap();
+        assert_eq!(tail.follow(TIMEOUT).unwrap(), vec!["last", "rotated"]);
+    }
+}
@@ -0,0 +1,500 @@
+//
+// Copyright (C) 2024 Eric Le Bihan <eric.le.bihan.dev@free.fr>
+//
+// SPDX-License-Identifier: MIT
+//
+
+use super::{PhaseProgress, UpgradeBackend};
+use crate::error::{Error, Result};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const BLOCK_SIZE: usize = 64 * 1024;
+
+/// Variable of the bootloader environment holding the slot to boot.
+const BOOT_SLOT_VARIABLE: &str = "boot_slot";
+/// Variable holding the slot to boot again if the upgrade is not confirmed.
+const ROLLBACK_SLOT_VARIABLE: &str = "rollback_slot";
+/// Variable holding the time, in seconds since UNIX epoch, before which the
+/// upgrade must be confirmed.
+const DEADLINE_VARIABLE: &str = "upgrade_deadline";
+
+/// Slot of an A/B partitioning scheme.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Slot {
+    A,
+    B,
+}
+
+impl Slot {
+    /// Return the other slot.
+    pub fn other(&self) -> Slot {
+        match self {
+            Slot::A => Slot::B,
+            Slot::B => Slot::A,
+        }
+    }
+
+    fn index(&self) -> usize {
+        match self {
+            Slot::A => 0,
+            Slot::B => 1,
+        }
+    }
+}
+
+impl Display for Slot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Slot::A => write!(f, "a"),
+            Slot::B => write!(f, "b"),
+        }
+    }
+}
+
+impl FromStr for Slot {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "a" | "A" => Ok(Slot::A),
+            "b" | "B" => Ok(Slot::B),
+            _ => Err(Error::InvalidSlot(s.to_string())),
+        }
+    }
+}
+
+/// Access to the slot devices and the bootloader environment of a machine.
+pub trait BootControl: Send {
+    /// Return the path to the device holding `slot`.
+    fn slot_device(&self, slot: Slot) -> &Path;
+    /// Return the slot the running system was booted from.
+    fn booted_slot(&self) -> Slot;
+    /// Return the value of a variable of the bootloader environment.
+    fn get_env(&self, name: &str) -> Result<Option<String>>;
+    /// Update the bootloader environment at once. Variables with no value are
+    /// removed.
+    fn set_env(&mut self, variables: &[(&str, Option<String>)]) -> Result<()>;
+}
+
+/// Bootloader environment stored as `name=value` lines in a file, with slot
+/// devices given as paths.
+#[derive(Debug)]
+pub struct FileBootControl {
+    slots: [PathBuf; 2],
+    environment: PathBuf,
+    booted: Slot,
+}
+
+impl FileBootControl {
+    /// Create a new boot control using `slots` devices for slots A and B and
+    /// `environment` as bootloader environment file.
+    ///
+    /// The slot booted is the slot to boot according to `environment` when
+    /// the boot control is created, which must then happen at startup, before
+    /// any upgrade.
+    pub fn new<P: AsRef<Path>>(slots: [PathBuf; 2], environment: P) -> Result<Self> {
+        let mut boot_control = Self {
+            slots,
+            environment: environment.as_ref().to_path_buf(),
+            booted: Slot::A,
+        };
+        if let Some(slot) = boot_control.get_env(BOOT_SLOT_VARIABLE)? {
+            boot_control.booted = slot.parse()?;
+        }
+        Ok(boot_control)
+    }
+
+    fn read_env(&self) -> Result<BTreeMap<String, String>> {
+        let text = match std::fs::read_to_string(&self.environment) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(text
+            .lines()
+            .filter_map(|l| l.split_once('='))
+            .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+            .collect())
+    }
+}
+
+impl BootControl for FileBootControl {
+    fn slot_device(&self, slot: Slot) -> &Path {
+        &self.slots[slot.index()]
+    }
+
+    fn booted_slot(&self) -> Slot {
+        self.booted
+    }
+
+    fn get_env(&self, name: &str) -> Result<Option<String>> {
+        Ok(self.read_env()?.remove(name))
+    }
+
+    fn set_env(&mut self, variables: &[(&str, Option<String>)]) -> Result<()> {
+        let mut env = self.read_env()?;
+        for (name, value) in variables {
+            match value {
+                Some(value) => env.insert(name.to_string(), value.clone()),
+                None => env.remove(*name),
+            };
+        }
+        let text: String = env.iter().map(|(k, v)| format!("{}={}\n", k, v)).collect();
+        let mut path = self.environment.clone().into_os_string();
+        path.push(".new");
+        let mut file = File::create(&path)?;
+        file.write_all(text.as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(&path, &self.environment)?;
+        Ok(())
+    }
+}
+
+/// Information about the image being installed.
+#[derive(Debug)]
+struct Installation {
+    image: PathBuf,
+    size: u64,
+    checksum: String,
+    slot: Slot,
+}
+
+/// Upgrade a machine by writing an image into the inactive slot of an A/B
+/// partitioning scheme, then booting it.
+///
+/// The upgrade target is the path to the image, whose SHA-256 checksum is
+/// read from a sibling file with the `.sha256` extension appended. The new
+/// slot must be confirmed within the confirmation window, once booted,
+/// otherwise the previous slot is booted again.
+///
+/// The checksum file is not authenticated: on its own, it only detects
+/// corrupted images. To only install signed images, set a `BundleVerifier`
+/// on the engine, which then hands over a verified copy of the payload of the
+/// bundle, along with the checksum from its signed manifest.
+pub struct AbSlotBackend<C: BootControl> {
+    boot_control: C,
+    confirmation_window: Duration,
+    installation: Option<Installation>,
+}
+
+impl<C: BootControl> AbSlotBackend<C> {
+    /// Create a new backend using `boot_control` to access the slots.
+    pub fn new(boot_control: C, confirmation_window: Duration) -> Self {
+        Self {
+            boot_control,
+            confirmation_window,
+            installation: None,
+        }
+    }
+
+    /// Return the slot to boot next.
+    pub fn boot_slot(&self) -> Result<Slot> {
+        match self.boot_control.get_env(BOOT_SLOT_VARIABLE)? {
+            Some(slot) => slot.parse(),
+            None => Ok(Slot::A),
+        }
+    }
+
+    fn installation(&self) -> Result<&Installation> {
+        self.installation.as_ref().ok_or(Error::NoUpgradeInProgress)
+    }
+}
+
+fn read_checksum(image: &Path) -> Result<String> {
+    let mut path = image.to_path_buf().into_os_string();
+    path.push(".sha256");
+    let text = std::fs::read_to_string(path)?;
+    let checksum = text.split_whitespace().next().unwrap_or_default();
+    Ok(checksum.to_lowercase())
+}
+
+fn copy_with_progress<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    size: u64,
+    hasher: &mut Sha256,
+    notify: &dyn Fn(u64, u8) -> Result<()>,
+) -> Result<()> {
+    let mut buffer = vec![0; BLOCK_SIZE];
+    let mut done: u64 = 0;
+    while done < size {
+        let count = BLOCK_SIZE.min((size - done) as usize);
+        reader.read_exact(&mut buffer[..count])?;
+        hasher.update(&buffer[..count]);
+        writer.write_all(&buffer[..count])?;
+        done += count as u64;
+        notify(done, (done * 100 / size) as u8)?;
+    }
+    Ok(())
+}
+
+impl<C: BootControl> UpgradeBackend for AbSlotBackend<C> {
+    fn download(
+        &mut self,
+        target: &str,
+        notify: &dyn Fn(PhaseProgress) -> Result<()>,
+    ) -> Result<()> {
+        if self.boot_control.get_env(DEADLINE_VARIABLE)?.is_some() {
+            return Err(Error::UpgradePending);
+        }
+        let image = PathBuf::from(target);
+        let size = std::fs::metadata(&image)?.len();
+        let checksum = read_checksum(&image)?;
+        let slot = self.boot_control.booted_slot().other();
+        self.installation = Some(Installation {
+            image,
+            size,
+            checksum,
+            slot,
+        });
+        notify(PhaseProgress::new(100).with_message(format!("Selected slot {}", slot)))
+    }
+
+    fn install(&mut self, notify: &dyn Fn(PhaseProgress) -> Result<()>) -> Result<()> {
+        let installation = self.installation()?;
+        let message = format!("Writing image to slot {}", installation.slot);
+        let mut image = File::open(&installation.image)?;
+        let mut device = OpenOptions::new()
+            .write(true)
+            .open(self.boot_control.slot_device(installation.slot))?;
+        let mut hasher = Sha256::new();
+        copy_with_progress(
+            &mut image,
+            &mut device,
+            installation.size,
+            &mut hasher,
+            &|bytes, position| {
+                notify(
+                    PhaseProgress::new(position)
+                        .with_message(message.as_str())
+                        .with_bytes(bytes),
+                )
+            },
+        )?;
+        device.sync_all()?;
+        notify(
+            PhaseProgress::new(100)
+                .with_message(message)
+                .with_bytes(installation.size),
+        )
+    }
+
+    fn verify(&mut self, notify: &dyn Fn(PhaseProgress) -> Result<()>) -> Result<()> {
+        let installation = self.installation()?;
+        let message = format!("Checking slot {}", installation.slot);
+        let mut device = File::open(self.boot_control.slot_device(installation.slot))?;
+        let mut hasher = Sha256::new();
+        copy_with_progress(
+            &mut device,
+            &mut std::io::sink(),
+            installation.size,
+            &mut hasher,
+            &|bytes, position| {
+                notify(
+                    PhaseProgress::new(position)
+                        .with_message(message.as_str())
+                        .with_bytes(bytes),
+                )
+            },
+        )?;
+        let checksum = hex::encode(hasher.finalize());
+        if checksum != installation.checksum {
+            return Err(Error::ChecksumMismatch(
+                installation.image.display().to_string(),
+            ));
+        }
+        notify(
+            PhaseProgress::new(100)
+                .with_message(message)
+                .with_bytes(installation.size),
+        )
+    }
+
+    fn finalize(&mut self, notify: &dyn Fn(PhaseProgress) -> Result<()>) -> Result<()> {
+        let installation = self.installation.take().ok_or(Error::NoUpgradeInProgress)?;
+        let deadline = SystemTime::now() + self.confirmation_window;
+        let deadline = deadline.duration_since(UNIX_EPOCH).unwrap_or_default();
+        self.boot_control.set_env(&[
+            (BOOT_SLOT_VARIABLE, Some(installation.slot.to_string())),
+            (
+                ROLLBACK_SLOT_VARIABLE,
+                Some(installation.slot.other().to_string()),
+            ),
+            (DEADLINE_VARIABLE, Some(deadline.as_secs().to_string())),
+        ])?;
+        notify(
+            PhaseProgress::new(100)
+                .with_message(format!("Slot {} will be booted", installation.slot)),
+        )
+    }
+
+    fn confirm(&mut self) -> Result<()> {
+        if self.boot_control.get_env(DEADLINE_VARIABLE)?.is_none() {
+            return Err(Error::NoUpgradePending);
+        }
+        if self.boot_slot()? != self.boot_control.booted_slot() {
+            return Err(Error::UpgradeNotBooted);
+        }
+        self.boot_control
+            .set_env(&[(ROLLBACK_SLOT_VARIABLE, None), (DEADLINE_VARIABLE, None)])
+    }
+
+    fn rollback(&mut self) -> Result<()> {
+        let slot = self
+            .boot_control
+            .get_env(ROLLBACK_SLOT_VARIABLE)?
+            .ok_or(Error::NoUpgradePending)?;
+        self.boot_control.set_env(&[
+            (BOOT_SLOT_VARIABLE, Some(slot)),
+            (ROLLBACK_SLOT_VARIABLE, None),
+            (DEADLINE_VARIABLE, None),
+        ])
+    }
+
+    fn confirmation_deadline(&self) -> Result<Option<SystemTime>> {
+        let deadline = self
+            .boot_control
+            .get_env(DEADLINE_VARIABLE)?
+            .and_then(|d| d.parse::<u64>().ok())
+            .map(|d| UNIX_EPOCH + Duration::from_secs(d));
+        Ok(deadline)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Engine;
+    use crate::upgrade::tests::{create_bundle, public_key, SECRET_KEY};
+    use crate::upgrade::{BundleVerifier, UpgradePhase};
+
+    const IMAGE: &[u8] = b"This is a new system image";
+
+    fn setup(name: &str) -> (PathBuf, FileBootControl) {
+        let dir = std::env::temp_dir().join(format!("artifex-ab-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let slots = [dir.join("slot-a"), dir.join("slot-b")];
+        for slot in &slots {
+            std::fs::write(slot, vec![0; 64]).unwrap();
+        }
+        let image = dir.join("system.img");
+        std::fs::write(&image, IMAGE).unwrap();
+        let checksum = hex::encode(Sha256::digest(IMAGE));
+        std::fs::write(
+            dir.join("system.img.sha256"),
+            format!("{}  system.img\n", checksum),
+        )
+        .unwrap();
+        let boot_control = FileBootControl::new(slots, dir.join("bootenv")).unwrap();
+        (image, boot_control)
+    }
+
+    /// Return a boot control as created after rebooting the machine.
+    fn reboot(boot_control: &FileBootControl) -> FileBootControl {
+        let slots = [Slot::A, Slot::B].map(|s| boot_control.slot_device(s).to_path_buf());
+        FileBootControl::new(slots, &boot_control.environment).unwrap()
+    }
+
+    #[test]
+    fn upgrade_and_confirm() {
+        let (image, boot_control) = setup("confirm");
+        let slot_b = boot_control.slot_device(Slot::B).to_path_buf();
+        let rebooted = reboot(&boot_control);
+        let backend = AbSlotBackend::new(boot_control, Duration::from_secs(60));
+        let mut engine = Engine::new(Box::new(backend));
+        engine.upgrade(image.to_str().unwrap(), |_| {}).unwrap();
+        let written = std::fs::read(&slot_b).unwrap();
+        assert_eq!(&written[..IMAGE.len()], IMAGE);
+        assert!(engine.confirmation_deadline().unwrap().is_some());
+        match engine.upgrade(image.to_str().unwrap(), |_| {}) {
+            Err(Error::UpgradeFailed(UpgradePhase::Download, e)) => {
+                assert!(matches!(*e, Error::UpgradePending))
+            }
+            _ => panic!("upgrade should be pending"),
+        }
+        assert!(matches!(
+            engine.confirm_upgrade(),
+            Err(Error::UpgradeNotBooted)
+        ));
+        assert!(engine.confirmation_deadline().unwrap().is_some());
+
+        let backend = AbSlotBackend::new(reboot(&rebooted), Duration::from_secs(60));
+        let mut engine = Engine::new(Box::new(backend));
+        engine.confirm_upgrade().unwrap();
+        assert!(engine.confirmation_deadline().unwrap().is_none());
+        assert!(matches!(
+            engine.rollback_upgrade(),
+            Err(Error::NoUpgradePending)
+        ));
+    }
+
+    #[test]
+    fn never_overwrite_booted_slot() {
+        let (image, boot_control) = setup("booted");
+        let target = image.to_str().unwrap();
+        let mut backend = AbSlotBackend::new(boot_control, Duration::from_secs(60));
+        backend.download(target, &|_| Ok(())).unwrap();
+        backend.finalize(&|_| Ok(())).unwrap();
+        backend.rollback().unwrap();
+        backend.download(target, &|_| Ok(())).unwrap();
+        backend.finalize(&|_| Ok(())).unwrap();
+        assert_eq!(backend.boot_slot().unwrap(), Slot::B);
+        assert_eq!(backend.boot_control.booted_slot(), Slot::A);
+
+        let mut backend = AbSlotBackend::new(reboot(&backend.boot_control), Duration::ZERO);
+        backend.confirm().unwrap();
+        backend.download(target, &|_| Ok(())).unwrap();
+        assert_eq!(backend.installation().unwrap().slot, Slot::A);
+    }
+
+    #[test]
+    fn upgrade_from_bundle() {
+        let (_, boot_control) = setup("bundle");
+        let slot_b = boot_control.slot_device(Slot::B).to_path_buf();
+        let bundle = create_bundle("ab-slot", IMAGE, &SECRET_KEY);
+        let backend = AbSlotBackend::new(boot_control, Duration::from_secs(60));
+        let mut engine = Engine::new(Box::new(backend));
+        engine.set_bundle_verifier(BundleVerifier::new(&[public_key()]).unwrap());
+        engine.upgrade(bundle.to_str().unwrap(), |_| {}).unwrap();
+        let written = std::fs::read(slot_b).unwrap();
+        assert_eq!(&written[..IMAGE.len()], IMAGE);
+    }
+
+    #[test]
+    fn upgrade_and_rollback() {
+        let (image, boot_control) = setup("rollback");
+        let mut backend = AbSlotBackend::new(boot_control, Duration::ZERO);
+        let target = image.to_str().unwrap();
+        backend.download(target, &|_| Ok(())).unwrap();
+        backend.install(&|_| Ok(())).unwrap();
+        backend.verify(&|_| Ok(())).unwrap();
+        backend.finalize(&|_| Ok(())).unwrap();
+        assert_eq!(backend.boot_slot().unwrap(), Slot::B);
+        assert!(backend.confirmation_deadline().unwrap().unwrap() <= SystemTime::now());
+        backend.rollback().unwrap();
+        assert_eq!(backend.boot_slot().unwrap(), Slot::A);
+    }
+
+    #[test]
+    fn reject_corrupted_image() {
+        let (image, boot_control) = setup("corrupted");
+        let mut checksum_path = image.clone().into_os_string();
+        checksum_path.push(".sha256");
+        std::fs::write(checksum_path, "0".repeat(64)).unwrap();
+        let mut backend = AbSlotBackend::new(boot_control, Duration::ZERO);
+        backend
+            .download(image.to_str().unwrap(), &|_| Ok(()))
+            .unwrap();
+        backend.install(&|_| Ok(())).unwrap();
+        let res = backend.verify(&|_| Ok(()));
+        assert!(matches!(res, Err(Error::ChecksumMismatch(_))));
+    }
+}
@@ -0,0 +1,307 @@
+//
+// Copyright (C) 2024 Eric Le Bihan <eric.le.bihan.dev@free.fr>
+//
+// SPDX-License-Identifier: MIT
+//
+
+use crate::error::{Error, Result};
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use tempfile::{Builder, TempDir};
+
+/// Name of the manifest file of a bundle.
+pub const MANIFEST_FILE: &str = "manifest.toml";
+/// Name of the file holding the signature of the manifest.
+pub const SIGNATURE_FILE: &str = "manifest.toml.sig";
+
+/// Description of the contents of a bundle.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BundleManifest {
+    /// Version of the system provided by the bundle.
+    pub version: String,
+    /// Identifier of the machines the bundle can be installed on.
+    pub compatible: String,
+    /// Files of the bundle.
+    pub files: Vec<BundleFile>,
+}
+
+/// File of a bundle, with its SHA-256 checksum.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BundleFile {
+    pub path: PathBuf,
+    pub sha256: String,
+}
+
+/// Upgrade bundle: a directory holding files described by a manifest, signed
+/// using Ed25519.
+///
+/// The first file of the manifest is the payload handed to the upgrade
+/// backend. It is copied to a private staging directory while its checksum is
+/// checked, so that the bundle can not be altered once verified. The copy is
+/// removed when the bundle is dropped.
+#[derive(Debug)]
+pub struct Bundle {
+    manifest: BundleManifest,
+    staging: TempDir,
+}
+
+impl Bundle {
+    /// Return the manifest of the bundle.
+    pub fn manifest(&self) -> &BundleManifest {
+        &self.manifest
+    }
+
+    /// Return the path to the verified copy of the payload of the bundle.
+    ///
+    /// Its checksum, taken from the signed manifest, is stored in a sibling
+    /// file with the `.sha256` extension appended, as expected by
+    /// `AbSlotBackend`.
+    pub fn payload(&self) -> PathBuf {
+        // A verified bundle holds at least one file.
+        self.staging.path().join(&self.manifest.files[0].path)
+    }
+}
+
+/// Copy `source` into `writer`, returning its SHA-256 checksum.
+fn copy_and_hash<W: Write>(source: &Path, writer: &mut W) -> Result<String> {
+    let mut file = File::open(source)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let count = file.read(&mut buffer)?;
+        if count == 0 {
+            break;
+        }
+        hasher.update(&buffer[..count]);
+        writer.write_all(&buffer[..count])?;
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Check that bundles are authentic before installing them.
+#[derive(Debug)]
+pub struct BundleVerifier {
+    trusted_keys: Vec<VerifyingKey>,
+    compatible: Option<String>,
+    staging_directory: Option<PathBuf>,
+}
+
+impl BundleVerifier {
+    /// Create a verifier accepting bundles signed by one of `trusted_keys`,
+    /// given as hexadecimal strings.
+    pub fn new<S: AsRef<str>>(trusted_keys: &[S]) -> Result<Self> {
+        let trusted_keys = trusted_keys
+            .iter()
+            .map(|k| {
+                let bytes =
+                    hex::decode(k.as_ref().trim()).map_err(|e| Error::InvalidKey(e.to_string()))?;
+                let bytes: [u8; 32] = bytes
+                    .try_into()
+                    .map_err(|_| Error::InvalidKey("wrong length".to_string()))?;
+                VerifyingKey::from_bytes(&bytes).map_err(|e| Error::InvalidKey(e.to_string()))
+            })
+            .collect::<Result<Vec<VerifyingKey>>>()?;
+        Ok(Self {
+            trusted_keys,
+            compatible: None,
+            staging_directory: None,
+        })
+    }
+
+    /// Only accept bundles targeting `compatible` machines.
+    pub fn with_compatible(mut self, compatible: &str) -> Self {
+        self.compatible = Some(compatible.to_string());
+        self
+    }
+
+    /// Copy the payload of the bundles into `directory`.
+    ///
+    /// By default, the payload is copied next to the bundle, so that it is
+    /// kept on the same filesystem instead of filling a RAM-backed temporary
+    /// directory.
+    pub fn with_staging_directory<P: AsRef<Path>>(mut self, directory: P) -> Self {
+        self.staging_directory = Some(directory.as_ref().to_path_buf());
+        self
+    }
+
+    /// Open the bundle at `path` and check its signature, compatibility and
+    /// the checksums of its files, keeping a copy of its payload.
+    pub fn verify<P: AsRef<Path>>(&self, path: P) -> Result<Bundle> {
+        let path = path.as_ref();
+        let data = std::fs::read(path.join(MANIFEST_FILE))?;
+        let signature = std::fs::read(path.join(SIGNATURE_FILE))?;
+        let signature = Signature::from_slice(&signature).map_err(|_| Error::InvalidSignature)?;
+        if !self
+            .trusted_keys
+            .iter()
+            .any(|k| k.verify(&data, &signature).is_ok())
+        {
+            return Err(Error::InvalidSignature);
+        }
+
+        let text = std::str::from_utf8(&data)?;
+        let manifest: BundleManifest =
+            toml::from_str(text).map_err(|e| Error::InvalidBundle(e.message().to_string()))?;
+        if let Some(compatible) = &self.compatible {
+            if &manifest.compatible != compatible {
+                return Err(Error::IncompatibleBundle(manifest.compatible));
+            }
+        }
+        if manifest.files.is_empty() {
+            return Err(Error::InvalidBundle("no files".to_string()));
+        }
+        let directory = match &self.staging_directory {
+            Some(directory) => directory.as_path(),
+            None => match path.parent() {
+                Some(parent) if parent != Path::new("") => parent,
+                _ => Path::new("."),
+            },
+        };
+        let staging = Builder::new()
+            .prefix(".artifex-bundle-")
+            .tempdir_in(directory)?;
+        for (index, file) in manifest.files.iter().enumerate() {
+            if file.path.is_absolute() || file.path.components().count() != 1 {
+                return Err(Error::InvalidBundle(format!(
+                    "invalid file path {}",
+                    file.path.display()
+                )));
+            }
+            let source = path.join(&file.path);
+            let checksum = if index == 0 {
+                let copy = staging.path().join(&file.path);
+                let mut writer = File::create(&copy)?;
+                let checksum = copy_and_hash(&source, &mut writer)?;
+                writer.sync_all()?;
+                let mut checksum_path = copy.into_os_string();
+                checksum_path.push(".sha256");
+                std::fs::write(checksum_path, format!("{}\n", checksum))?;
+                checksum
+            } else {
+                copy_and_hash(&source, &mut std::io::sink())?
+            };
+            if checksum != file.sha256.to_lowercase() {
+                return Err(Error::ChecksumMismatch(file.path.display().to_string()));
+            }
+        }
+        Ok(Bundle { manifest, staging })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use ed25519_dalek::{Signer, SigningKey};
+
+    pub(crate) const SECRET_KEY: [u8; 32] = [7; 32];
+
+    pub(crate) fn public_key() -> String {
+        hex::encode(
+            SigningKey::from_bytes(&SECRET_KEY)
+                .verifying_key()
+                .as_bytes(),
+        )
+    }
+
+    /// Create a bundle holding `payload`, signed with `secret_key`.
+    pub(crate) fn create_bundle(name: &str, payload: &[u8], secret_key: &[u8; 32]) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("artifex-bundle-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("system.img"), payload).unwrap();
+        let manifest = format!(
+            "version = \"2.0\"\ncompatible = \"acme-board\"\n\n[[files]]\npath = \"system.img\"\nsha256 = \"{}\"\n",
+            hex::encode(Sha256::digest(payload))
+        );
+        std::fs::write(dir.join(MANIFEST_FILE), &manifest).unwrap();
+        let signature = SigningKey::from_bytes(secret_key).sign(manifest.as_bytes());
+        std::fs::write(dir.join(SIGNATURE_FILE), signature.to_bytes()).unwrap();
+        dir
+    }
+
+    #[test]
+    fn verify_valid_bundle() {
+        let path = create_bundle("valid", b"payload", &SECRET_KEY);
+        let verifier = BundleVerifier::new(&[public_key()])
+            .unwrap()
+            .with_compatible("acme-board");
+        let bundle = verifier.verify(&path).unwrap();
+        assert_eq!(bundle.manifest().version, "2.0");
+        let payload = bundle.payload();
+        assert_eq!(payload.file_name().unwrap(), "system.img");
+        assert_ne!(payload, path.join("system.img"));
+        assert!(payload.starts_with(path.parent().unwrap()));
+        assert_eq!(std::fs::read(&payload).unwrap(), b"payload");
+        drop(bundle);
+        assert!(!payload.exists());
+    }
+
+    #[test]
+    fn use_staging_directory() {
+        let path = create_bundle("staging", b"payload", &SECRET_KEY);
+        let staging = path.with_extension("staging");
+        let _ = std::fs::remove_dir_all(&staging);
+        std::fs::create_dir_all(&staging).unwrap();
+        let verifier = BundleVerifier::new(&[public_key()])
+            .unwrap()
+            .with_staging_directory(&staging);
+        let bundle = verifier.verify(&path).unwrap();
+        assert!(bundle.payload().starts_with(&staging));
+        assert_eq!(std::fs::read(bundle.payload()).unwrap(), b"payload");
+    }
+
+    #[test]
+    fn keep_verified_payload() {
+        let path = create_bundle("swapped", b"payload", &SECRET_KEY);
+        let verifier = BundleVerifier::new(&[public_key()]).unwrap();
+        let bundle = verifier.verify(&path).unwrap();
+        std::fs::write(path.join("system.img"), b"malware").unwrap();
+        assert_eq!(std::fs::read(bundle.payload()).unwrap(), b"payload");
+        let mut checksum_path = bundle.payload().into_os_string();
+        checksum_path.push(".sha256");
+        assert_eq!(
+            std::fs::read_to_string(checksum_path).unwrap().trim(),
+            hex::encode(Sha256::digest(b"payload"))
+        );
+    }
+
+    #[test]
+    fn reject_untrusted_bundle() {
+        let path = create_bundle("untrusted", b"payload", &[9; 32]);
+        let verifier = BundleVerifier::new(&[public_key()]).unwrap();
+        assert!(matches!(
+            verifier.verify(path),
+            Err(Error::InvalidSignature)
+        ));
+    }
+
+    #[test]
+    fn reject_tampered_bundle() {
+        let path = create_bundle("tampered", b"payload", &SECRET_KEY);
+        std::fs::write(path.join("system.img"), b"malware").unwrap();
+        let verifier = BundleVerifier::new(&[public_key()]).unwrap();
+        assert!(matches!(
+            verifier.verify(path),
+            Err(Error::ChecksumMismatch(_))
+        ));
+    }
+
+    #[test]
+    fn reject_incompatible_bundle() {
+        let path = create_bundle("incompatible", b"payload", &SECRET_KEY);
+        let verifier = BundleVerifier::new(&[public_key()])
+            .unwrap()
+            .with_compatible("other-board");
+        assert!(matches!(
+            verifier.verify(path),
+            Err(Error::IncompatibleBundle(_))
+        ));
+    }
+}
@@ -0,0 +1,271 @@
+//
+// Copyright (C) 2024 Eric Le Bihan <eric.le.bihan.dev@free.fr>
+//
+// SPDX-License-Identifier: MIT
+//
+
+use super::{PhaseProgress, UpgradeBackend, UpgradePhase};
+use crate::error::{Error, Result};
+use rand::{rngs::StdRng, thread_rng, Rng, SeedableRng};
+use std::cell::Cell;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+/// Position of the upgrade, in percents, at which a failure is injected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FailurePoint {
+    /// Fail when reaching the given position.
+    At(u8),
+    /// Fail at a position picked at random for each upgrade.
+    Random,
+}
+
+impl FromStr for FailurePoint {
+    type Err = String;
+
+    /// Parse `random`, or a position such as `42` or `42%`.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if s == "random" {
+            return Ok(FailurePoint::Random);
+        }
+        s.strip_suffix('%')
+            .unwrap_or(s)
+            .parse::<u8>()
+            .ok()
+            .filter(|p| *p <= 100)
+            .map(FailurePoint::At)
+            .ok_or_else(|| format!("invalid failure point: {}", s))
+    }
+}
+
+/// Wrap an upgrade backend to make it misbehave on purpose, in order to test
+/// how clients handle errors.
+///
+/// Positions are given for the whole upgrade. Once the failure point is
+/// reached, the progression is no longer reported and the current phase is
+/// interrupted: the wrapped backend is notified with `Error::InjectedFault`,
+/// which it returns at once.
+pub struct FaultInjectionBackend {
+    inner: Box<dyn UpgradeBackend>,
+    failure: Option<FailurePoint>,
+    stall: Option<(u8, Duration)>,
+    rng: StdRng,
+    failure_position: Option<u8>,
+    pending_stall: Option<(u8, Duration)>,
+}
+
+impl FaultInjectionBackend {
+    /// Wrap `inner`, without injecting any fault yet.
+    pub fn new(inner: Box<dyn UpgradeBackend>) -> Self {
+        Self {
+            inner,
+            failure: None,
+            stall: None,
+            rng: StdRng::from_rng(thread_rng()).expect("failed to seed generator"),
+            failure_position: None,
+            pending_stall: None,
+        }
+    }
+
+    /// Make upgrades fail at `failure`.
+    pub fn with_failure(mut self, failure: FailurePoint) -> Self {
+        self.failure = Some(failure);
+        self
+    }
+
+    /// Make upgrades hang for `duration` when reaching `position`.
+    pub fn with_stall(mut self, position: u8, duration: Duration) -> Self {
+        self.stall = Some((position, duration));
+        self
+    }
+
+    /// Pick random failure points from `seed`, making them reproducible.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.rng = StdRng::seed_from_u64(seed);
+        self
+    }
+
+    fn run_phase<F>(
+        &mut self,
+        phase: UpgradePhase,
+        notify: &dyn Fn(PhaseProgress) -> Result<()>,
+        run: F,
+    ) -> Result<()>
+    where
+        F: FnOnce(&mut dyn UpgradeBackend, &dyn Fn(PhaseProgress) -> Result<()>) -> Result<()>,
+    {
+        let failure_position = self.failure_position;
+        let failed_at = Cell::new(None);
+        let stall = Cell::new(self.pending_stall);
+        let faulty_notify = |progress: PhaseProgress| -> Result<()> {
+            if let Some(position) = failed_at.get() {
+                return Err(Error::InjectedFault(position));
+            }
+            let position = phase.overall_position(progress.position);
+            if let Some((at, duration)) = stall.get() {
+                if position >= at {
+                    stall.set(None);
+                    std::thread::sleep(duration);
+                }
+            }
+            match failure_position {
+                Some(at) if position >= at => {
+                    failed_at.set(Some(position));
+                    Err(Error::InjectedFault(position))
+                }
+                _ => notify(progress),
+            }
+        };
+        let res = run(self.inner.as_mut(), &faulty_notify);
+        self.pending_stall = stall.get();
+        match failed_at.get() {
+            Some(position) => Err(Error::InjectedFault(position)),
+            None => res,
+        }
+    }
+}
+
+impl UpgradeBackend for FaultInjectionBackend {
+    fn download(
+        &mut self,
+        target: &str,
+        notify: &dyn Fn(PhaseProgress) -> Result<()>,
+    ) -> Result<()> {
+        self.failure_position = match self.failure {
+            Some(FailurePoint::At(position)) => Some(position),
+            Some(FailurePoint::Random) => Some(self.rng.gen_range(1..=100)),
+            None => None,
+        };
+        self.pending_stall = self.stall;
+        self.run_phase(UpgradePhase::Download, notify, |backend, notify| {
+            backend.download(target, notify)
+        })
+    }
+
+    fn install(&mut self, notify: &dyn Fn(PhaseProgress) -> Result<()>) -> Result<()> {
+        self.run_phase(UpgradePhase::Install, notify, |backend, notify| {
+            backend.install(notify)
+        })
+    }
+
+    fn verify(&mut self, notify: &dyn Fn(PhaseProgress) -> Result<()>) -> Result<()> {
+        self.run_phase(UpgradePhase::Verify, notify, |backend, notify| {
+            backend.verify(notify)
+        })
+    }
+
+    fn finalize(&mut self, notify: &dyn Fn(PhaseProgress) -> Result<()>) -> Result<()> {
+        self.run_phase(UpgradePhase::Finalize, notify, |backend, notify| {
+            backend.finalize(notify)
+        })
+    }
+
+    fn confirm(&mut self) -> Result<()> {
+        self.inner.confirm()
+    }
+
+    fn rollback(&mut self) -> Result<()> {
+        self.inner.rollback()
+    }
+
+    fn confirmation_deadline(&self) -> Result<Option<SystemTime>> {
+        self.inner.confirmation_deadline()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Engine;
+    use crate::upgrade::SimulationBackend;
+    use random_progression::VirtualClock;
+    use std::cell::RefCell;
+    use std::sync::atomic::{AtomicU8, Ordering};
+    use std::sync::Arc;
+
+    /// Backend recording the last position reached while installing.
+    struct CountingBackend(Arc<AtomicU8>);
+
+    impl UpgradeBackend for CountingBackend {
+        fn download(
+            &mut self,
+            _: &str,
+            notify: &dyn Fn(PhaseProgress) -> Result<()>,
+        ) -> Result<()> {
+            notify(PhaseProgress::new(100))
+        }
+
+        fn install(&mut self, notify: &dyn Fn(PhaseProgress) -> Result<()>) -> Result<()> {
+            for position in (10..=100).step_by(10) {
+                notify(PhaseProgress::new(position))?;
+                self.0.store(position, Ordering::SeqCst);
+            }
+            Ok(())
+        }
+
+        fn verify(&mut self, notify: &dyn Fn(PhaseProgress) -> Result<()>) -> Result<()> {
+            notify(PhaseProgress::new(100))
+        }
+
+        fn finalize(&mut self, notify: &dyn Fn(PhaseProgress) -> Result<()>) -> Result<()> {
+            notify(PhaseProgress::new(100))
+        }
+    }
+
+    fn simulation() -> Box<dyn UpgradeBackend> {
+        Box::new(
+            SimulationBackend::new()
+                .with_seed(1)
+                .with_clock(Arc::new(VirtualClock::new())),
+        )
+    }
+
+    #[test]
+    fn parse_failure_point() {
+        assert_eq!("42".parse(), Ok(FailurePoint::At(42)));
+        assert_eq!("42%".parse(), Ok(FailurePoint::At(42)));
+        assert_eq!("random".parse(), Ok(FailurePoint::Random));
+        assert!("142".parse::<FailurePoint>().is_err());
+    }
+
+    #[test]
+    fn fail_at_position() {
+        let backend = FaultInjectionBackend::new(simulation()).with_failure(FailurePoint::At(60));
+        let mut engine = Engine::new(Box::new(backend));
+        let positions = RefCell::new(vec![]);
+        let res = engine.upgrade("2.0", |p| positions.borrow_mut().push(p.position));
+        match res {
+            Err(Error::UpgradeFailed(UpgradePhase::Verify, e)) => {
+                assert!(matches!(*e, Error::InjectedFault(75)))
+            }
+            _ => panic!("upgrade should fail"),
+        }
+        assert!(positions.into_inner().iter().all(|p| *p < 60));
+    }
+
+    #[test]
+    fn interrupt_phase() {
+        let reached = Arc::new(AtomicU8::new(0));
+        let backend = FaultInjectionBackend::new(Box::new(CountingBackend(reached.clone())))
+            .with_failure(FailurePoint::At(40));
+        let mut engine = Engine::new(Box::new(backend));
+        let res = engine.upgrade("2.0", |_| {});
+        match res {
+            Err(Error::UpgradeFailed(UpgradePhase::Install, e)) => {
+                assert!(matches!(*e, Error::InjectedFault(40)))
+            }
+            _ => panic!("upgrade should fail"),
+        }
+        assert_eq!(reached.load(Ordering::SeqCst), 50);
+    }
+
+    #[test]
+    fn fail_at_random_position() {
+        let backend = FaultInjectionBackend::new(simulation())
+            .with_failure(FailurePoint::Random)
+            .with_seed(3);
+        let mut engine = Engine::new(Box::new(backend));
+        let res = engine.upgrade("2.0", |_| {});
+        assert!(matches!(res, Err(Error::UpgradeFailed(_, _))));
+    }
+}
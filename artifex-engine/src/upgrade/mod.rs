@@ -0,0 +1,125 @@
+//
+// Copyright (C) 2024 Eric Le Bihan <eric.le.bihan.dev@free.fr>
+//
+// SPDX-License-Identifier: MIT
+//
+
+mod ab_slot;
+mod bundle;
+mod fault;
+mod progress;
+mod script;
+mod simulation;
+
+pub use ab_slot::{AbSlotBackend, BootControl, FileBootControl, Slot};
+pub use bundle::{Bundle, BundleFile, BundleManifest, BundleVerifier};
+pub use fault::{FailurePoint, FaultInjectionBackend};
+pub(crate) use progress::EtaEstimator;
+pub use progress::{PhaseProgress, UpgradeProgress};
+pub use script::{ScriptBackend, ScriptStep};
+pub use simulation::SimulationBackend;
+
+use crate::error::{Error, Result};
+use std::{fmt::Display, str::FromStr, time::SystemTime};
+
+/// Phases of an upgrade, run in order by the engine.
+///
+/// The verification of the bundle of the new system is part of `Download`.
+/// `Verify` checks the system once installed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpgradePhase {
+    Download,
+    Install,
+    Verify,
+    Finalize,
+}
+
+impl UpgradePhase {
+    /// All the phases, in execution order.
+    pub const ALL: [UpgradePhase; 4] = [
+        UpgradePhase::Download,
+        UpgradePhase::Install,
+        UpgradePhase::Verify,
+        UpgradePhase::Finalize,
+    ];
+
+    /// Return the name of the phase.
+    pub fn name(&self) -> &'static str {
+        match self {
+            UpgradePhase::Download => "download",
+            UpgradePhase::Install => "install",
+            UpgradePhase::Verify => "verify",
+            UpgradePhase::Finalize => "finalize",
+        }
+    }
+
+    /// Convert a position in the phase to a position in the whole upgrade,
+    /// each phase accounting for the same share of it.
+    pub fn overall_position(&self, phase_position: u8) -> u8 {
+        let count = UpgradePhase::ALL.len() as u32;
+        let index = UpgradePhase::ALL
+            .iter()
+            .position(|p| p == self)
+            .unwrap_or(0) as u32;
+        ((index * 100 + phase_position as u32) / count) as u8
+    }
+}
+
+impl Display for UpgradePhase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for UpgradePhase {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        UpgradePhase::ALL
+            .into_iter()
+            .find(|p| p.name() == s)
+            .ok_or_else(|| Error::UnknownPhase(s.to_string()))
+    }
+}
+
+/// Interface of a system able to upgrade a machine.
+///
+/// Each phase reports its own progression via `notify`. An error returned by
+/// `notify` cancels the phase: the implementation must stop at once and
+/// return it. Errors specific to an implementation can be raised using
+/// `Error::Backend`.
+pub trait UpgradeBackend: Send {
+    /// Fetch the new system, `target` being a version or an image reference.
+    fn download(
+        &mut self,
+        target: &str,
+        notify: &dyn Fn(PhaseProgress) -> Result<()>,
+    ) -> Result<()>;
+    /// Install the new system.
+    fn install(&mut self, notify: &dyn Fn(PhaseProgress) -> Result<()>) -> Result<()>;
+    /// Check the new system has been correctly installed.
+    fn verify(&mut self, notify: &dyn Fn(PhaseProgress) -> Result<()>) -> Result<()>;
+    /// Make the new system the one to use.
+    fn finalize(&mut self, notify: &dyn Fn(PhaseProgress) -> Result<()>) -> Result<()>;
+
+    /// Make the last upgrade permanent.
+    fn confirm(&mut self) -> Result<()> {
+        Err(Error::Unsupported)
+    }
+
+    /// Revert the last upgrade, going back to the previous system.
+    fn rollback(&mut self) -> Result<()> {
+        Err(Error::Unsupported)
+    }
+
+    /// Return the time before which the last upgrade must be confirmed, if
+    /// any confirmation is expected.
+    fn confirmation_deadline(&self) -> Result<Option<SystemTime>> {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    pub(crate) use super::bundle::tests::{create_bundle, public_key, SECRET_KEY};
+}
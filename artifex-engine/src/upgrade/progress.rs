@@ -0,0 +1,99 @@
+//
+// Copyright (C) 2024 Eric Le Bihan <eric.le.bihan.dev@free.fr>
+//
+// SPDX-License-Identifier: MIT
+//
+
+use super::UpgradePhase;
+use std::time::{Duration, Instant};
+
+/// Progression reported by an upgrade backend during a phase.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PhaseProgress {
+    /// Progression of the phase, in percents.
+    pub position: u8,
+    /// Description of what is being done.
+    pub message: String,
+    /// Number of bytes processed during the phase.
+    pub bytes_processed: u64,
+}
+
+impl PhaseProgress {
+    /// Create a new progression report for a phase.
+    pub fn new(position: u8) -> Self {
+        Self {
+            position,
+            ..Default::default()
+        }
+    }
+
+    /// Describe what is being done.
+    pub fn with_message<S: Into<String>>(mut self, message: S) -> Self {
+        self.message = message.into();
+        self
+    }
+
+    /// Set the number of bytes processed.
+    pub fn with_bytes(mut self, bytes_processed: u64) -> Self {
+        self.bytes_processed = bytes_processed;
+        self
+    }
+}
+
+/// Progression of a whole upgrade.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UpgradeProgress {
+    /// Phase being run.
+    pub phase: UpgradePhase,
+    /// Progression of the phase, in percents.
+    pub phase_position: u8,
+    /// Progression of the upgrade, in percents.
+    pub position: u8,
+    /// Description of what is being done.
+    pub message: String,
+    /// Number of bytes processed during the phase.
+    pub bytes_processed: u64,
+    /// Estimated time remaining, if known.
+    pub eta: Option<Duration>,
+}
+
+/// Estimate the time remaining from the observed progression rate.
+#[derive(Debug)]
+pub(crate) struct EtaEstimator {
+    start: Instant,
+}
+
+impl EtaEstimator {
+    pub(crate) fn new() -> Self {
+        Self {
+            start: Instant::now(),
+        }
+    }
+
+    /// Return the time remaining to reach 100%, from `position`.
+    pub(crate) fn estimate(&self, position: u8) -> Option<Duration> {
+        estimate(self.start.elapsed(), position)
+    }
+}
+
+fn estimate(elapsed: Duration, position: u8) -> Option<Duration> {
+    match position {
+        0 => None,
+        100.. => Some(Duration::ZERO),
+        _ => Some(elapsed.mul_f64((100 - position) as f64 / position as f64)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimate_time_remaining() {
+        let elapsed = Duration::from_secs(10);
+        assert_eq!(estimate(elapsed, 0), None);
+        assert_eq!(estimate(elapsed, 25), Some(Duration::from_secs(30)));
+        assert_eq!(estimate(elapsed, 50), Some(Duration::from_secs(10)));
+        assert_eq!(estimate(elapsed, 100), Some(Duration::ZERO));
+    }
+}
@@ -0,0 +1,183 @@
+//
+// Copyright (C) 2024 Eric Le Bihan <eric.le.bihan.dev@free.fr>
+//
+// SPDX-License-Identifier: MIT
+//
+
+use super::{PhaseProgress, UpgradeBackend, UpgradePhase};
+use crate::error::{Error, Result};
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+/// Name of the environment variable holding the upgrade target.
+const TARGET_VARIABLE: &str = "ARTIFEX_UPGRADE_TARGET";
+
+/// A local script run during an upgrade phase.
+#[derive(Clone, Debug)]
+pub struct ScriptStep {
+    pub phase: UpgradePhase,
+    pub program: PathBuf,
+    pub args: Vec<String>,
+}
+
+/// Upgrade a machine by running a sequence of local scripts.
+///
+/// The upgrade target is passed to the scripts via the
+/// `ARTIFEX_UPGRADE_TARGET` environment variable. A script reports its
+/// progression by printing lines such as `42%` or `PROGRESS 42` on its
+/// standard output. Any other line is reported as a progression message.
+#[derive(Debug, Default)]
+pub struct ScriptBackend {
+    steps: Vec<ScriptStep>,
+    target: String,
+}
+
+impl ScriptBackend {
+    /// Create a new backend running `steps`, in order, in their phases.
+    pub fn new(steps: Vec<ScriptStep>) -> Self {
+        Self {
+            steps,
+            target: String::new(),
+        }
+    }
+
+    fn run_phase(
+        &self,
+        phase: UpgradePhase,
+        notify: &dyn Fn(PhaseProgress) -> Result<()>,
+    ) -> Result<()> {
+        let steps: Vec<&ScriptStep> = self.steps.iter().filter(|s| s.phase == phase).collect();
+        let count = steps.len() as u32;
+        for (index, step) in steps.into_iter().enumerate() {
+            let offset = index as u32 * 100;
+            self.run_step(step, &|progress: PhaseProgress| {
+                let position = ((offset + progress.position as u32) / count) as u8;
+                notify(PhaseProgress {
+                    position,
+                    ..progress
+                })
+            })?;
+        }
+        notify(PhaseProgress::new(100))
+    }
+
+    fn run_step(
+        &self,
+        step: &ScriptStep,
+        notify: &dyn Fn(PhaseProgress) -> Result<()>,
+    ) -> Result<()> {
+        let mut child = Command::new(&step.program)
+            .args(&step.args)
+            .env(TARGET_VARIABLE, &self.target)
+            .stdout(Stdio::piped())
+            .spawn()?;
+        if let Some(stdout) = child.stdout.take() {
+            let mut progress = PhaseProgress::new(0);
+            for line in BufReader::new(stdout).lines() {
+                let line = line?;
+                match parse_progress(&line) {
+                    Some(position) => progress.position = position,
+                    None if !line.trim().is_empty() => progress.message = line.trim().to_string(),
+                    None => continue,
+                }
+                if let Err(e) = notify(progress.clone()) {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return Err(e);
+                }
+            }
+        }
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(Error::ScriptFailed(
+                step.program.display().to_string(),
+                status.code().unwrap_or(-1),
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn parse_progress(line: &str) -> Option<u8> {
+    let line = line.trim();
+    let value = match line.strip_prefix("PROGRESS") {
+        Some(value) => value,
+        None => line.strip_suffix('%')?,
+    };
+    value.trim().parse::<u8>().ok().filter(|v| *v <= 100)
+}
+
+impl UpgradeBackend for ScriptBackend {
+    fn download(
+        &mut self,
+        target: &str,
+        notify: &dyn Fn(PhaseProgress) -> Result<()>,
+    ) -> Result<()> {
+        self.target = target.to_string();
+        self.run_phase(UpgradePhase::Download, notify)
+    }
+
+    fn install(&mut self, notify: &dyn Fn(PhaseProgress) -> Result<()>) -> Result<()> {
+        self.run_phase(UpgradePhase::Install, notify)
+    }
+
+    fn verify(&mut self, notify: &dyn Fn(PhaseProgress) -> Result<()>) -> Result<()> {
+        self.run_phase(UpgradePhase::Verify, notify)
+    }
+
+    fn finalize(&mut self, notify: &dyn Fn(PhaseProgress) -> Result<()>) -> Result<()> {
+        self.run_phase(UpgradePhase::Finalize, notify)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    fn shell_step(phase: UpgradePhase, script: &str) -> ScriptStep {
+        ScriptStep {
+            phase,
+            program: PathBuf::from("/bin/sh"),
+            args: vec!["-c".to_string(), script.to_string()],
+        }
+    }
+
+    #[test]
+    fn parse_progress_lines() {
+        assert_eq!(parse_progress("42%"), Some(42));
+        assert_eq!(parse_progress("PROGRESS 7"), Some(7));
+        assert_eq!(parse_progress("PROGRESS 250"), None);
+        assert_eq!(parse_progress("downloading..."), None);
+    }
+
+    #[test]
+    fn run_scripts() {
+        let mut backend = ScriptBackend::new(vec![
+            shell_step(
+                UpgradePhase::Install,
+                "test \"$ARTIFEX_UPGRADE_TARGET\" = 2.0 && echo 50%",
+            ),
+            shell_step(UpgradePhase::Install, "echo Installing; echo PROGRESS 50"),
+        ]);
+        let reports = RefCell::new(vec![]);
+        let notify = |p: PhaseProgress| {
+            reports.borrow_mut().push(p);
+            Ok(())
+        };
+        backend.download("2.0", &notify).unwrap();
+        backend.install(&notify).unwrap();
+        let reports = reports.borrow();
+        let positions: Vec<u8> = reports.iter().map(|p| p.position).collect();
+        assert_eq!(positions, vec![100, 25, 50, 75, 100]);
+        assert_eq!(reports[3].message, "Installing");
+    }
+
+    #[test]
+    fn fail_on_script_error() {
+        let mut backend = ScriptBackend::new(vec![shell_step(UpgradePhase::Verify, "exit 3")]);
+        let res = backend.verify(&|_| Ok(()));
+        assert!(matches!(res, Err(Error::ScriptFailed(_, 3))));
+    }
+}
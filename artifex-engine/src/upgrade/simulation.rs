@@ -0,0 +1,147 @@
+//
+// Copyright (C) 2024 Eric Le Bihan <eric.le.bihan.dev@free.fr>
+//
+// SPDX-License-Identifier: MIT
+//
+
+use super::{PhaseProgress, UpgradeBackend};
+use crate::error::Result;
+use rand::{rngs::StdRng, thread_rng, Rng, SeedableRng};
+use random_progression::{Clock, Curve, RandomProgression, SystemClock, TimedProgression};
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Pretend to upgrade a machine, sleeping through a random progression.
+///
+/// By default, the same delay, picked at random, is used between each step
+/// of the progression. A curve can be set instead, for a more believable
+/// progression.
+pub struct SimulationBackend {
+    delays: Range<Duration>,
+    curve: Option<(Curve, Duration)>,
+    rng: StdRng,
+    clock: Arc<dyn Clock>,
+}
+
+impl SimulationBackend {
+    /// Create a backend waiting from 0.5 s to 2 s between each step.
+    pub fn new() -> Self {
+        Self {
+            delays: Duration::from_millis(500)..Duration::from_millis(2000),
+            curve: None,
+            rng: StdRng::from_rng(thread_rng()).expect("failed to seed generator"),
+            clock: Arc::new(SystemClock::new()),
+        }
+    }
+
+    /// Generate the progressions from `seed`, making upgrades reproducible.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.rng = StdRng::seed_from_u64(seed);
+        self
+    }
+
+    /// Set the range of the delay between each step.
+    pub fn with_delays(mut self, delays: Range<Duration>) -> Self {
+        self.delays = delays;
+        self
+    }
+
+    /// Make the installation follow `curve`, lasting `duration`.
+    pub fn with_curve(mut self, curve: Curve, duration: Duration) -> Self {
+        self.curve = Some((curve, duration));
+        self
+    }
+
+    /// Use `clock` to wait between steps, such as a `VirtualClock` to avoid
+    /// waiting at all.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+}
+
+impl Default for SimulationBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Debug for SimulationBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SimulationBackend")
+            .field("delays", &self.delays)
+            .field("curve", &self.curve)
+            .finish()
+    }
+}
+
+impl UpgradeBackend for SimulationBackend {
+    fn download(
+        &mut self,
+        target: &str,
+        notify: &dyn Fn(PhaseProgress) -> Result<()>,
+    ) -> Result<()> {
+        notify(PhaseProgress::new(100).with_message(format!("Pretending to fetch {}", target)))
+    }
+
+    fn install(&mut self, notify: &dyn Fn(PhaseProgress) -> Result<()>) -> Result<()> {
+        if let Some((curve, duration)) = &self.curve {
+            let progression = TimedProgression::builder(*duration)
+                .curve(curve.clone())
+                .clock(self.clock.clone())
+                .build_with_rng(&mut self.rng);
+            for (_, position) in progression {
+                notify(PhaseProgress::new(position).with_message("Pretending to install system"))?;
+            }
+            return Ok(());
+        }
+        let delay = if self.delays.is_empty() {
+            self.delays.start
+        } else {
+            self.rng.gen_range(self.delays.clone())
+        };
+        let progression = RandomProgression::builder()
+            .delays(delay..delay)
+            .clock(self.clock.clone())
+            .build_with_rng(&mut self.rng);
+        for position in progression {
+            notify(PhaseProgress::new(position).with_message("Pretending to install system"))?;
+        }
+        Ok(())
+    }
+
+    fn verify(&mut self, notify: &dyn Fn(PhaseProgress) -> Result<()>) -> Result<()> {
+        notify(PhaseProgress::new(100).with_message("Pretending to verify system"))
+    }
+
+    fn finalize(&mut self, notify: &dyn Fn(PhaseProgress) -> Result<()>) -> Result<()> {
+        notify(PhaseProgress::new(100).with_message("Pretending to switch to new system"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use random_progression::VirtualClock;
+    use std::cell::RefCell;
+
+    #[test]
+    fn follow_curve() {
+        let clock = VirtualClock::new();
+        let mut backend = SimulationBackend::new()
+            .with_curve(Curve::Download { jitter: 0.2 }, Duration::from_secs(10))
+            .with_clock(Arc::new(clock.clone()));
+        let positions = RefCell::new(vec![]);
+        backend
+            .install(&|p: PhaseProgress| {
+                positions.borrow_mut().push(p.position);
+                Ok(())
+            })
+            .unwrap();
+        let positions = positions.into_inner();
+        assert_eq!(positions.len(), 40);
+        assert_eq!(positions.last(), Some(&100));
+        assert_eq!(clock.elapsed(), Duration::from_secs(10));
+    }
+}
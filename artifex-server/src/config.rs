@@ -0,0 +1,268 @@
+//
+// Copyright (C) 2024 Eric Le Bihan <eric.le.bihan.dev@free.fr>
+//
+// SPDX-License-Identifier: MIT
+//
+
+use crate::fault::FaultConfig;
+use anyhow::{anyhow, Context, Result};
+use artifex_engine::{
+    AbSlotBackend, BundleVerifier, Engine, FileBootControl, ScriptBackend, ScriptStep,
+    SimulationBackend, UpgradeBackend, UpgradePhase,
+};
+use random_progression::Curve;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+/// Configuration of the server, read from a TOML file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Directory where the state of the server is saved.
+    pub state_directory: Option<PathBuf>,
+    pub upgrade: UpgradeConfig,
+    pub bundle: Option<BundleConfig>,
+    /// Faults to inject, for test environments only.
+    pub faults: Option<FaultConfig>,
+}
+
+/// Settings of the verification of upgrade bundles.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BundleConfig {
+    /// Ed25519 public keys allowed to sign bundles, in hexadecimal.
+    pub trusted_keys: Vec<String>,
+    /// Identifier bundles must be compatible with.
+    pub compatible: Option<String>,
+    /// Directory where the payload of a bundle is copied while verified.
+    /// Defaults to the directory holding the bundle.
+    pub staging_directory: Option<PathBuf>,
+}
+
+/// Selection and settings of the upgrade backend.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "backend", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum UpgradeConfig {
+    Simulation {
+        /// Shape of the progression: `linear`, `ease-in`, `ease-out`,
+        /// `ease-in-out` or `download`. Random steps are used if not set.
+        curve: Option<String>,
+        #[serde(default = "default_simulation_duration")]
+        duration: String,
+    },
+    Script {
+        steps: Vec<ScriptStepConfig>,
+    },
+    AbSlot {
+        slots: [PathBuf; 2],
+        environment: PathBuf,
+        #[serde(default = "default_confirmation_window")]
+        confirmation_window: String,
+    },
+}
+
+impl Default for UpgradeConfig {
+    fn default() -> Self {
+        UpgradeConfig::Simulation {
+            curve: None,
+            duration: default_simulation_duration(),
+        }
+    }
+}
+
+fn default_simulation_duration() -> String {
+    "20s".to_string()
+}
+
+fn default_confirmation_window() -> String {
+    "10m".to_string()
+}
+
+/// Script run by the `script` upgrade backend.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScriptStepConfig {
+    pub phase: String,
+    pub program: PathBuf,
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
+impl Config {
+    /// Read the configuration from a file.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let text = std::fs::read_to_string(path).with_context(|| "failed to read file")?;
+        toml::from_str(&text).with_context(|| "failed to parse configuration")
+    }
+
+    /// Build an engine matching the configuration.
+    pub fn build_engine(&self) -> Result<Engine> {
+        let mut backend = self.upgrade.build_backend()?;
+        if let Some(faults) = &self.faults {
+            backend = faults.wrap_backend(backend)?;
+        }
+        let mut engine = Engine::new(backend);
+        if let Some(bundle) = &self.bundle {
+            let mut verifier =
+                BundleVerifier::new(&bundle.trusted_keys).with_context(|| "invalid trusted key")?;
+            if let Some(compatible) = &bundle.compatible {
+                verifier = verifier.with_compatible(compatible);
+            }
+            if let Some(directory) = &bundle.staging_directory {
+                verifier = verifier.with_staging_directory(directory);
+            }
+            engine.set_bundle_verifier(verifier);
+        }
+        Ok(engine)
+    }
+}
+
+impl UpgradeConfig {
+    fn build_backend(&self) -> Result<Box<dyn UpgradeBackend>> {
+        let backend: Box<dyn UpgradeBackend> = match self {
+            UpgradeConfig::Simulation { curve, duration } => {
+                let mut backend = SimulationBackend::new();
+                if let Some(curve) = curve {
+                    let curve = curve.parse::<Curve>().map_err(|e| anyhow!(e))?;
+                    let duration = humantime::parse_duration(duration)
+                        .with_context(|| format!("invalid duration {}", duration))?;
+                    backend = backend.with_curve(curve, duration);
+                }
+                Box::new(backend)
+            }
+            UpgradeConfig::Script { steps } => {
+                let steps = steps
+                    .iter()
+                    .map(|s| {
+                        Ok(ScriptStep {
+                            phase: s.phase.parse::<UpgradePhase>()?,
+                            program: s.program.clone(),
+                            args: s.args.clone(),
+                        })
+                    })
+                    .collect::<Result<Vec<ScriptStep>>>()?;
+                Box::new(ScriptBackend::new(steps))
+            }
+            UpgradeConfig::AbSlot {
+                slots,
+                environment,
+                confirmation_window,
+            } => {
+                let window = humantime::parse_duration(confirmation_window)
+                    .with_context(|| "invalid confirmation window")?;
+                let boot_control = FileBootControl::new(slots.clone(), environment)
+                    .with_context(|| "invalid bootloader environment")?;
+                Box::new(AbSlotBackend::new(boot_control, window))
+            }
+        };
+        Ok(backend)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG_SCRIPT: &str = r#"
+[upgrade]
+backend = "script"
+
+[[upgrade.steps]]
+phase = "download"
+program = "/usr/libexec/artifex/download"
+
+[[upgrade.steps]]
+phase = "install"
+program = "/usr/libexec/artifex/install"
+args = ["--verbose"]
+"#;
+
+    #[test]
+    fn parse_script_config() {
+        let config: Config = toml::from_str(CONFIG_SCRIPT).unwrap();
+        match &config.upgrade {
+            UpgradeConfig::Script { steps } => {
+                assert_eq!(steps.len(), 2);
+                assert_eq!(steps[1].args, vec!["--verbose"]);
+            }
+            _ => panic!("Unexpected backend"),
+        }
+        assert!(config.build_engine().is_ok());
+    }
+
+    #[test]
+    fn reject_unknown_phase() {
+        let config: Config = toml::from_str(
+            r#"
+[upgrade]
+backend = "script"
+steps = [{ phase = "reboot", program = "/sbin/reboot" }]
+"#,
+        )
+        .unwrap();
+        assert!(config.build_engine().is_err());
+    }
+
+    #[test]
+    fn parse_ab_slot_config() {
+        let config: Config = toml::from_str(
+            r#"
+[upgrade]
+backend = "ab-slot"
+slots = ["/dev/mmcblk0p2", "/dev/mmcblk0p3"]
+environment = "/var/lib/artifex/bootenv"
+confirmation_window = "5m"
+"#,
+        )
+        .unwrap();
+        assert!(matches!(config.upgrade, UpgradeConfig::AbSlot { .. }));
+        assert!(config.build_engine().is_ok());
+    }
+
+    #[test]
+    fn parse_bundle_config() {
+        let config: Config = toml::from_str(
+            r#"
+[bundle]
+trusted_keys = ["ea4a6c63e29c520abef5507b132ec5f9954776aebebe7b92421eea691446d22c"]
+compatible = "acme-board"
+staging_directory = "/var/lib/artifex/staging"
+"#,
+        )
+        .unwrap();
+        assert!(config.bundle.is_some());
+        assert!(config.build_engine().is_ok());
+    }
+
+    #[test]
+    fn default_to_simulation() {
+        let config: Config = toml::from_str("").unwrap();
+        assert!(matches!(
+            config.upgrade,
+            UpgradeConfig::Simulation { curve: None, .. }
+        ));
+    }
+
+    #[test]
+    fn parse_simulation_curve() {
+        let config: Config = toml::from_str(
+            r#"
+            [upgrade]
+            backend = "simulation"
+            curve = "download"
+            duration = "30s"
+            "#,
+        )
+        .unwrap();
+        assert!(config.build_engine().is_ok());
+        let config: Config = toml::from_str(
+            r#"
+            [upgrade]
+            backend = "simulation"
+            curve = "bumpy"
+            "#,
+        )
+        .unwrap();
+        assert!(config.build_engine().is_err());
+    }
+}
@@ -0,0 +1,267 @@
+//
+// Copyright (C) 2024 Eric Le Bihan <eric.le.bihan.dev@free.fr>
+//
+// SPDX-License-Identifier: MIT
+//
+
+use anyhow::{anyhow, Context, Result};
+use artifex_engine::{FailurePoint, FaultInjectionBackend, UpgradeBackend};
+use artifex_rpc::UpgradeReply;
+use futures::Stream;
+use rand::{rngs::StdRng, thread_rng, Rng, SeedableRng};
+use serde::Deserialize;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+use tonic::{Code, Status};
+
+type UpgradeStream = Pin<Box<dyn Stream<Item = std::result::Result<UpgradeReply, Status>> + Send>>;
+
+/// Settings of the faults injected by the server, for test environments.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FaultConfig {
+    /// Position at which upgrades fail: `random` or a percentage.
+    pub fail_upgrade_at: Option<String>,
+    /// Position at which upgrades hang.
+    pub stall_upgrade_at: Option<u8>,
+    /// How long upgrades hang.
+    pub stall_duration: Option<String>,
+    /// Position at which the upgrade streams are cut.
+    pub drop_upgrade_at: Option<u8>,
+    /// Delay added to `Inspect` and `Execute` requests.
+    pub latency: Option<String>,
+    /// Seed of the random generator, to make faults reproducible.
+    pub seed: Option<u64>,
+    /// Errors returned instead of handling requests.
+    pub errors: Vec<ErrorFaultConfig>,
+}
+
+/// Error returned at random by the server.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ErrorFaultConfig {
+    /// gRPC status code, such as `unavailable` or `deadline_exceeded`.
+    pub code: String,
+    /// Probability of returning the error, from 0 to 1.
+    pub probability: f64,
+    /// Names of the methods affected, such as `Inspect`. All methods are
+    /// affected if empty.
+    #[serde(default)]
+    pub methods: Vec<String>,
+    #[serde(default)]
+    pub message: Option<String>,
+}
+
+fn default_stall_duration() -> String {
+    "1m".to_string()
+}
+
+/// Parse the name of a gRPC status code, ignoring case and underscores.
+fn parse_code(name: &str) -> Result<Code> {
+    let name = name.replace('_', "").to_lowercase();
+    (0..=16)
+        .map(Code::from_i32)
+        .find(|code| format!("{:?}", code).to_lowercase() == name)
+        .ok_or_else(|| anyhow!("unknown status code {}", name))
+}
+
+impl FaultConfig {
+    /// Wrap `backend` to inject the upgrade failures and stalls, if any.
+    pub fn wrap_backend(
+        &self,
+        backend: Box<dyn UpgradeBackend>,
+    ) -> Result<Box<dyn UpgradeBackend>> {
+        if self.fail_upgrade_at.is_none() && self.stall_upgrade_at.is_none() {
+            return Ok(backend);
+        }
+        let mut backend = FaultInjectionBackend::new(backend);
+        if let Some(failure) = &self.fail_upgrade_at {
+            let failure = failure.parse::<FailurePoint>().map_err(|e| anyhow!(e))?;
+            backend = backend.with_failure(failure);
+        }
+        if let Some(position) = self.stall_upgrade_at {
+            let duration = self
+                .stall_duration
+                .clone()
+                .unwrap_or_else(default_stall_duration);
+            let duration = humantime::parse_duration(&duration)
+                .with_context(|| format!("invalid stall duration {}", duration))?;
+            backend = backend.with_stall(position, duration);
+        }
+        if let Some(seed) = self.seed {
+            backend = backend.with_seed(seed);
+        }
+        Ok(Box::new(backend))
+    }
+
+    /// Build the injector of the faults affecting requests.
+    pub fn build_injector(&self) -> Result<FaultInjector> {
+        let latency = self
+            .latency
+            .as_ref()
+            .map(|l| humantime::parse_duration(l).with_context(|| format!("invalid latency {}", l)))
+            .transpose()?;
+        let errors = self
+            .errors
+            .iter()
+            .map(|e| {
+                Ok(ErrorFault {
+                    code: parse_code(&e.code)?,
+                    probability: e.probability,
+                    methods: e.methods.iter().map(|m| m.to_lowercase()).collect(),
+                    message: e
+                        .message
+                        .clone()
+                        .unwrap_or_else(|| "injected fault".to_string()),
+                })
+            })
+            .collect::<Result<Vec<ErrorFault>>>()?;
+        let rng = match self.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_rng(thread_rng())?,
+        };
+        Ok(FaultInjector {
+            latency,
+            drop_upgrade_at: self.drop_upgrade_at,
+            errors,
+            rng: Mutex::new(rng),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct ErrorFault {
+    code: Code,
+    probability: f64,
+    methods: Vec<String>,
+    message: String,
+}
+
+/// Make the handling of requests misbehave on purpose.
+///
+/// The default injector injects no fault.
+#[derive(Debug)]
+pub struct FaultInjector {
+    latency: Option<Duration>,
+    drop_upgrade_at: Option<u8>,
+    errors: Vec<ErrorFault>,
+    rng: Mutex<StdRng>,
+}
+
+impl Default for FaultInjector {
+    fn default() -> Self {
+        Self {
+            latency: None,
+            drop_upgrade_at: None,
+            errors: vec![],
+            rng: Mutex::new(StdRng::seed_from_u64(0)),
+        }
+    }
+}
+
+impl FaultInjector {
+    /// Return the error to send instead of handling a request to `method`,
+    /// if any.
+    pub fn error(&self, method: &str) -> Option<Status> {
+        let method = method.to_lowercase();
+        let mut rng = self.rng.lock().unwrap();
+        self.errors
+            .iter()
+            .filter(|e| e.methods.is_empty() || e.methods.contains(&method))
+            .find(|e| rng.gen_bool(e.probability.clamp(0.0, 1.0)))
+            .map(|e| Status::new(e.code, e.message.clone()))
+    }
+
+    /// Wait for the latency added to requests.
+    pub async fn delay(&self) {
+        if let Some(latency) = self.latency {
+            tokio::time::sleep(latency).await;
+        }
+    }
+
+    /// Cut `stream` once the upgrade reaches the drop position, as if the
+    /// connection was lost: an `UNAVAILABLE` error is sent instead of the
+    /// remaining replies.
+    pub fn wrap_upgrade_stream(&self, stream: UpgradeStream) -> UpgradeStream {
+        let position = match self.drop_upgrade_at {
+            Some(position) => position as i32,
+            None => return stream,
+        };
+        let (tx, rx) = mpsc::channel(100);
+        let mut stream = stream;
+        tokio::spawn(async move {
+            while let Some(item) = stream.next().await {
+                let item = match item {
+                    Ok(reply) if reply.position >= position => {
+                        Err(Status::unavailable("connection dropped"))
+                    }
+                    item => item,
+                };
+                let last = item.is_err();
+                if tx.send(item).await.is_err() || last {
+                    break;
+                }
+            }
+        });
+        Box::pin(ReceiverStream::new(rx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_fault_config() {
+        let text = r#"
+            fail_upgrade_at = "random"
+            latency = "2s"
+            seed = 42
+
+            [[errors]]
+            code = "deadline_exceeded"
+            probability = 1.0
+            methods = ["Inspect"]
+        "#;
+        let config: FaultConfig = toml::from_str(text).unwrap();
+        let injector = config.build_injector().unwrap();
+        assert_eq!(injector.latency, Some(Duration::from_secs(2)));
+        let status = injector.error("Inspect").unwrap();
+        assert_eq!(status.code(), Code::DeadlineExceeded);
+        assert!(injector.error("Execute").is_none());
+    }
+
+    #[test]
+    fn reject_unknown_code() {
+        let config = FaultConfig {
+            errors: vec![ErrorFaultConfig {
+                code: "oops".to_string(),
+                probability: 0.5,
+                methods: vec![],
+                message: None,
+            }],
+            ..Default::default()
+        };
+        assert!(config.build_injector().is_err());
+    }
+
+    #[tokio::test]
+    async fn drop_upgrade_stream() {
+        let config = FaultConfig {
+            drop_upgrade_at: Some(50),
+            ..Default::default()
+        };
+        let injector = config.build_injector().unwrap();
+        let replies = [10, 60, 100].map(|position| UpgradeReply {
+            position,
+            ..Default::default()
+        });
+        let stream = Box::pin(tokio_stream::iter(replies).map(Ok)) as UpgradeStream;
+        let items: Vec<_> = injector.wrap_upgrade_stream(stream).collect().await;
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[1].as_ref().unwrap_err().code(), Code::Unavailable);
+    }
+}
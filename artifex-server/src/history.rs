@@ -0,0 +1,215 @@
+//
+// Copyright (C) 2024 Eric Le Bihan <eric.le.bihan.dev@free.fr>
+//
+// SPDX-License-Identifier: MIT
+//
+
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Name of the file holding the history, in the state directory.
+const HISTORY_FILE: &str = "upgrades.toml";
+
+/// Maximum number of upgrades kept in the history.
+const HISTORY_SIZE: usize = 100;
+
+/// Outcome of an upgrade.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UpgradeOutcome {
+    Running,
+    Success,
+    Failure,
+    /// The server stopped while the upgrade was running.
+    Interrupted,
+}
+
+/// State of an upgrade session.
+///
+/// Timestamps are given in seconds since the Unix epoch.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct UpgradeRecord {
+    pub session_id: String,
+    pub target: String,
+    pub phase: String,
+    pub position: i32,
+    pub outcome: UpgradeOutcome,
+    pub reason: String,
+    pub started_at: u64,
+    pub updated_at: u64,
+    pub finished_at: Option<u64>,
+}
+
+impl UpgradeRecord {
+    /// Create the record of an upgrade to `target` starting now.
+    pub fn new(session_id: &str, target: &str) -> Self {
+        let now = timestamp();
+        Self {
+            session_id: session_id.to_string(),
+            target: target.to_string(),
+            phase: String::new(),
+            position: 0,
+            outcome: UpgradeOutcome::Running,
+            reason: String::new(),
+            started_at: now,
+            updated_at: now,
+            finished_at: None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct HistoryFile {
+    #[serde(default)]
+    upgrades: Vec<UpgradeRecord>,
+}
+
+/// Record of the upgrades performed on the machine, oldest first.
+///
+/// When opened from a state directory, the history is saved to it after each
+/// change, so it survives restarts of the server. Otherwise, it is only kept
+/// in memory.
+#[derive(Debug, Default)]
+pub struct UpgradeHistory {
+    path: Option<PathBuf>,
+    records: Vec<UpgradeRecord>,
+}
+
+impl UpgradeHistory {
+    /// Load the history stored in `directory`, creating it if needed.
+    ///
+    /// Upgrades which were still running when the server stopped are marked
+    /// as interrupted.
+    pub fn open<P: AsRef<Path>>(directory: P) -> std::io::Result<Self> {
+        let directory = directory.as_ref();
+        std::fs::create_dir_all(directory)?;
+        let path = directory.join(HISTORY_FILE);
+        let file: HistoryFile = match std::fs::read_to_string(&path) {
+            Ok(text) => toml::from_str(&text)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HistoryFile::default(),
+            Err(e) => return Err(e),
+        };
+        let mut history = Self {
+            path: Some(path),
+            records: file.upgrades,
+        };
+        let mut interrupted = false;
+        for record in history
+            .records
+            .iter_mut()
+            .filter(|r| r.outcome == UpgradeOutcome::Running)
+        {
+            record.outcome = UpgradeOutcome::Interrupted;
+            record.reason = "Server stopped during upgrade".to_string();
+            record.finished_at = Some(record.updated_at);
+            interrupted = true;
+        }
+        if interrupted {
+            history.save()?;
+        }
+        Ok(history)
+    }
+
+    /// Return the recorded upgrades, oldest first.
+    pub fn records(&self) -> &[UpgradeRecord] {
+        &self.records
+    }
+
+    /// Add or replace the record of an upgrade session, then save the history.
+    pub fn update(&mut self, record: &UpgradeRecord) -> std::io::Result<()> {
+        match self
+            .records
+            .iter_mut()
+            .find(|r| r.session_id == record.session_id)
+        {
+            Some(existing) => *existing = record.clone(),
+            None => {
+                self.records.push(record.clone());
+                let excess = self.records.len().saturating_sub(HISTORY_SIZE);
+                self.records.drain(..excess);
+            }
+        }
+        self.save()
+    }
+
+    /// Write the history to a temporary file, then move it over the previous
+    /// one, so that a crash never leaves a partially written history.
+    fn save(&self) -> std::io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let file = HistoryFile {
+            upgrades: self.records.clone(),
+        };
+        let text = toml::to_string(&file)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        let mut temp_path = path.clone().into_os_string();
+        temp_path.push(".new");
+        let mut temp = File::create(&temp_path)?;
+        temp.write_all(text.as_bytes())?;
+        temp.sync_all()?;
+        std::fs::rename(&temp_path, path)?;
+        if let Some(parent) = path.parent() {
+            File::open(parent)?.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+/// Return the current time, in seconds since the Unix epoch.
+pub(crate) fn timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state_directory(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("artifex-history-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn persist_history() {
+        let dir = state_directory("persist");
+        let mut history = UpgradeHistory::open(&dir).unwrap();
+        let mut record = UpgradeRecord::new("first", "2.0");
+        history.update(&record).unwrap();
+        record.outcome = UpgradeOutcome::Success;
+        record.position = 100;
+        history.update(&record).unwrap();
+        history
+            .update(&UpgradeRecord::new("second", "3.0"))
+            .unwrap();
+
+        let history = UpgradeHistory::open(&dir).unwrap();
+        let records = history.records();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0], record);
+        assert_eq!(records[1].session_id, "second");
+        assert_eq!(records[1].outcome, UpgradeOutcome::Interrupted);
+        assert!(records[1].finished_at.is_some());
+    }
+
+    #[test]
+    fn limit_history_size() {
+        let mut history = UpgradeHistory::default();
+        for index in 0..HISTORY_SIZE + 2 {
+            let record = UpgradeRecord::new(&index.to_string(), "2.0");
+            history.update(&record).unwrap();
+        }
+        assert_eq!(history.records().len(), HISTORY_SIZE);
+        assert_eq!(history.records()[0].session_id, "2");
+    }
+}
@@ -4,4 +4,8 @@
 // SPDX-License-Identifier: MIT
 //
 
+pub mod config;
+pub mod fault;
+pub mod history;
 pub mod service;
+pub mod session;
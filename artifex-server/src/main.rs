@@ -6,9 +6,14 @@
 
 use anyhow::{Context, Result};
 use artifex_rpc::{artifex_server::ArtifexServer, FILE_DESCRIPTOR_SET};
-use artifex_server::service::ArtifexService;
-use clap::Parser;
-use std::net::SocketAddr;
+use artifex_server::{
+    config::Config,
+    fault::{ErrorFaultConfig, FaultConfig},
+    history::UpgradeHistory,
+    service::ArtifexService,
+};
+use clap::{Args, Parser};
+use std::{net::SocketAddr, path::PathBuf};
 use tonic::transport::Server;
 
 
@@ -20,6 +25,89 @@ struct Cli {
 
     #[arg(short, long, help = "Port to use", default_value_t = 50051)]
     port: u16,
+
+    #[arg(short, long, help = "Path to configuration file")]
+    config: Option<PathBuf>,
+
+    #[command(flatten)]
+    faults: FaultArgs,
+}
+
+/// Faults to inject, overriding the ones of the configuration file.
+#[derive(Args)]
+#[command(next_help_heading = "Fault injection (for test environments)")]
+struct FaultArgs {
+    #[arg(long, help = "Fail upgrades at a position (in percents) or at random")]
+    fail_upgrade_at: Option<String>,
+
+    #[arg(long, help = "Make upgrades hang at a position (in percents)")]
+    stall_upgrade_at: Option<u8>,
+
+    #[arg(long, help = "Duration of upgrade hangs, like 30s")]
+    stall_duration: Option<String>,
+
+    #[arg(long, help = "Cut upgrade streams at a position (in percents)")]
+    drop_upgrade_at: Option<u8>,
+
+    #[arg(long, help = "Delay added to Inspect and Execute, like 2s")]
+    latency: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "CODE:PROBABILITY",
+        help = "Return a gRPC error at a given probability, like unavailable:0.1"
+    )]
+    inject_error: Vec<String>,
+
+    #[arg(long, help = "Seed of the random generator of faults")]
+    fault_seed: Option<u64>,
+}
+
+impl FaultArgs {
+    /// Apply the faults given on the command line to `config`.
+    fn apply(self, config: &mut Option<FaultConfig>) -> Result<()> {
+        let has_faults = self.fail_upgrade_at.is_some()
+            || self.stall_upgrade_at.is_some()
+            || self.drop_upgrade_at.is_some()
+            || self.latency.is_some()
+            || !self.inject_error.is_empty();
+        if !has_faults {
+            return Ok(());
+        }
+        let config = config.get_or_insert_with(FaultConfig::default);
+        if self.fail_upgrade_at.is_some() {
+            config.fail_upgrade_at = self.fail_upgrade_at;
+        }
+        if self.stall_upgrade_at.is_some() {
+            config.stall_upgrade_at = self.stall_upgrade_at;
+        }
+        if self.stall_duration.is_some() {
+            config.stall_duration = self.stall_duration;
+        }
+        if self.drop_upgrade_at.is_some() {
+            config.drop_upgrade_at = self.drop_upgrade_at;
+        }
+        if self.latency.is_some() {
+            config.latency = self.latency;
+        }
+        if self.fault_seed.is_some() {
+            config.seed = self.fault_seed;
+        }
+        for error in self.inject_error {
+            let (code, probability) = error
+                .split_once(':')
+                .with_context(|| format!("invalid error fault {}", error))?;
+            config.errors.push(ErrorFaultConfig {
+                code: code.to_string(),
+                probability: probability
+                    .parse()
+                    .with_context(|| format!("invalid probability {}", probability))?,
+                methods: vec![],
+                message: None,
+            });
+        }
+        Ok(())
+    }
 }
 
 #[tokio::main]
@@ -30,7 +118,31 @@ async fn main() -> Result<()> {
         .parse()
         .with_context(|| "failed to parse address")?;
     let address = SocketAddr::new(address, args.port);
-    let artifex = ArtifexService::default();
+    let mut config = match &args.config {
+        Some(path) => Config::from_file(path)
+            .with_context(|| format!("failed to load configuration from {}", path.display()))?,
+        None => Config::default(),
+    };
+    args.faults.apply(&mut config.faults)?;
+    let engine = config
+        .build_engine()
+        .with_context(|| "failed to set up engine")?;
+    let mut artifex = match &config.state_directory {
+        Some(path) => {
+            let history = UpgradeHistory::open(path).with_context(|| {
+                format!("failed to load upgrade history from {}", path.display())
+            })?;
+            ArtifexService::with_history(engine, history)
+        }
+        None => ArtifexService::new(engine),
+    };
+    if let Some(faults) = &config.faults {
+        let injector = faults
+            .build_injector()
+            .with_context(|| "invalid fault injection settings")?;
+        artifex.set_fault_injector(injector);
+    }
+    artifex.watch_confirmation();
     let server = ArtifexServer::new(artifex);
 
     let reflection = tonic_reflection::server::Builder::configure()
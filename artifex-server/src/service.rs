@@ -4,33 +4,188 @@
 // SPDX-License-Identifier: MIT
 //
 
-use artifex_engine::Engine;
+use artifex_engine::{Engine, Error, FileTail};
 use artifex_rpc::{
-    artifex_server::Artifex, upgrade_reply, ExecuteReply, ExecuteRequest, InspectReply,
-    InspectRequest, UpgradeReply, UpgradeRequest,
+    artifex_server::Artifex, upgrade_reply, ConfirmUpgradeReply, ConfirmUpgradeRequest,
+    ExecuteReply, ExecuteRequest, GetUpgradeHistoryReply, GetUpgradeHistoryRequest, InspectReply,
+    InspectRequest, RollbackReply, RollbackRequest, TailFileReply, TailFileRequest, UpgradeReply,
+    UpgradeRequest, WatchUpgradeRequest,
 };
 
 use futures::Stream;
 use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
 use std::{pin::Pin, sync::Arc};
 use tokio::sync::mpsc;
 use tokio::task;
 use tokio_stream::wrappers::ReceiverStream;
 use tonic::{Request, Response, Status};
 
+use crate::fault::FaultInjector;
+use crate::history::{UpgradeHistory, UpgradeOutcome, UpgradeRecord};
+use crate::session::UpgradeSessions;
+
+/// Delay after which a followed file is checked even without notification.
+const TAIL_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+fn to_status(error: Error) -> Status {
+    match error {
+        Error::Io(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            Status::not_found(e.to_string())
+        }
+        Error::Io(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+            Status::permission_denied(e.to_string())
+        }
+        e @ (Error::NoUpgradePending | Error::UpgradeNotBooted | Error::UpgradePending) => {
+            Status::failed_precondition(e.to_string())
+        }
+        e @ Error::Unsupported => Status::unimplemented(e.to_string()),
+        e => Status::internal(e.to_string()),
+    }
+}
+
+fn to_error_code(error: &Error) -> upgrade_reply::ErrorCode {
+    use upgrade_reply::ErrorCode;
+    match error {
+        Error::Backend(_) => ErrorCode::Backend,
+        Error::ChecksumMismatch(_) => ErrorCode::ChecksumMismatch,
+        Error::IncompatibleBundle(_) => ErrorCode::IncompatibleBundle,
+        Error::InjectedFault(_) => ErrorCode::InjectedFault,
+        Error::InvalidBundle(_) | Error::InvalidKey(_) => ErrorCode::InvalidBundle,
+        Error::InvalidSignature => ErrorCode::InvalidSignature,
+        Error::Io(_) | Error::Nix(_) | Error::Utf8(_) => ErrorCode::Io,
+        Error::ScriptFailed(_, _) => ErrorCode::ScriptFailed,
+        Error::Unsupported => ErrorCode::Unsupported,
+        Error::UpgradeFailed(_, e) => to_error_code(e),
+        Error::UpgradePending => ErrorCode::UpgradePending,
+        _ => ErrorCode::Unknown,
+    }
+}
+
+/// Build the reply sent when an upgrade fails because of `error`.
+fn to_failure_reply(error: Error) -> UpgradeReply {
+    let error_code = to_error_code(&error) as i32;
+    let (failed_phase, reason) = match error {
+        Error::UpgradeFailed(phase, e) => (phase.to_string(), e.to_string()),
+        e => (String::new(), e.to_string()),
+    };
+    UpgradeReply {
+        status: upgrade_reply::Status::Failure as i32,
+        reason,
+        error_code,
+        failed_phase,
+        ..Default::default()
+    }
+}
+
+fn to_rpc_record(record: UpgradeRecord) -> artifex_rpc::UpgradeRecord {
+    use artifex_rpc::upgrade_record::Outcome;
+    let outcome = match record.outcome {
+        UpgradeOutcome::Running => Outcome::Running,
+        UpgradeOutcome::Success => Outcome::Success,
+        UpgradeOutcome::Failure => Outcome::Failure,
+        UpgradeOutcome::Interrupted => Outcome::Interrupted,
+    };
+    artifex_rpc::UpgradeRecord {
+        session_id: record.session_id,
+        target: record.target,
+        phase: record.phase,
+        position: record.position,
+        outcome: outcome as i32,
+        reason: record.reason,
+        started_at: record.started_at,
+        updated_at: record.updated_at,
+        finished_at: record.finished_at,
+    }
+}
+
+/// Run `f` on the engine in a blocking task, as a running upgrade holds the
+/// engine until it is over.
+async fn with_engine<T, F>(engine: &Arc<Mutex<Engine>>, f: F) -> Result<T, Error>
+where
+    T: Send + 'static,
+    F: FnOnce(&mut Engine) -> Result<T, Error> + Send + 'static,
+{
+    let engine = engine.clone();
+    task::spawn_blocking(move || f(&mut engine.lock().unwrap()))
+        .await
+        .map_err(|e| Error::Backend(Box::new(e)))?
+}
+
+/// Roll back the last upgrade if it is not confirmed before its deadline.
+fn spawn_confirmation_watchdog(engine: Arc<Mutex<Engine>>) {
+    tokio::spawn(async move {
+        loop {
+            let deadline = match with_engine(&engine, |e| e.confirmation_deadline()).await {
+                Ok(Some(deadline)) => deadline,
+                _ => break,
+            };
+            match deadline.duration_since(SystemTime::now()) {
+                Ok(remaining) => tokio::time::sleep(remaining).await,
+                Err(_) => {
+                    if let Err(e) = with_engine(&engine, |e| e.rollback_upgrade()).await {
+                        eprintln!("Failed to roll back unconfirmed upgrade: {}", e);
+                    }
+                    break;
+                }
+            }
+        }
+    });
+}
+
 #[derive(Default)]
 pub struct ArtifexService {
     engine: Arc<Mutex<Engine>>,
+    sessions: UpgradeSessions,
+    faults: FaultInjector,
+}
+
+impl ArtifexService {
+    /// Create a new service exposing the features of `engine`.
+    pub fn new(engine: Engine) -> Self {
+        Self {
+            engine: Arc::new(Mutex::new(engine)),
+            sessions: UpgradeSessions::default(),
+            faults: FaultInjector::default(),
+        }
+    }
+
+    /// Create a new service exposing the features of `engine`, recording
+    /// upgrades in `history`.
+    pub fn with_history(engine: Engine, history: UpgradeHistory) -> Self {
+        Self {
+            engine: Arc::new(Mutex::new(engine)),
+            sessions: UpgradeSessions::new(history),
+            faults: FaultInjector::default(),
+        }
+    }
+
+    /// Make the service misbehave as directed by `injector`.
+    pub fn set_fault_injector(&mut self, injector: FaultInjector) {
+        self.faults = injector;
+    }
+
+    /// Watch for the confirmation of the last upgrade, rolling it back if
+    /// it does not happen in time.
+    pub fn watch_confirmation(&self) {
+        spawn_confirmation_watchdog(self.engine.clone());
+    }
 }
 
 #[tonic::async_trait]
 impl Artifex for ArtifexService {
     type UpgradeStream = Pin<Box<dyn Stream<Item = Result<UpgradeReply, Status>> + Send>>;
+    type WatchUpgradeStream = Pin<Box<dyn Stream<Item = Result<UpgradeReply, Status>> + Send>>;
+    type TailFileStream = Pin<Box<dyn Stream<Item = Result<TailFileReply, Status>> + Send>>;
 
     async fn inspect(
         &self,
         _request: Request<InspectRequest>,
     ) -> Result<Response<InspectReply>, Status> {
+        if let Some(status) = self.faults.error("Inspect") {
+            return Err(status);
+        }
+        self.faults.delay().await;
         let engine = self.engine.lock().unwrap();
         let info = engine.inspect().unwrap();
         let response = InspectReply {
@@ -44,6 +199,10 @@ impl Artifex for ArtifexService {
         &self,
         request: Request<ExecuteRequest>,
     ) -> Result<Response<ExecuteReply>, Status> {
+        if let Some(status) = self.faults.error("Execute") {
+            return Err(status);
+        }
+        self.faults.delay().await;
         let execute_req = request.into_inner();
         let mut args = execute_req.command.split_whitespace();
         let engine = self.engine.lock().unwrap();
@@ -59,36 +218,149 @@ impl Artifex for ArtifexService {
 
     async fn upgrade(
         &self,
-        _request: Request<UpgradeRequest>,
+        request: Request<UpgradeRequest>,
     ) -> Result<Response<Self::UpgradeStream>, Status> {
-        let (tx, rx) = mpsc::channel(100);
+        if let Some(status) = self.faults.error("Upgrade") {
+            return Err(status);
+        }
+        let upgrade_req = request.into_inner();
+        let publisher = self.sessions.start(&upgrade_req.target)?;
+        let stream = publisher.follow();
         let engine = self.engine.clone();
-        let tx_clone = tx.clone();
+        let runtime = tokio::runtime::Handle::current();
         task::spawn_blocking(move || {
-            let engine = engine.lock().unwrap();
-            let res = engine.upgrade(move |position| {
-                let reply = UpgradeReply {
+            let mut guard = engine.lock().unwrap();
+            let res = guard.upgrade(&upgrade_req.target, |progress| {
+                publisher.publish(UpgradeReply {
                     status: upgrade_reply::Status::Running as i32,
-                    position: position as i32,
-                };
-                if tx_clone
-                    .blocking_send(Result::<_, Status>::Ok(reply))
-                    .is_err()
-                {}
+                    position: progress.position as i32,
+                    phase: progress.phase.to_string(),
+                    phase_position: progress.phase_position as i32,
+                    message: progress.message.clone(),
+                    bytes_processed: progress.bytes_processed,
+                    eta: progress.eta.map(|eta| eta.as_secs()),
+                    ..Default::default()
+                });
             });
-            let status = if res.is_ok() {
-                upgrade_reply::Status::Success as i32
-            } else {
-                upgrade_reply::Status::Failure as i32
-            };
-            let reply = UpgradeReply {
-                status,
-                position: 100,
+            drop(guard);
+            let reply = match res {
+                Ok(()) => {
+                    let _guard = runtime.enter();
+                    spawn_confirmation_watchdog(engine);
+                    UpgradeReply {
+                        status: upgrade_reply::Status::Success as i32,
+                        position: 100,
+                        ..Default::default()
+                    }
+                }
+                Err(e) => to_failure_reply(e),
             };
-            let _ = tx.blocking_send(Result::<_, Status>::Ok(reply));
+            publisher.publish(reply);
+        });
+
+        let stream = self.faults.wrap_upgrade_stream(Box::pin(stream));
+        Ok(Response::new(stream as Self::UpgradeStream))
+    }
+
+    async fn watch_upgrade(
+        &self,
+        request: Request<WatchUpgradeRequest>,
+    ) -> Result<Response<Self::WatchUpgradeStream>, Status> {
+        if let Some(status) = self.faults.error("WatchUpgrade") {
+            return Err(status);
+        }
+        let watch_req = request.into_inner();
+        let stream = self.sessions.watch(&watch_req.session_id)?;
+        let stream = self.faults.wrap_upgrade_stream(Box::pin(stream));
+        Ok(Response::new(stream as Self::WatchUpgradeStream))
+    }
+
+    async fn get_upgrade_history(
+        &self,
+        _request: Request<GetUpgradeHistoryRequest>,
+    ) -> Result<Response<GetUpgradeHistoryReply>, Status> {
+        if let Some(status) = self.faults.error("GetUpgradeHistory") {
+            return Err(status);
+        }
+        let upgrades = self
+            .sessions
+            .history()
+            .into_iter()
+            .map(to_rpc_record)
+            .collect();
+        Ok(Response::new(GetUpgradeHistoryReply { upgrades }))
+    }
+
+    async fn confirm_upgrade(
+        &self,
+        _request: Request<ConfirmUpgradeRequest>,
+    ) -> Result<Response<ConfirmUpgradeReply>, Status> {
+        if let Some(status) = self.faults.error("ConfirmUpgrade") {
+            return Err(status);
+        }
+        with_engine(&self.engine, |e| e.confirm_upgrade())
+            .await
+            .map_err(to_status)?;
+        Ok(Response::new(ConfirmUpgradeReply {}))
+    }
+
+    async fn rollback(
+        &self,
+        _request: Request<RollbackRequest>,
+    ) -> Result<Response<RollbackReply>, Status> {
+        if let Some(status) = self.faults.error("Rollback") {
+            return Err(status);
+        }
+        with_engine(&self.engine, |e| e.rollback_upgrade())
+            .await
+            .map_err(to_status)?;
+        Ok(Response::new(RollbackReply {}))
+    }
+
+    async fn tail_file(
+        &self,
+        request: Request<TailFileRequest>,
+    ) -> Result<Response<Self::TailFileStream>, Status> {
+        if let Some(status) = self.faults.error("TailFile") {
+            return Err(status);
+        }
+        let tail_req = request.into_inner();
+        let path = tail_req.path.clone();
+        let count = tail_req.lines as usize;
+        let (mut tail, lines) = task::spawn_blocking(move || {
+            let mut tail = FileTail::open(path)?;
+            let lines = tail.read_last(count)?;
+            Ok::<_, Error>((tail, lines))
+        })
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?
+        .map_err(to_status)?;
+        let (tx, rx) = mpsc::channel(100);
+        task::spawn_blocking(move || {
+            if tx.blocking_send(Ok(TailFileReply { lines })).is_err() || !tail_req.follow {
+                return;
+            }
+            loop {
+                match tail.follow(TAIL_POLL_INTERVAL) {
+                    Ok(lines) if lines.is_empty() => {
+                        if tx.is_closed() {
+                            break;
+                        }
+                    }
+                    Ok(lines) => {
+                        if tx.blocking_send(Ok(TailFileReply { lines })).is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        let _ = tx.blocking_send(Err(to_status(e)));
+                        break;
+                    }
+                }
+            }
         });
 
         let ostream = ReceiverStream::new(rx);
-        Ok(Response::new(Box::pin(ostream) as Self::UpgradeStream))
+        Ok(Response::new(Box::pin(ostream) as Self::TailFileStream))
     }
 }
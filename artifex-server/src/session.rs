@@ -0,0 +1,456 @@
+//
+// Copyright (C) 2024 Eric Le Bihan <eric.le.bihan.dev@free.fr>
+//
+// SPDX-License-Identifier: MIT
+//
+
+use crate::history::{timestamp, UpgradeHistory, UpgradeOutcome, UpgradeRecord};
+use artifex_rpc::{upgrade_reply, UpgradeReply};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::metadata::MetadataMap;
+use tonic::{Code, Status};
+use uuid::Uuid;
+
+/// Name of the metadata entry holding the identifier of the active session,
+/// when an upgrade is rejected.
+pub const SESSION_ID_KEY: &str = "upgrade-session-id";
+
+/// Number of updates of a session kept for the clients watching it. Slower
+/// clients miss updates.
+const UPDATES_CAPACITY: usize = 100;
+
+/// Errors raised when managing upgrade sessions.
+#[derive(Debug, PartialEq)]
+pub enum SessionError {
+    /// An upgrade is in progress, in the given session.
+    AlreadyActive(String),
+    /// No such upgrade session.
+    NotFound(String),
+    /// The history of the upgrades could not be saved.
+    History(String),
+}
+
+impl std::fmt::Display for SessionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SessionError::AlreadyActive(id) => {
+                write!(f, "upgrade session {} already in progress", id)
+            }
+            SessionError::NotFound(id) => write!(f, "no upgrade session {}", id),
+            SessionError::History(reason) => {
+                write!(f, "failed to save upgrade history: {}", reason)
+            }
+        }
+    }
+}
+
+impl From<SessionError> for Status {
+    /// Convert to `ALREADY_EXISTS`, `NOT_FOUND` or `INTERNAL`. The identifier
+    /// of the active session is given in the `upgrade-session-id` metadata.
+    fn from(error: SessionError) -> Self {
+        match &error {
+            SessionError::AlreadyActive(id) => {
+                let mut metadata = MetadataMap::new();
+                if let Ok(value) = id.parse() {
+                    metadata.insert(SESSION_ID_KEY, value);
+                }
+                Status::with_metadata(Code::AlreadyExists, error.to_string(), metadata)
+            }
+            SessionError::NotFound(_) => Status::not_found(error.to_string()),
+            SessionError::History(_) => Status::internal(error.to_string()),
+        }
+    }
+}
+
+/// Upgrade in progress or last upgrade performed on the machine.
+struct UpgradeSession {
+    id: String,
+    state: watch::Receiver<UpgradeReply>,
+    updates: broadcast::Sender<UpgradeReply>,
+}
+
+impl UpgradeSession {
+    /// Return true if the upgrade is still running.
+    fn is_active(&self) -> bool {
+        self.state.has_changed().is_ok()
+            && self.state.borrow().status() == upgrade_reply::Status::Running
+    }
+}
+
+/// Handle used to publish the state of an upgrade session.
+pub struct SessionPublisher {
+    id: String,
+    sender: watch::Sender<UpgradeReply>,
+    updates: broadcast::Sender<UpgradeReply>,
+    record: Mutex<UpgradeRecord>,
+    history: Arc<Mutex<UpgradeHistory>>,
+    initiator: Mutex<Option<mpsc::Sender<Result<UpgradeReply, Status>>>>,
+}
+
+impl SessionPublisher {
+    /// Return the identifier of the session.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Return a stream of the states of the session, starting with the
+    /// current one, for the client which started the upgrade.
+    ///
+    /// Unlike the streams returned by `UpgradeSessions::watch`, this one
+    /// misses no updates: `publish` waits for it to be read. It ends when the
+    /// publisher is dropped.
+    pub fn follow(&self) -> ReceiverStream<Result<UpgradeReply, Status>> {
+        let (tx, rx) = mpsc::channel(UPDATES_CAPACITY);
+        let _ = tx.try_send(Ok(self.sender.borrow().clone()));
+        *self.initiator.lock().unwrap() = Some(tx);
+        ReceiverStream::new(rx)
+    }
+
+    /// Update the state of the session.
+    ///
+    /// The session is tagged with its identifier. If the state can not be
+    /// saved in the history, the error is appended to the message of the
+    /// reply, as the upgrade goes on anyway.
+    ///
+    /// Blocks while the stream returned by `follow` is full, so it must not
+    /// be called from an asynchronous task once followed.
+    pub fn publish(&self, mut reply: UpgradeReply) {
+        reply.session_id = self.id.clone();
+        if let Err(e) = self.record(&reply) {
+            reply.message = match reply.message.as_str() {
+                "" => e.to_string(),
+                message => format!("{} ({})", message, e),
+            };
+        }
+        // Broadcast while holding the state, so that a client starting to
+        // watch gets each update either in the state or as an update.
+        self.sender.send_modify(|state| {
+            *state = reply.clone();
+            let _ = self.updates.send(reply.clone());
+        });
+        let mut initiator = self.initiator.lock().unwrap();
+        if let Some(tx) = initiator.as_ref() {
+            if tx.blocking_send(Ok(reply)).is_err() {
+                *initiator = None;
+            }
+        }
+    }
+
+    /// Save the state of the session in the history, when it changes enough
+    /// to be worth it.
+    fn record(&self, reply: &UpgradeReply) -> Result<(), SessionError> {
+        let mut record = self.record.lock().unwrap();
+        let previous = record.clone();
+        match reply.status() {
+            upgrade_reply::Status::Running => {
+                record.phase = reply.phase.clone();
+                record.position = reply.position;
+            }
+            upgrade_reply::Status::Success => {
+                record.position = reply.position;
+                record.outcome = UpgradeOutcome::Success;
+            }
+            upgrade_reply::Status::Failure => {
+                record.phase = reply.failed_phase.clone();
+                record.outcome = UpgradeOutcome::Failure;
+                record.reason = reply.reason.clone();
+            }
+        }
+        if record.phase == previous.phase
+            && record.position == previous.position
+            && record.outcome == previous.outcome
+        {
+            return Ok(());
+        }
+        record.updated_at = timestamp();
+        if record.outcome != UpgradeOutcome::Running {
+            record.finished_at = Some(record.updated_at);
+        }
+        self.history
+            .lock()
+            .unwrap()
+            .update(&record)
+            .map_err(|e| SessionError::History(e.to_string()))
+    }
+}
+
+/// Wait for the next update of a session, or `None` if the publisher is gone.
+///
+/// Missed updates are reported as a `DATA_LOSS` status.
+async fn next_update(
+    updates: &mut broadcast::Receiver<UpgradeReply>,
+    state: &mut watch::Receiver<UpgradeReply>,
+) -> Option<Result<UpgradeReply, Status>> {
+    loop {
+        tokio::select! {
+            biased;
+            update = updates.recv() => {
+                return match update {
+                    Ok(update) => Some(Ok(update)),
+                    Err(RecvError::Lagged(count)) => Some(Err(Status::data_loss(format!(
+                        "missed {} updates of the upgrade session",
+                        count
+                    )))),
+                    Err(RecvError::Closed) => None,
+                };
+            }
+            res = state.changed() => {
+                if res.is_err() {
+                    return updates.try_recv().ok().map(Ok);
+                }
+            }
+        }
+    }
+}
+
+/// Keep track of upgrade sessions, allowing only one active upgrade at a time.
+///
+/// Clients follow a session by watching it: they get its current state, then
+/// all its updates, until the upgrade is over. Watching a session does not
+/// affect it, so clients can come and go while the upgrade goes on. Clients
+/// too slow to keep up with the updates are told so with a `DATA_LOSS`
+/// status, and can watch the session again to get its current state. The
+/// client which started the upgrade follows it using
+/// `SessionPublisher::follow` instead, to get all the updates.
+///
+/// The state of the sessions is kept in an `UpgradeHistory`.
+#[derive(Default)]
+pub struct UpgradeSessions {
+    current: Mutex<Option<UpgradeSession>>,
+    history: Arc<Mutex<UpgradeHistory>>,
+}
+
+impl UpgradeSessions {
+    /// Create a new session manager, recording sessions in `history`.
+    pub fn new(history: UpgradeHistory) -> Self {
+        Self {
+            current: Mutex::new(None),
+            history: Arc::new(Mutex::new(history)),
+        }
+    }
+
+    /// Return the records of the past and current upgrades, oldest first.
+    pub fn history(&self) -> Vec<UpgradeRecord> {
+        self.history.lock().unwrap().records().to_vec()
+    }
+
+    /// Start a new upgrade session, to `target`.
+    ///
+    /// Fails if an upgrade is already in progress, or if the session can not
+    /// be saved in the history.
+    pub fn start(&self, target: &str) -> Result<SessionPublisher, SessionError> {
+        let mut current = self.current.lock().unwrap();
+        if let Some(session) = current.as_ref().filter(|s| s.is_active()) {
+            return Err(SessionError::AlreadyActive(session.id.clone()));
+        }
+        let id = Uuid::new_v4().to_string();
+        let record = UpgradeRecord::new(&id, target);
+        self.history
+            .lock()
+            .unwrap()
+            .update(&record)
+            .map_err(|e| SessionError::History(e.to_string()))?;
+        let (sender, state) = watch::channel(UpgradeReply {
+            status: upgrade_reply::Status::Running as i32,
+            session_id: id.clone(),
+            ..Default::default()
+        });
+        let (updates, _) = broadcast::channel(UPDATES_CAPACITY);
+        *current = Some(UpgradeSession {
+            id: id.clone(),
+            state,
+            updates: updates.clone(),
+        });
+        Ok(SessionPublisher {
+            id,
+            sender,
+            updates,
+            record: Mutex::new(record),
+            history: self.history.clone(),
+            initiator: Mutex::new(None),
+        })
+    }
+
+    /// Return a stream of the states of the session `id`, starting with the
+    /// current one, then its updates until the upgrade is over.
+    ///
+    /// An empty `id` designates the last session. The stream fails with
+    /// `DATA_LOSS` if its reader misses updates.
+    pub fn watch(
+        &self,
+        id: &str,
+    ) -> Result<ReceiverStream<Result<UpgradeReply, Status>>, SessionError> {
+        let current = self.current.lock().unwrap();
+        let session = match current.as_ref() {
+            Some(session) if id.is_empty() || session.id == id => session,
+            _ => return Err(SessionError::NotFound(id.to_string())),
+        };
+        let mut state = session.state.clone();
+        let (reply, mut updates) = {
+            let reply = state.borrow_and_update();
+            (reply.clone(), session.updates.subscribe())
+        };
+        let (tx, rx) = mpsc::channel(UPDATES_CAPACITY);
+        tokio::spawn(async move {
+            let mut reply = Ok(reply);
+            loop {
+                let running =
+                    matches!(&reply, Ok(r) if r.status() == upgrade_reply::Status::Running);
+                if tx.send(reply).await.is_err() || !running {
+                    break;
+                }
+                reply = match next_update(&mut updates, &mut state).await {
+                    Some(reply) => reply,
+                    None => break,
+                };
+            }
+        });
+        Ok(ReceiverStream::new(rx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_stream::StreamExt;
+
+    #[tokio::test]
+    async fn reject_concurrent_upgrade() {
+        let sessions = UpgradeSessions::default();
+        let publisher = sessions.start("2.0").unwrap();
+        let error = sessions.start("2.0").err().unwrap();
+        assert_eq!(
+            error,
+            SessionError::AlreadyActive(publisher.id().to_string())
+        );
+        let status = Status::from(error);
+        assert_eq!(status.code(), Code::AlreadyExists);
+        assert_eq!(
+            status.metadata().get(SESSION_ID_KEY).unwrap(),
+            publisher.id()
+        );
+        publisher.publish(UpgradeReply {
+            status: upgrade_reply::Status::Success as i32,
+            ..Default::default()
+        });
+        drop(publisher);
+        assert!(sessions.start("2.0").is_ok());
+        let history = sessions.history();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].target, "2.0");
+        assert_eq!(history[0].outcome, UpgradeOutcome::Success);
+        assert_eq!(history[1].outcome, UpgradeOutcome::Running);
+    }
+
+    #[tokio::test]
+    async fn watch_session() {
+        let sessions = UpgradeSessions::default();
+        assert!(sessions.watch("").is_err());
+        let publisher = sessions.start("2.0").unwrap();
+        publisher.publish(UpgradeReply {
+            position: 42,
+            ..Default::default()
+        });
+        let mut stream = sessions.watch(publisher.id()).unwrap();
+        let reply = stream.next().await.unwrap().unwrap();
+        assert_eq!(reply.position, 42);
+        assert_eq!(reply.session_id, publisher.id());
+        publisher.publish(UpgradeReply {
+            status: upgrade_reply::Status::Success as i32,
+            position: 100,
+            ..Default::default()
+        });
+        drop(publisher);
+        let reply = stream.next().await.unwrap().unwrap();
+        assert_eq!(reply.status(), upgrade_reply::Status::Success);
+        assert!(stream.next().await.is_none());
+        assert_eq!(
+            sessions.watch("unknown").err().unwrap(),
+            SessionError::NotFound("unknown".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn report_history_errors() {
+        let dir = std::env::temp_dir().join(format!("artifex-session-{}", std::process::id()));
+        let sessions = UpgradeSessions::new(UpgradeHistory::open(&dir).unwrap());
+        let publisher = sessions.start("2.0").unwrap();
+        let mut stream = sessions.watch(publisher.id()).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        publisher.publish(UpgradeReply {
+            position: 42,
+            message: "Installing".to_string(),
+            ..Default::default()
+        });
+        stream.next().await;
+        let reply = stream.next().await.unwrap().unwrap();
+        assert!(reply
+            .message
+            .starts_with("Installing (failed to save upgrade history:"));
+        drop(publisher);
+        let status = Status::from(sessions.start("3.0").err().unwrap());
+        assert_eq!(status.code(), Code::Internal);
+    }
+
+    #[tokio::test]
+    async fn get_all_updates() {
+        let sessions = UpgradeSessions::default();
+        let publisher = sessions.start("2.0").unwrap();
+        let mut stream = sessions.watch(publisher.id()).unwrap();
+        for position in 1..=3 {
+            publisher.publish(UpgradeReply {
+                position,
+                ..Default::default()
+            });
+        }
+        for position in 0..=3 {
+            assert_eq!(stream.next().await.unwrap().unwrap().position, position);
+        }
+        drop(publisher);
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn follow_without_missing_updates() {
+        let sessions = UpgradeSessions::default();
+        let publisher = sessions.start("2.0").unwrap();
+        let mut stream = publisher.follow();
+        let count = UPDATES_CAPACITY as i32 * 2;
+        let publishing = tokio::task::spawn_blocking(move || {
+            for position in 1..=count {
+                publisher.publish(UpgradeReply {
+                    position,
+                    ..Default::default()
+                });
+            }
+        });
+        for position in 0..=count {
+            assert_eq!(stream.next().await.unwrap().unwrap().position, position);
+        }
+        publishing.await.unwrap();
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn report_missed_updates() {
+        let sessions = UpgradeSessions::default();
+        let publisher = sessions.start("2.0").unwrap();
+        let mut stream = sessions.watch(publisher.id()).unwrap();
+        for position in 1..=(UPDATES_CAPACITY as i32 * 2) {
+            publisher.publish(UpgradeReply {
+                position,
+                ..Default::default()
+            });
+        }
+        assert_eq!(stream.next().await.unwrap().unwrap().position, 0);
+        let status = stream.next().await.unwrap().unwrap_err();
+        assert_eq!(status.code(), Code::DataLoss);
+        assert!(stream.next().await.is_none());
+        let mut stream = sessions.watch(publisher.id()).unwrap();
+        let reply = stream.next().await.unwrap().unwrap();
+        assert_eq!(reply.position, UPDATES_CAPACITY as i32 * 2);
+    }
+}
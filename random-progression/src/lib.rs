@@ -4,28 +4,186 @@
 // SPDX-License-Identifier: MIT
 //
 
-use rand::{distributions::Uniform, thread_rng, Rng};
+mod timed;
+
+pub use timed::{Curve, TimedProgression, TimedProgressionBuilder};
+
+use rand::{distributions::Uniform, rngs::StdRng, thread_rng, Rng, SeedableRng};
 use std::collections::VecDeque;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-pub struct RandomProgression {
-    positions: VecDeque<u8>,
+/// Source of time used to wait between the steps of a progression.
+pub trait Clock: Send + Sync {
+    /// Return the time elapsed since the creation of the clock.
+    fn elapsed(&self) -> Duration;
+    /// Wait for `duration`.
+    fn sleep(&self, duration: Duration);
 }
 
-impl RandomProgression {
+/// Clock following the time of the system.
+#[derive(Debug)]
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl SystemClock {
     pub fn new() -> Self {
-        let mut rng = thread_rng();
-        let count = rng.gen_range(1..10);
-        let mut positions: Vec<u8> = (&mut rng)
-            .sample_iter(Uniform::new(1, 99))
-            .take(count)
-            .collect();
-        positions.push(100);
-        positions.sort();
+        Self {
+            start: Instant::now(),
+        }
+    }
+}
 
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration)
+    }
+}
+
+/// Clock whose time only moves forward when sleeping, without actually
+/// waiting.
+///
+/// Clones share the same time.
+#[derive(Clone, Debug, Default)]
+pub struct VirtualClock {
+    elapsed: Arc<Mutex<Duration>>,
+}
+
+impl VirtualClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Clock for VirtualClock {
+    fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap() += duration;
+    }
+}
+
+/// Build a `RandomProgression` with custom settings.
+pub struct RandomProgressionBuilder {
+    steps: Range<usize>,
+    delays: Range<Duration>,
+    seed: Option<u64>,
+    clock: Arc<dyn Clock>,
+}
+
+impl RandomProgressionBuilder {
+    fn new() -> Self {
         Self {
-            positions: VecDeque::from(positions),
+            steps: 1..10,
+            delays: Duration::ZERO..Duration::ZERO,
+            seed: None,
+            clock: Arc::new(SystemClock::new()),
+        }
+    }
+
+    /// Set the range of the number of intermediate steps, before reaching 100%.
+    /// An empty range such as `3..3` gives a fixed number of steps.
+    pub fn steps(mut self, steps: Range<usize>) -> Self {
+        self.steps = steps;
+        self
+    }
+
+    /// Set the range of the delay before each step. An empty range gives a
+    /// fixed delay. There is no delay by default.
+    pub fn delays(mut self, delays: Range<Duration>) -> Self {
+        self.delays = delays;
+        self
+    }
+
+    /// Generate the progression from `seed`, making it reproducible.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Use `clock` to wait before each step.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Build the progression, using the seed if set.
+    pub fn build(self) -> RandomProgression {
+        match self.seed {
+            Some(seed) => self.build_with_rng(&mut StdRng::seed_from_u64(seed)),
+            None => self.build_with_rng(&mut thread_rng()),
         }
     }
+
+    /// Build the progression using `rng` as source of randomness.
+    pub fn build_with_rng<R: Rng + ?Sized>(self, rng: &mut R) -> RandomProgression {
+        let count = if self.steps.is_empty() {
+            self.steps.start
+        } else {
+            rng.gen_range(self.steps.clone())
+        };
+        let mut positions: Vec<u8> = (0..count).map(|_| rng.gen_range(1..99)).collect();
+        positions.push(100);
+        positions.sort();
+        let steps = positions
+            .into_iter()
+            .map(|position| {
+                let delay = if self.delays.is_empty() {
+                    self.delays.start
+                } else {
+                    rng.sample(Uniform::new(self.delays.start, self.delays.end))
+                };
+                (delay, position)
+            })
+            .collect();
+        RandomProgression {
+            steps,
+            clock: self.clock,
+        }
+    }
+}
+
+/// Iterator over a random sequence of positions in percents, ending at 100%.
+///
+/// If delays are set, the iterator waits before returning each position.
+pub struct RandomProgression {
+    steps: VecDeque<(Duration, u8)>,
+    clock: Arc<dyn Clock>,
+}
+
+impl RandomProgression {
+    pub fn new() -> Self {
+        Self::builder().build()
+    }
+
+    /// Create a progression generated from `seed`.
+    pub fn from_seed(seed: u64) -> Self {
+        Self::builder().seed(seed).build()
+    }
+
+    /// Create a progression using `rng` as source of randomness.
+    pub fn from_rng<R: Rng + ?Sized>(rng: &mut R) -> Self {
+        Self::builder().build_with_rng(rng)
+    }
+
+    /// Return a builder to customize the progression.
+    pub fn builder() -> RandomProgressionBuilder {
+        RandomProgressionBuilder::new()
+    }
 }
 
 impl Default for RandomProgression {
@@ -38,7 +196,11 @@ impl std::iter::Iterator for RandomProgression {
     type Item = u8;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.positions.pop_front()
+        let (delay, position) = self.steps.pop_front()?;
+        if !delay.is_zero() {
+            self.clock.sleep(delay);
+        }
+        Some(position)
     }
 }
 
@@ -53,4 +215,29 @@ mod tests {
             println!("Progression: {}%", position);
         }
     }
+
+    #[test]
+    fn reproducible() {
+        let first: Vec<u8> = RandomProgression::from_seed(42).collect();
+        let second: Vec<u8> = RandomProgression::from_seed(42).collect();
+        assert_eq!(first, second);
+        assert_eq!(first.last(), Some(&100));
+        assert!(first.windows(2).all(|w| w[0] <= w[1]));
+    }
+
+    #[test]
+    fn virtual_delays() {
+        let clock = VirtualClock::new();
+        let progression = RandomProgression::builder()
+            .steps(4..5)
+            .delays(Duration::from_secs(1)..Duration::from_secs(2))
+            .seed(7)
+            .clock(Arc::new(clock.clone()))
+            .build();
+        let start = Instant::now();
+        assert_eq!(progression.count(), 5);
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(clock.elapsed() >= Duration::from_secs(5));
+        assert!(clock.elapsed() < Duration::from_secs(10));
+    }
 }
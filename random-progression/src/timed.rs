@@ -0,0 +1,320 @@
+//
+// Copyright (C) 2024 Eric Le Bihan <eric.le.bihan.dev@free.fr>
+//
+// SPDX-License-Identifier: MIT
+//
+
+use crate::{Clock, SystemClock};
+use rand::{rngs::StdRng, thread_rng, Rng, SeedableRng};
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Shape of a progression over time.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Curve {
+    /// Constant speed.
+    Linear,
+    /// Slow start, then speeding up.
+    EaseIn,
+    /// Fast start, then slowing down.
+    EaseOut,
+    /// Slow start and end.
+    EaseInOut,
+    /// Download whose rate randomly drifts by up to `jitter` times the
+    /// average rate at each tick.
+    Download { jitter: f64 },
+}
+
+impl Curve {
+    /// Return the ratio of work done after the ratio `t` of the time.
+    fn ratio(&self, t: f64) -> f64 {
+        match self {
+            Curve::Linear | Curve::Download { .. } => t,
+            Curve::EaseIn => t * t,
+            Curve::EaseOut => t * (2.0 - t),
+            Curve::EaseInOut => t * t * (3.0 - 2.0 * t),
+        }
+    }
+}
+
+impl FromStr for Curve {
+    type Err = String;
+
+    /// Parse `linear`, `ease-in`, `ease-out`, `ease-in-out` or `download`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "linear" => Ok(Curve::Linear),
+            "ease-in" => Ok(Curve::EaseIn),
+            "ease-out" => Ok(Curve::EaseOut),
+            "ease-in-out" => Ok(Curve::EaseInOut),
+            "download" => Ok(Curve::Download { jitter: 0.3 }),
+            _ => Err(format!("unknown curve: {}", s)),
+        }
+    }
+}
+
+/// Build a `TimedProgression` with custom settings.
+pub struct TimedProgressionBuilder {
+    duration: Duration,
+    tick: Duration,
+    curve: Curve,
+    plateaus: Vec<(u8, Duration)>,
+    seed: Option<u64>,
+    clock: Arc<dyn Clock>,
+}
+
+impl TimedProgressionBuilder {
+    fn new(duration: Duration) -> Self {
+        Self {
+            duration,
+            tick: Duration::from_millis(250),
+            curve: Curve::Linear,
+            plateaus: vec![],
+            seed: None,
+            clock: Arc::new(SystemClock::new()),
+        }
+    }
+
+    /// Set the time between two samples. Defaults to 250 ms.
+    pub fn tick(mut self, tick: Duration) -> Self {
+        self.tick = tick;
+        self
+    }
+
+    /// Set the shape of the progression. Defaults to `Curve::Linear`.
+    pub fn curve(mut self, curve: Curve) -> Self {
+        self.curve = curve;
+        self
+    }
+
+    /// Stall at `position` for `duration`, which adds to the duration of the
+    /// progression.
+    ///
+    /// The progression ends as soon as it reaches 100%, so a plateau at 100%
+    /// or above is ignored.
+    pub fn plateau(mut self, position: u8, duration: Duration) -> Self {
+        self.plateaus.push((position.min(100), duration));
+        self
+    }
+
+    /// Generate the random parts of the progression from `seed`.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Use `clock` to wait between samples.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Build the progression, using the seed if set.
+    pub fn build(self) -> TimedProgression {
+        match self.seed {
+            Some(seed) => self.build_with_rng(&mut StdRng::seed_from_u64(seed)),
+            None => self.build_with_rng(&mut thread_rng()),
+        }
+    }
+
+    /// Build the progression using `rng` as source of randomness.
+    pub fn build_with_rng<R: Rng + ?Sized>(mut self, rng: &mut R) -> TimedProgression {
+        let tick = self.tick.max(Duration::from_millis(1));
+        let count = (self.duration.as_nanos() / tick.as_nanos()).max(1) as u32;
+        let ratios: Vec<f64> = match self.curve {
+            Curve::Download { jitter } => {
+                let mut rate = 1.0f64;
+                let mut total = 0.0;
+                let mut done = vec![];
+                for _ in 0..count {
+                    rate = (rate + jitter * rng.gen_range(-1.0..=1.0)).clamp(0.1, 2.0);
+                    total += rate;
+                    done.push(total);
+                }
+                done.iter().map(|d| d / total).collect()
+            }
+            ref curve => (1..=count)
+                .map(|k| curve.ratio(k as f64 / count as f64))
+                .collect(),
+        };
+
+        self.plateaus.sort_by_key(|(position, _)| *position);
+        let mut plateaus = VecDeque::from(self.plateaus);
+        let mut samples = VecDeque::new();
+        let mut offset = Duration::ZERO;
+        for (k, ratio) in ratios.into_iter().enumerate() {
+            let position = (ratio * 100.0).round().min(100.0) as u8;
+            let elapsed = tick * (k as u32 + 1);
+            while let Some(&(at, duration)) = plateaus.front() {
+                if at > position || at == 100 {
+                    break;
+                }
+                plateaus.pop_front();
+                let holds = (duration.as_nanos() / tick.as_nanos()).max(1) as u32;
+                for hold in 0..holds {
+                    samples.push_back((elapsed - tick + offset + tick * (hold + 1), at));
+                }
+                offset += tick * holds;
+            }
+            samples.push_back((elapsed + offset, position));
+        }
+        TimedProgression {
+            samples,
+            elapsed: Duration::ZERO,
+            clock: self.clock,
+        }
+    }
+}
+
+/// Iterator over the positions in percents of a progression lasting a given
+/// time, sampled at regular interval.
+///
+/// Each item is the time elapsed since the start of the progression and the
+/// position reached at that time. The iterator waits until that time before
+/// returning each item. The last position is 100%.
+pub struct TimedProgression {
+    samples: VecDeque<(Duration, u8)>,
+    elapsed: Duration,
+    clock: Arc<dyn Clock>,
+}
+
+impl TimedProgression {
+    /// Return a builder for a progression lasting `duration`.
+    pub fn builder(duration: Duration) -> TimedProgressionBuilder {
+        TimedProgressionBuilder::new(duration)
+    }
+
+    /// Convert the progression into an asynchronous stream, using the timer
+    /// of Tokio instead of the clock.
+    #[cfg(feature = "stream")]
+    pub fn into_stream(self) -> impl futures_util::Stream<Item = (Duration, u8)> {
+        futures_util::stream::unfold(
+            (self.samples, Duration::ZERO),
+            |(mut samples, elapsed)| async move {
+                let (at, position) = samples.pop_front()?;
+                tokio::time::sleep(at.saturating_sub(elapsed)).await;
+                Some(((at, position), (samples, at)))
+            },
+        )
+    }
+}
+
+impl Iterator for TimedProgression {
+    type Item = (Duration, u8);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (at, position) = self.samples.pop_front()?;
+        self.clock.sleep(at.saturating_sub(self.elapsed));
+        self.elapsed = at;
+        Some((at, position))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::VirtualClock;
+
+    fn collect(builder: TimedProgressionBuilder) -> Vec<(Duration, u8)> {
+        builder
+            .clock(Arc::new(VirtualClock::new()))
+            .build()
+            .collect()
+    }
+
+    fn is_monotonic(samples: &[(Duration, u8)]) -> bool {
+        samples
+            .windows(2)
+            .all(|w| w[0].0 < w[1].0 && w[0].1 <= w[1].1)
+    }
+
+    #[test]
+    fn parse_curve() {
+        assert_eq!("ease-in-out".parse(), Ok(Curve::EaseInOut));
+        assert!("bumpy".parse::<Curve>().is_err());
+    }
+
+    #[test]
+    fn follow_curves() {
+        let ten = Duration::from_secs(10);
+        let tick = Duration::from_secs(1);
+        let linear = collect(TimedProgression::builder(ten).tick(tick));
+        assert_eq!(linear.len(), 10);
+        assert_eq!(linear[4], (Duration::from_secs(5), 50));
+        assert_eq!(linear[9], (ten, 100));
+        let ease_in = collect(
+            TimedProgression::builder(ten)
+                .tick(tick)
+                .curve(Curve::EaseIn),
+        );
+        assert_eq!(ease_in[4], (Duration::from_secs(5), 25));
+        let ease_out = collect(
+            TimedProgression::builder(ten)
+                .tick(tick)
+                .curve(Curve::EaseOut),
+        );
+        assert_eq!(ease_out[4], (Duration::from_secs(5), 75));
+        assert!(is_monotonic(&ease_out));
+    }
+
+    #[test]
+    fn stall_on_plateau() {
+        let samples = collect(
+            TimedProgression::builder(Duration::from_secs(10))
+                .tick(Duration::from_secs(1))
+                .plateau(30, Duration::from_secs(3)),
+        );
+        assert_eq!(samples.len(), 13);
+        let held: Vec<u8> = samples[2..6].iter().map(|s| s.1).collect();
+        assert_eq!(held, vec![30, 30, 30, 30]);
+        assert_eq!(samples[12], (Duration::from_secs(13), 100));
+        assert!(is_monotonic(&samples));
+    }
+
+    #[test]
+    fn ignore_final_plateau() {
+        let builder =
+            || TimedProgression::builder(Duration::from_secs(10)).tick(Duration::from_secs(1));
+        let samples = collect(builder().plateau(100, Duration::from_secs(3)));
+        assert_eq!(samples, collect(builder()));
+        assert_eq!(samples.last(), Some(&(Duration::from_secs(10), 100)));
+    }
+
+    #[test]
+    fn reproducible_download() {
+        let builder = || {
+            TimedProgression::builder(Duration::from_secs(20))
+                .curve(Curve::Download { jitter: 0.5 })
+                .seed(3)
+        };
+        let samples = collect(builder());
+        assert_eq!(samples, collect(builder()));
+        assert_eq!(samples.last().unwrap().1, 100);
+        assert!(is_monotonic(&samples));
+    }
+
+    #[test]
+    fn wait_using_clock() {
+        let clock = VirtualClock::new();
+        let progression = TimedProgression::builder(Duration::from_secs(5))
+            .clock(Arc::new(clock.clone()))
+            .build();
+        assert_eq!(progression.last().unwrap().0, Duration::from_secs(5));
+        assert_eq!(clock.elapsed(), Duration::from_secs(5));
+    }
+
+    #[cfg(feature = "stream")]
+    #[tokio::test]
+    async fn stream_progression() {
+        use futures_util::StreamExt;
+
+        let progression = TimedProgression::builder(Duration::from_millis(50))
+            .tick(Duration::from_millis(10))
+            .build();
+        let samples: Vec<(Duration, u8)> = progression.into_stream().collect().await;
+        assert_eq!(samples.len(), 5);
+        assert_eq!(samples[4].1, 100);
+    }
+}